@@ -0,0 +1,160 @@
+use crate::models::Scope;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::http::header::{AUTHORIZATION, COOKIE};
+use axum::http::{HeaderMap, Method};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a login session stays valid.
+pub const SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+/// Cookie carrying the session token, so `<img>` and `<video>` elements in
+/// the SPA are authenticated without an `Authorization` header.
+pub const SESSION_COOKIE: &str = "session";
+/// Marks a bearer token as an API key rather than a session token.
+pub const API_KEY_PREFIX: &str = "msk_";
+/// Cookie carrying a share access token, scoped to the share's own path.
+pub const SHARE_ACCESS_COOKIE: &str = "share_access";
+/// How long the access token for a password-protected share stays valid.
+pub const SHARE_ACCESS_TTL_SECS: i64 = 4 * 60 * 60;
+
+/// Hash `password` with argon2id and a random salt, as a PHC string.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// A hash to verify against when the username is unknown, so a failed
+/// login takes as long whether or not the account exists.
+pub fn dummy_password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password(&new_token()).expect("hash dummy password"))
+}
+
+/// A fresh random token, 64 hex characters.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A fresh API key: `API_KEY_PREFIX` followed by a random token.
+pub fn new_api_key() -> String {
+    format!("{}{}", API_KEY_PREFIX, new_token())
+}
+
+/// Tokens are stored hashed so a leaked database cannot be used to log in.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The token a request authenticates with: `Authorization: Bearer <token>`,
+/// or else the session cookie.
+pub fn request_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(v) = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        if let Some(token) = v.strip_prefix("Bearer ") {
+            return Some(token.trim().to_string());
+        }
+    }
+    cookie_value(headers, SESSION_COOKIE)
+}
+
+/// The value of cookie `name`, if the request carries it.
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value.to_string())
+}
+
+// The password hash is the signing key: it never leaves the server, and
+// changing or removing the password invalidates every token issued for it
+fn share_access_signature(share_id: i64, password_hash: &str, expires_at: i64) -> String {
+    hash_token(&format!(
+        "{}:{}:{}:{}",
+        password_hash, share_id, expires_at, password_hash
+    ))
+}
+
+/// A token standing in for the password of share `share_id` until
+/// `expires_at`: `<expires_at>.<signature>`.
+pub fn share_access_token(share_id: i64, password_hash: &str, expires_at: i64) -> String {
+    format!(
+        "{}.{}",
+        expires_at,
+        share_access_signature(share_id, password_hash, expires_at)
+    )
+}
+
+/// Whether `token` was issued by `share_access_token` for this share and
+/// password and has not expired by `now`.
+pub fn verify_share_access(token: &str, share_id: i64, password_hash: &str, now: i64) -> bool {
+    let (expires_at, signature) = match token.split_once('.') {
+        Some((e, sig)) => match e.parse::<i64>() {
+            Ok(e) => (e, sig),
+            Err(_) => return false,
+        },
+        None => return false,
+    };
+    let want = share_access_signature(share_id, password_hash, expires_at);
+    // Compare without short-circuiting so timing does not leak a prefix
+    let same = want.len() == signature.len()
+        && want
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    same && expires_at > now
+}
+
+/// The scope an API key needs for a request. User and key management and
+/// anything else that changes data need `admin`; playback needs `stream`;
+/// every other read needs `read`.
+pub fn required_scope(method: &Method, path: &str) -> Scope {
+    const STREAM_PATHS: [&str; 5] = [
+        "/media/stream",
+        "/media/image",
+        "/media/hls/",
+        "/media/preview",
+        "/media/sprites/",
+    ];
+    if path.starts_with("/admin") {
+        Scope::Admin
+    } else if path == "/scan" || path.starts_with("/scan/") {
+        Scope::Scan
+    } else if method != Method::GET && method != Method::HEAD {
+        Scope::Admin
+    } else if STREAM_PATHS.iter().any(|p| path.starts_with(p)) {
+        Scope::Stream
+    } else {
+        Scope::Read
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
@@ -17,4 +17,16 @@ pub struct AppConfig {
     // Optional directory to serve the built SPA (client/dist). When present the server
     // will mount the client as a fallback for non-API routes (history-api fallback).
     pub client_dist_dir: Option<String>,
+    // When true, watch directory_to_scan with inotify and index changes as they happen.
+    pub watch_enabled: Option<bool>,
+    // Quiet period before a burst of filesystem events is applied (default 1000 ms).
+    pub watch_debounce_ms: Option<u64>,
+    // Where HLS segments are cached (defaults to a `transcode` dir next to the thumbnails).
+    pub transcode_dir: Option<String>,
+    // Maximum number of ffmpeg transcodes running at once (default 2).
+    pub transcode_concurrency: Option<usize>,
+    // Upper bound on the thumbnails directory in megabytes; unset leaves it unbounded.
+    pub thumbnails_max_mb: Option<u64>,
+    // Mark the session cookie Secure (default true); turn off for plain-HTTP development.
+    pub cookie_secure: Option<bool>,
 }
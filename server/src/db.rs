@@ -1,75 +1,416 @@
-use crate::models::{MediaEntry, NewMediaEntry};
+use crate::metadata::AudioTags;
+use crate::models::{
+    Album, ApiKey, Artist, IndexedChild, MediaEntry, MediaMetadata, NewMediaEntry, ProbeInfo, Role,
+    Scope, Share, TagCount, Track, User,
+};
 use serde_json;
 use sqlx::{query, query_scalar, Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
 
-pub async fn initialize_database(pool: SqlitePool) -> Result<(), sqlx::Error> {
-    let create = r#"
-        CREATE TABLE IF NOT EXISTS media (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL,
-            path TEXT NOT NULL UNIQUE,
-            parent_id INTEGER,
-            mime_type TEXT,
-            size INTEGER,
-            tags TEXT,
-            thumb_path TEXT,
-            width INTEGER,
-            height INTEGER,
-            duration_secs INTEGER,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (parent_id) REFERENCES media (id)
-        )
-    "#;
+// One schema change. `AddColumn` is idempotent so databases created by builds
+// that already had the column in CREATE TABLE migrate cleanly.
+enum MigrationStep {
+    Sql(&'static str),
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
 
-    let idx_parent = "CREATE INDEX IF NOT EXISTS idx_parent_id ON media (parent_id)";
-    let idx_path = "CREATE INDEX IF NOT EXISTS idx_path ON media (path)";
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: &'static [MigrationStep],
+}
 
-    query(create).execute(&pool).await?;
-    query(idx_parent).execute(&pool).await?;
-    query(idx_path).execute(&pool).await?;
+// Ordered list of schema migrations. Append new entries; never edit applied ones.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create media table",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS media (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    path TEXT NOT NULL UNIQUE,
+                    parent_id INTEGER,
+                    mime_type TEXT,
+                    size INTEGER,
+                    tags TEXT,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (parent_id) REFERENCES media (id)
+                )
+                "#,
+            ),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_parent_id ON media (parent_id)"),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_path ON media (path)"),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "thumbnail and dimension columns",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "thumb_path",
+                definition: "TEXT",
+            },
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "width",
+                definition: "INTEGER",
+            },
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "height",
+                definition: "INTEGER",
+            },
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "duration_secs",
+                definition: "INTEGER",
+            },
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "scan fingerprint columns",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "mtime_ns",
+                definition: "INTEGER",
+            },
+            MigrationStep::AddColumn {
+                table: "media",
+                column: "inode",
+                definition: "INTEGER",
+            },
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "full-text search index",
+        steps: &[
+            MigrationStep::Sql(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS media_fts USING fts5(name, path, tags, tokenize = 'unicode61')",
+            ),
+            MigrationStep::Sql("DELETE FROM media_fts"),
+            MigrationStep::Sql(
+                "INSERT INTO media_fts (rowid, name, path, tags) SELECT id, name, path, CASE WHEN json_valid(tags) THEN (SELECT group_concat(value, ' ') FROM json_each(media.tags)) END FROM media",
+            ),
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "normalized tags",
+        steps: &[
+            MigrationStep::Sql(
+                "CREATE TABLE IF NOT EXISTS tags (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS media_tags (
+                    media_id INTEGER NOT NULL REFERENCES media (id) ON DELETE CASCADE,
+                    tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+                    PRIMARY KEY (media_id, tag_id)
+                )
+                "#,
+            ),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_media_tags_tag ON media_tags (tag_id)"),
+            // Carry over the JSON-encoded tags, then drop the old column
+            MigrationStep::Sql(
+                r#"
+                INSERT OR IGNORE INTO tags (name)
+                SELECT DISTINCT trim(j.value) FROM media, json_each(media.tags) j
+                WHERE json_valid(media.tags) AND trim(j.value) != ''
+                "#,
+            ),
+            MigrationStep::Sql(
+                r#"
+                INSERT OR IGNORE INTO media_tags (media_id, tag_id)
+                SELECT media.id, tags.id FROM media, json_each(media.tags) j
+                JOIN tags ON tags.name = trim(j.value)
+                WHERE json_valid(media.tags)
+                "#,
+            ),
+            MigrationStep::Sql("ALTER TABLE media DROP COLUMN tags"),
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "EXIF metadata",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS media_metadata (
+                    media_id INTEGER PRIMARY KEY REFERENCES media (id) ON DELETE CASCADE,
+                    taken_at TEXT,
+                    camera_make TEXT,
+                    camera_model TEXT,
+                    lens TEXT,
+                    exposure_time TEXT,
+                    f_number REAL,
+                    iso INTEGER,
+                    orientation INTEGER,
+                    gps_latitude REAL,
+                    gps_longitude REAL
+                )
+                "#,
+            ),
+            // Forget fingerprints of photos so the next scan reads their EXIF
+            MigrationStep::Sql(
+                "UPDATE media SET mtime_ns = NULL WHERE mime_type IN ('image/jpeg', 'image/tiff', 'image/heic', 'image/heif')",
+            ),
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "music library",
+        steps: &[
+            MigrationStep::Sql(
+                "CREATE TABLE IF NOT EXISTS artists (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS albums (
+                    id INTEGER PRIMARY KEY,
+                    title TEXT NOT NULL,
+                    artist_id INTEGER REFERENCES artists (id),
+                    year INTEGER
+                )
+                "#,
+            ),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_albums_title ON albums (title)"),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS audio_tracks (
+                    media_id INTEGER PRIMARY KEY REFERENCES media (id) ON DELETE CASCADE,
+                    title TEXT,
+                    artist_id INTEGER REFERENCES artists (id),
+                    album_id INTEGER REFERENCES albums (id),
+                    track_number INTEGER,
+                    disc_number INTEGER,
+                    year INTEGER,
+                    genre TEXT,
+                    duration_secs REAL
+                )
+                "#,
+            ),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_audio_tracks_album ON audio_tracks (album_id)"),
+            MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_audio_tracks_artist ON audio_tracks (artist_id)"),
+            // Forget fingerprints of audio files so the next scan reads their tags
+            MigrationStep::Sql("UPDATE media SET mtime_ns = NULL WHERE mime_type LIKE 'audio/%'"),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "ffprobe results",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS media_probe (
+                media_id INTEGER PRIMARY KEY REFERENCES media (id) ON DELETE CASCADE,
+                container TEXT,
+                video_codec TEXT,
+                width INTEGER,
+                height INTEGER,
+                frame_rate REAL,
+                bitrate INTEGER,
+                duration_secs REAL,
+                audio_tracks TEXT NOT NULL DEFAULT '[]',
+                subtitles TEXT NOT NULL DEFAULT '[]'
+            )
+            "#,
+        )],
+    },
+    Migration {
+        version: 9,
+        description: "chosen video thumbnail frame",
+        steps: &[MigrationStep::AddColumn {
+            table: "media",
+            column: "thumb_time_secs",
+            definition: "REAL",
+        }],
+    },
+    Migration {
+        version: 10,
+        description: "user accounts and sessions",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS users (
+                    id INTEGER PRIMARY KEY,
+                    username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                    password_hash TEXT NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                )
+                "#,
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS sessions (
+                    token_hash TEXT PRIMARY KEY,
+                    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                    expires_at INTEGER NOT NULL
+                )
+                "#,
+            ),
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "directory access control lists",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS media_acl (
+                media_id INTEGER NOT NULL REFERENCES media (id) ON DELETE CASCADE,
+                user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                PRIMARY KEY (media_id, user_id)
+            )
+            "#,
+        )],
+    },
+    Migration {
+        version: 12,
+        description: "user roles and invites",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "users",
+                column: "role",
+                definition: "TEXT NOT NULL DEFAULT 'viewer'",
+            },
+            MigrationStep::AddColumn {
+                table: "users",
+                column: "disabled",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            // Accounts from before roles existed could do everything
+            MigrationStep::Sql("UPDATE users SET role = 'admin'"),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS user_invites (
+                    token_hash TEXT PRIMARY KEY,
+                    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                    expires_at INTEGER NOT NULL
+                )
+                "#,
+            ),
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "scoped API keys",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                scopes TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                expires_at INTEGER,
+                last_used_at INTEGER
+            )
+            "#,
+        )],
+    },
+    Migration {
+        version: 14,
+        description: "public share links",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS shares (
+                id INTEGER PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                media_id INTEGER NOT NULL REFERENCES media (id) ON DELETE CASCADE,
+                user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                expires_at INTEGER,
+                password_hash TEXT,
+                max_downloads INTEGER,
+                downloads INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )],
+    },
+];
 
-    Ok(())
-}
+/// Schema version this binary migrates databases up to.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
 
-pub async fn upsert_media(pool: SqlitePool, entry: &NewMediaEntry) -> Result<i64, sqlx::Error> {
-    let tags_json: Option<String> = entry
-        .tags
-        .as_ref()
-        .and_then(|t| serde_json::to_string(t).ok());
-    let q = r#"
-        INSERT INTO media (name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-        ON CONFLICT(path) DO UPDATE SET
-            name=excluded.name,
-            parent_id=excluded.parent_id,
-            mime_type=excluded.mime_type,
-            size=excluded.size,
-            tags=excluded.tags,
-            thumb_path=excluded.thumb_path,
-            width=excluded.width,
-            height=excluded.height,
-            duration_secs=excluded.duration_secs
-    "#;
+/// Read the schema version recorded in the database (0 for a fresh or legacy file).
+pub async fn schema_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    query_scalar("PRAGMA user_version").fetch_one(pool).await
+}
 
-    query(q)
-        .bind(&entry.name)
-        .bind(&entry.path)
-        .bind(entry.parent_id)
-        .bind(&entry.mime_type)
-        .bind(entry.size)
-        .bind(&tags_json)
-        .bind(&entry.thumb_path)
-        .bind(entry.width)
-        .bind(entry.height)
-        .bind(entry.duration_secs)
-        .execute(&pool)
+async fn column_exists(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    column: &str,
+) -> Result<bool, sqlx::Error> {
+    let count: i64 = query_scalar("SELECT COUNT(1) FROM pragma_table_info(?1) WHERE name = ?2")
+        .bind(table)
+        .bind(column)
+        .fetch_one(&mut **tx)
         .await?;
+    Ok(count > 0)
+}
 
-    let id: i64 = query_scalar("SELECT id FROM media WHERE path = ?1")
-        .bind(&entry.path)
-        .fetch_one(&pool)
-        .await?;
+/// Bring the schema up to `SCHEMA_VERSION`, applying each pending migration in
+/// its own transaction. Fails without touching anything if the database was
+/// written by a newer build.
+pub async fn initialize_database(pool: SqlitePool) -> Result<(), sqlx::Error> {
+    let current = schema_version(&pool).await?;
+    if current > SCHEMA_VERSION {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "database schema version {} is newer than this binary supports ({}); refusing to start",
+                current, SCHEMA_VERSION
+            )
+            .into(),
+        ));
+    }
 
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let mut tx = pool.begin().await?;
+        for step in m.steps {
+            match step {
+                MigrationStep::Sql(sql) => {
+                    query(sql).execute(&mut *tx).await?;
+                }
+                MigrationStep::AddColumn {
+                    table,
+                    column,
+                    definition,
+                } => {
+                    if !column_exists(&mut tx, table, column).await? {
+                        let sql =
+                            format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition);
+                        query(&sql).execute(&mut *tx).await?;
+                    }
+                }
+            }
+        }
+        // PRAGMA does not accept bound parameters; the version is a trusted constant
+        query(&format!("PRAGMA user_version = {}", m.version))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        tracing::info!("Applied schema migration {}: {}", m.version, m.description);
+    }
+
+    Ok(())
+}
+
+pub async fn upsert_media(pool: SqlitePool, entry: &NewMediaEntry) -> Result<i64, sqlx::Error> {
+    // The row and its search index entry are written together
+    let mut tx = pool.begin().await?;
+    let id = upsert_media_in_tx(&mut tx, entry).await?;
+    tx.commit().await?;
     Ok(id)
 }
 
@@ -77,23 +418,20 @@ pub async fn upsert_media_in_tx(
     tx: &mut Transaction<'_, Sqlite>,
     entry: &NewMediaEntry,
 ) -> Result<i64, sqlx::Error> {
-    let tags_json: Option<String> = entry
-        .tags
-        .as_ref()
-        .and_then(|t| serde_json::to_string(t).ok());
     let q = r#"
-        INSERT INTO media (name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        INSERT INTO media (name, path, parent_id, mime_type, size, thumb_path, width, height, duration_secs, mtime_ns, inode)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
         ON CONFLICT(path) DO UPDATE SET
             name=excluded.name,
             parent_id=excluded.parent_id,
             mime_type=excluded.mime_type,
             size=excluded.size,
-            tags=excluded.tags,
             thumb_path=excluded.thumb_path,
             width=excluded.width,
             height=excluded.height,
-            duration_secs=excluded.duration_secs
+            duration_secs=excluded.duration_secs,
+            mtime_ns=COALESCE(excluded.mtime_ns, media.mtime_ns),
+            inode=COALESCE(excluded.inode, media.inode)
     "#;
 
     query(q)
@@ -102,11 +440,12 @@ pub async fn upsert_media_in_tx(
         .bind(entry.parent_id)
         .bind(&entry.mime_type)
         .bind(entry.size)
-        .bind(&tags_json)
         .bind(&entry.thumb_path)
         .bind(entry.width)
         .bind(entry.height)
         .bind(entry.duration_secs)
+        .bind(entry.mtime_ns)
+        .bind(entry.inode)
         .execute(&mut **tx)
         .await?;
 
@@ -115,68 +454,109 @@ pub async fn upsert_media_in_tx(
         .fetch_one(&mut **tx)
         .await?;
 
+    // None leaves existing tags alone so rescans do not wipe them
+    if let Some(tags) = &entry.tags {
+        query("DELETE FROM media_tags WHERE media_id = ?1")
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+        add_media_tags_in_tx(tx, id, tags).await?;
+    }
+
+    sync_search_index_in_tx(tx, id).await?;
+
     Ok(id)
 }
 
+// Tags are indexed as plain space-separated words
+const FTS_TAGS_EXPR: &str = "(SELECT group_concat(t.name, ' ') FROM media_tags mt JOIN tags t ON t.id = mt.tag_id WHERE mt.media_id = media.id)";
+
+/// Rewrite the full-text index entry for `id` from the current `media` row.
+pub async fn sync_search_index_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+) -> Result<(), sqlx::Error> {
+    query("DELETE FROM media_fts WHERE rowid = ?1")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    query(&format!(
+        "INSERT INTO media_fts (rowid, name, path, tags) SELECT id, name, path, {} FROM media WHERE id = ?1",
+        FTS_TAGS_EXPR
+    ))
+    .bind(id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+// Columns selected for a MediaEntry, in the order expected by `media_from_row`.
+// Tags are aggregated from media_tags into a JSON array, sorted by name.
+const MEDIA_COLUMNS: &str = "m.id, m.name, m.path, m.parent_id, m.mime_type, m.size, \
+    (SELECT json_group_array(name) FROM (SELECT t.name FROM media_tags mt JOIN tags t ON t.id = mt.tag_id WHERE mt.media_id = m.id ORDER BY t.name)), \
+    m.thumb_path, m.width, m.height, m.duration_secs, m.created_at";
+
+type MediaRow = (
+    i64,
+    String,
+    String,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    String,
+);
+
+fn media_from_row(r: MediaRow) -> MediaEntry {
+    let tags: Option<Vec<String>> =
+        r.6.as_ref()
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .filter(|t| !t.is_empty());
+    MediaEntry {
+        id: r.0,
+        name: r.1,
+        path: r.2,
+        parent_id: r.3,
+        mime_type: r.4,
+        size: r.5,
+        created_at: r.11,
+        tags,
+        thumb_path: r.7,
+        width: r.8,
+        height: r.9,
+        duration_secs: r.10,
+    }
+}
+
 pub async fn get_media_by_id(pool: SqlitePool, id: i64) -> Result<Option<MediaEntry>, sqlx::Error> {
-    let row = sqlx::query_as::<_, (i64, String, String, Option<i64>, Option<String>, Option<i64>, Option<String>, Option<String>, Option<i64>, Option<i64>, Option<i64>, String)>(
-        "SELECT id, name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs, created_at FROM media WHERE id = ?1",
-    )
+    let row = sqlx::query_as::<_, MediaRow>(&format!(
+        "SELECT {} FROM media m WHERE m.id = ?1",
+        MEDIA_COLUMNS
+    ))
     .bind(id)
     .fetch_optional(&pool)
     .await?;
 
-    if let Some(r) = row {
-        let tags: Option<Vec<String>> = r.6.as_ref().and_then(|s| serde_json::from_str(s).ok());
-        Ok(Some(MediaEntry {
-            id: r.0,
-            name: r.1,
-            path: r.2,
-            parent_id: r.3,
-            mime_type: r.4,
-            size: r.5,
-            created_at: r.11,
-            tags,
-            thumb_path: r.7,
-            width: r.8,
-            height: r.9,
-            duration_secs: r.10,
-        }))
-    } else {
-        Ok(None)
-    }
+    Ok(row.map(media_from_row))
 }
 
 pub async fn get_media_by_path(
     pool: SqlitePool,
     path: String,
 ) -> Result<Option<MediaEntry>, sqlx::Error> {
-    let row = sqlx::query_as::<_, (i64, String, String, Option<i64>, Option<String>, Option<i64>, Option<String>, Option<String>, Option<i64>, Option<i64>, Option<i64>, String)>(
-        "SELECT id, name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs, created_at FROM media WHERE path = ?1",
-    )
+    let row = sqlx::query_as::<_, MediaRow>(&format!(
+        "SELECT {} FROM media m WHERE m.path = ?1",
+        MEDIA_COLUMNS
+    ))
     .bind(path)
     .fetch_optional(&pool)
     .await?;
 
-    if let Some(r) = row {
-        let tags: Option<Vec<String>> = r.6.as_ref().and_then(|s| serde_json::from_str(s).ok());
-        Ok(Some(MediaEntry {
-            id: r.0,
-            name: r.1,
-            path: r.2,
-            parent_id: r.3,
-            mime_type: r.4,
-            size: r.5,
-            created_at: r.11,
-            tags,
-            thumb_path: r.7,
-            width: r.8,
-            height: r.9,
-            duration_secs: r.10,
-        }))
-    } else {
-        Ok(None)
-    }
+    Ok(row.map(media_from_row))
 }
 
 pub async fn list_children(
@@ -184,69 +564,135 @@ pub async fn list_children(
     parent_id: Option<i64>,
     tags: Option<Vec<String>>,
 ) -> Result<Vec<MediaEntry>, sqlx::Error> {
-    let rows = sqlx::query_as::<_, (i64, String, String, Option<i64>, Option<String>, Option<i64>, Option<String>, Option<String>, Option<i64>, Option<i64>, Option<i64>, String)>(
-        "SELECT id, name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs, created_at FROM media WHERE parent_id IS ?1",
-    )
-    .bind(parent_id)
-    .fetch_all(&pool)
-    .await?;
+    let mut sql = format!(
+        "SELECT {} FROM media m WHERE m.parent_id IS ?",
+        MEDIA_COLUMNS
+    );
+    let filter_tags = push_tag_filter(&mut sql, tags.as_deref());
 
-    let mut out = Vec::new();
-    for r in rows {
-        let tags_vec: Option<Vec<String>> = r.6.as_ref().and_then(|s| serde_json::from_str(s).ok());
-        out.push(MediaEntry {
-            id: r.0,
-            name: r.1,
-            path: r.2,
-            parent_id: r.3,
-            mime_type: r.4,
-            size: r.5,
-            created_at: r.11,
-            tags: tags_vec,
-            thumb_path: r.7,
-            width: r.8,
-            height: r.9,
-            duration_secs: r.10,
-        });
+    let mut q = sqlx::query_as::<_, MediaRow>(&sql).bind(parent_id);
+    for t in &filter_tags {
+        q = q.bind(t);
+    }
+    if !filter_tags.is_empty() {
+        q = q.bind(filter_tags.len() as i64);
     }
+    let rows = q.fetch_all(&pool).await?;
 
-    if let Some(filter_tags) = tags {
-        out = out
-            .into_iter()
-            .filter(|entry| {
-                if let Some(tlist) = &entry.tags {
-                    filter_tags.iter().all(|ft| tlist.contains(ft))
-                } else {
-                    false
-                }
-            })
-            .collect();
+    Ok(rows.into_iter().map(media_from_row).collect())
+}
+
+// Append SQL restricting rows to those carrying every tag in `tags`. Returns
+// the de-duplicated tag names; callers bind each of them followed by their count.
+fn push_tag_filter(sql: &mut String, tags: Option<&[String]>) -> Vec<String> {
+    let mut wanted: Vec<String> = Vec::new();
+    for t in tags.unwrap_or(&[]) {
+        if !wanted.contains(t) {
+            wanted.push(t.clone());
+        }
     }
+    if wanted.is_empty() {
+        return wanted;
+    }
+    let placeholders = vec!["?"; wanted.len()].join(", ");
+    sql.push_str(&format!(
+        " AND m.id IN (SELECT mt.media_id FROM media_tags mt JOIN tags t ON t.id = mt.tag_id WHERE t.name IN ({}) GROUP BY mt.media_id HAVING COUNT(DISTINCT t.id) = ?)",
+        placeholders
+    ));
+    wanted
+}
 
-    Ok(out)
+// Rows hidden from a user: `m` is, or sits below, a directory with ACL grants
+// none of which is for the user. Only the restricted directories are looked
+// at, per candidate row, so a listing does not expand whole subtrees. Paths
+// are compared byte-for-byte like `delete_media_subtree_in_tx`. `param` is
+// the placeholder the user id is bound to.
+fn hidden_entry_sql(param: &str) -> String {
+    format!(
+        r#"EXISTS (
+            SELECT 1 FROM media_acl a JOIN media d ON d.id = a.media_id
+            WHERE m.path = d.path OR substr(m.path, 1, length(d.path) + 1) = d.path || '{}'
+            GROUP BY a.media_id HAVING SUM(a.user_id = {}) = 0
+        )"#,
+        std::path::MAIN_SEPARATOR,
+        param
+    )
 }
 
-pub async fn list_children_advanced(
-    pool: SqlitePool,
-    parent_id: Option<i64>,
-    tags: Option<Vec<String>>, // if provided, we'll post-filter in Rust and paginate after filtering
-    type_filter: Option<&str>, // "file" | "directory"
-    kind_filter: Option<&str>, // "image" | "video" | "audio" | "other"
-    limit: Option<i64>,
-    offset: Option<i64>,
-    sort: Option<&str>,  // "name" | "created" | "size"
-    order: Option<&str>, // "asc" | "desc"
-) -> Result<Vec<MediaEntry>, sqlx::Error> {
-    // Build dynamic SQL safely by mapping only known parameters to SQL fragments.
-    let mut sql = String::from(
-        "SELECT id, name, path, parent_id, mime_type, size, tags, thumb_path, width, height, duration_secs, created_at FROM media WHERE parent_id IS ?",
-    );
+// For statements with numbered parameters: true when row `m` is visible to
+// the viewer bound to `param`, and for every row when it is bound to NULL.
+fn visible_entry_sql(param: &str) -> String {
+    format!("({} IS NULL OR NOT {})", param, hidden_entry_sql(param))
+}
+
+// Append SQL dropping rows `viewer` may not see. Callers bind the viewer's id
+// where the clause sits in the statement.
+fn push_access_filter(sql: &mut String, viewer: Option<i64>) {
+    if viewer.is_some() {
+        sql.push_str(&format!(" AND NOT {}", hidden_entry_sql("?")));
+    }
+}
 
+/// Whether `user_id` may see media `id`. A directory with ACL grants is only
+/// visible to the users it names, and so is everything below it; nested
+/// grants narrow access further rather than widening it.
+pub async fn can_access(pool: &SqlitePool, user_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+    let denied: bool = query_scalar(
+        r#"
+        WITH RECURSIVE chain(id, parent_id) AS (
+            SELECT id, parent_id FROM media WHERE id = ?1
+            UNION ALL
+            SELECT m.id, m.parent_id FROM media m JOIN chain c ON m.id = c.parent_id
+        )
+        SELECT EXISTS (
+            SELECT 1 FROM chain c JOIN media_acl a ON a.media_id = c.id
+            GROUP BY c.id HAVING SUM(a.user_id = ?2) = 0
+        )
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(!denied)
+}
+
+/// Usernames granted access to directory `id`; empty when it is unrestricted.
+pub async fn get_acl(pool: &SqlitePool, id: i64) -> Result<Vec<String>, sqlx::Error> {
+    query_scalar(
+        "SELECT u.username FROM media_acl a JOIN users u ON u.id = a.user_id WHERE a.media_id = ?1 ORDER BY u.username",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Replace the grants on directory `id` with `user_ids`. An empty list lifts
+/// the restriction.
+pub async fn set_acl(pool: &SqlitePool, id: i64, user_ids: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query("DELETE FROM media_acl WHERE media_id = ?1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    for user_id in user_ids {
+        query("INSERT OR IGNORE INTO media_acl (media_id, user_id) VALUES (?1, ?2)")
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+// Append SQL for the "type" (file | directory) and "kind" (image | video | audio | other)
+// filters. Unknown values are ignored.
+fn push_type_kind_filters(sql: &mut String, type_filter: Option<&str>, kind_filter: Option<&str>) {
     // Type filter
     if let Some(t) = type_filter {
         match t {
-            "file" => sql.push_str(" AND mime_type IS NOT NULL"),
-            "directory" => sql.push_str(" AND mime_type IS NULL"),
+            "file" => sql.push_str(" AND m.mime_type IS NOT NULL"),
+            "directory" => sql.push_str(" AND m.mime_type IS NULL"),
             _ => {}
         }
     }
@@ -254,22 +700,46 @@ pub async fn list_children_advanced(
     // Kind filter
     if let Some(k) = kind_filter {
         match k {
-            "image" => sql.push_str(" AND mime_type LIKE 'image/%'"),
-            "video" => sql.push_str(" AND mime_type LIKE 'video/%'"),
-            "audio" => sql.push_str(" AND mime_type LIKE 'audio/%'"),
+            "image" => sql.push_str(" AND m.mime_type LIKE 'image/%'"),
+            "video" => sql.push_str(" AND m.mime_type LIKE 'video/%'"),
+            "audio" => sql.push_str(" AND m.mime_type LIKE 'audio/%'"),
             "other" => sql.push_str(
-                " AND mime_type IS NOT NULL AND mime_type NOT LIKE 'image/%' AND mime_type NOT LIKE 'video/%' AND mime_type NOT LIKE 'audio/%'",
+                " AND m.mime_type IS NOT NULL AND m.mime_type NOT LIKE 'image/%' AND m.mime_type NOT LIKE 'video/%' AND m.mime_type NOT LIKE 'audio/%'",
             ),
             _ => {}
         }
     }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn list_children_advanced(
+    pool: SqlitePool,
+    parent_id: Option<i64>,
+    tags: Option<Vec<String>>, // entries must carry all of these tags
+    type_filter: Option<&str>, // "file" | "directory"
+    kind_filter: Option<&str>, // "image" | "video" | "audio" | "other"
+    limit: Option<i64>,
+    offset: Option<i64>,
+    sort: Option<&str>,  // "name" | "created" | "size"
+    order: Option<&str>, // "asc" | "desc"
+    viewer: Option<i64>, // hide entries this user has no grant for; None shows everything
+) -> Result<Vec<MediaEntry>, sqlx::Error> {
+    // Build dynamic SQL safely by mapping only known parameters to SQL fragments.
+    let mut sql = format!(
+        "SELECT {} FROM media m WHERE m.parent_id IS ?",
+        MEDIA_COLUMNS
+    );
+
+    push_type_kind_filters(&mut sql, type_filter, kind_filter);
+    let filter_tags = push_tag_filter(&mut sql, tags.as_deref());
+    push_access_filter(&mut sql, viewer);
 
     // Sorting
     let sort_col = match sort.unwrap_or("name") {
-        "name" => "name",
-        "created" | "created_at" => "created_at",
-        "size" => "size",
-        _ => "name",
+        "name" => "m.name",
+        "created" | "created_at" => "m.created_at",
+        "size" => "m.size",
+        _ => "m.name",
     };
     let ord = match order.unwrap_or("asc").to_ascii_lowercase().as_str() {
         "asc" => "ASC",
@@ -278,126 +748,767 @@ pub async fn list_children_advanced(
     };
     sql.push_str(&format!(" ORDER BY {} {}", sort_col, ord));
 
-    let use_sql_pagination = tags.is_none();
-    if use_sql_pagination {
-        // Apply LIMIT/OFFSET only when we are not filtering by tags at the Rust layer.
-        let lim = limit.unwrap_or(100).max(0);
-        let off = offset.unwrap_or(0).max(0);
-        sql.push_str(" LIMIT ? OFFSET ?");
-
-        let q = sqlx::query_as::<
-            _,
-            (
-                i64,
-                String,
-                String,
-                Option<i64>,
-                Option<String>,
-                Option<i64>,
-                Option<String>,
-                Option<String>,
-                Option<i64>,
-                Option<i64>,
-                Option<i64>,
-                String,
-            ),
-        >(&sql)
-        .bind(parent_id)
-        .bind(lim)
-        .bind(off);
+    let lim = limit.unwrap_or(100).max(0);
+    let off = offset.unwrap_or(0).max(0);
+    sql.push_str(" LIMIT ? OFFSET ?");
 
-        let rows = q.fetch_all(&pool).await?;
+    let mut q = sqlx::query_as::<_, MediaRow>(&sql).bind(parent_id);
+    for t in &filter_tags {
+        q = q.bind(t);
+    }
+    if !filter_tags.is_empty() {
+        q = q.bind(filter_tags.len() as i64);
+    }
+    if let Some(user_id) = viewer {
+        q = q.bind(user_id);
+    }
+    let rows = q.bind(lim).bind(off).fetch_all(&pool).await?;
 
-        let mut out = Vec::new();
-        for r in rows {
-            let tags_vec: Option<Vec<String>> =
-                r.6.as_ref().and_then(|s| serde_json::from_str(s).ok());
-            out.push(MediaEntry {
-                id: r.0,
-                name: r.1,
-                path: r.2,
-                parent_id: r.3,
-                mime_type: r.4,
-                size: r.5,
-                created_at: r.11,
-                tags: tags_vec,
-                thumb_path: r.7,
-                width: r.8,
-                height: r.9,
-                duration_secs: r.10,
-            });
-        }
-        return Ok(out);
+    Ok(rows.into_iter().map(media_from_row).collect())
+}
+
+/// Turn free-text user input into an FTS5 query: every word must match, either
+/// exactly or as a prefix ("holi" finds "holiday").
+/// Returns None when the input contains nothing searchable.
+pub fn fts_match_expression(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .filter(|t| t.chars().any(|c| c.is_alphanumeric()))
+        .map(|t| format!("\"{}\"*", t.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Full-text search over names, paths and tags, best matches first.
+pub async fn search_media(
+    pool: SqlitePool,
+    q: &str,
+    type_filter: Option<&str>,
+    kind_filter: Option<&str>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    viewer: Option<i64>,
+) -> Result<Vec<MediaEntry>, sqlx::Error> {
+    let expr = match fts_match_expression(q) {
+        Some(e) => e,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut sql = format!(
+        "SELECT {} FROM media_fts JOIN media m ON m.id = media_fts.rowid WHERE media_fts MATCH ?",
+        MEDIA_COLUMNS
+    );
+    push_type_kind_filters(&mut sql, type_filter, kind_filter);
+    push_access_filter(&mut sql, viewer);
+    // Weight name matches above tags, and tags above path components
+    sql.push_str(" ORDER BY bm25(media_fts, 10.0, 1.0, 5.0), m.name LIMIT ? OFFSET ?");
+
+    let mut stmt = sqlx::query_as::<_, MediaRow>(&sql).bind(expr);
+    if let Some(user_id) = viewer {
+        stmt = stmt.bind(user_id);
     }
+    let rows = stmt
+        .bind(limit.unwrap_or(100).max(0))
+        .bind(offset.unwrap_or(0).max(0))
+        .fetch_all(&pool)
+        .await?;
+
+    Ok(rows.into_iter().map(media_from_row).collect())
+}
 
-    // Without SQL LIMIT/OFFSET, fetch all, filter tags in Rust, then paginate.
-    let q = sqlx::query_as::<
+/// List the rows currently indexed under `parent_id`, keyed by relative path.
+/// Used by the scanner to compare on-disk fingerprints against the index.
+pub async fn list_indexed_children(
+    pool: &SqlitePool,
+    parent_id: Option<i64>,
+) -> Result<HashMap<String, IndexedChild>, sqlx::Error> {
+    let rows = sqlx::query_as::<
         _,
         (
             i64,
             String,
-            String,
-            Option<i64>,
             Option<String>,
             Option<i64>,
-            Option<String>,
-            Option<String>,
             Option<i64>,
             Option<i64>,
-            Option<i64>,
-            String,
         ),
-    >(&sql)
-    .bind(parent_id);
-
-    let rows = q.fetch_all(&pool).await?;
+    >(
+        "SELECT id, path, mime_type, size, mtime_ns, inode FROM media WHERE parent_id IS ?1",
+    )
+    .bind(parent_id)
+    .fetch_all(pool)
+    .await?;
 
-    let mut out = Vec::new();
+    let mut out = HashMap::with_capacity(rows.len());
     for r in rows {
-        let tags_vec: Option<Vec<String>> = r.6.as_ref().and_then(|s| serde_json::from_str(s).ok());
-        out.push(MediaEntry {
-            id: r.0,
-            name: r.1,
-            path: r.2,
-            parent_id: r.3,
-            mime_type: r.4,
-            size: r.5,
-            created_at: r.11,
-            tags: tags_vec,
-            thumb_path: r.7,
-            width: r.8,
-            height: r.9,
-            duration_secs: r.10,
-        });
+        out.insert(
+            r.1,
+            IndexedChild {
+                id: r.0,
+                is_dir: r.2.is_none(),
+                size: r.3,
+                mtime_ns: r.4,
+                inode: r.5,
+            },
+        );
     }
+    Ok(out)
+}
 
-    let mut filtered = if let Some(filter_tags) = tags {
-        out.into_iter()
-            .filter(|entry| {
-                if let Some(tlist) = &entry.tags {
-                    filter_tags.iter().all(|ft| tlist.contains(ft))
-                } else {
-                    false
-                }
-            })
-            .collect::<Vec<_>>()
-    } else {
-        out
-    };
+/// Delete the row at `path` together with every row nested under it.
+/// Returns the ids that were removed so callers can clean up derived files.
+pub async fn delete_media_subtree_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    path: &str,
+) -> Result<Vec<i64>, sqlx::Error> {
+    // Compare the prefix byte-for-byte; LIKE would be case-insensitive here.
+    let prefix = format!("{}{}", path, std::path::MAIN_SEPARATOR);
+    let filter = "path = ?1 OR substr(path, 1, length(?2)) = ?2";
 
-    // Apply pagination after filtering
-    let lim = limit.unwrap_or(100).max(0) as usize;
-    let off = offset.unwrap_or(0).max(0) as usize;
-    let end = off.saturating_add(lim);
-    let sliced = if off >= filtered.len() {
-        Vec::new()
-    } else if end >= filtered.len() {
-        filtered.split_off(off)
-    } else {
-        filtered[off..end].to_vec()
+    let ids: Vec<i64> = query_scalar(&format!("SELECT id FROM media WHERE {}", filter))
+        .bind(path)
+        .bind(&prefix)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    query(&format!(
+        "DELETE FROM media_fts WHERE rowid IN (SELECT id FROM media WHERE {})",
+        filter
+    ))
+    .bind(path)
+    .bind(&prefix)
+    .execute(&mut **tx)
+    .await?;
+
+    for table in [
+        "media_tags",
+        "media_metadata",
+        "audio_tracks",
+        "media_probe",
+    ] {
+        query(&format!(
+            "DELETE FROM {} WHERE media_id IN (SELECT id FROM media WHERE {})",
+            table, filter
+        ))
+        .bind(path)
+        .bind(&prefix)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    query(&format!("DELETE FROM media WHERE {}", filter))
+        .bind(path)
+        .bind(&prefix)
+        .execute(&mut **tx)
+        .await?;
+
+    prune_music_library_in_tx(tx).await?;
+
+    Ok(ids)
+}
+
+// Attach `tags` to media `id`, creating tag rows as needed. Blank names are skipped.
+async fn add_media_tags_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    for t in tags.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        query("INSERT OR IGNORE INTO tags (name) VALUES (?1)")
+            .bind(t)
+            .execute(&mut **tx)
+            .await?;
+        query("INSERT OR IGNORE INTO media_tags (media_id, tag_id) SELECT ?1, id FROM tags WHERE name = ?2")
+            .bind(id)
+            .bind(t)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Replace the EXIF metadata stored for media `id`. None removes it.
+pub async fn set_media_metadata_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+    meta: Option<&MediaMetadata>,
+) -> Result<(), sqlx::Error> {
+    query("DELETE FROM media_metadata WHERE media_id = ?1")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    let m = match meta {
+        Some(m) => m,
+        None => return Ok(()),
     };
+    query(
+        r#"
+        INSERT INTO media_metadata (media_id, taken_at, camera_make, camera_model, lens, exposure_time, f_number, iso, orientation, gps_latitude, gps_longitude)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+        "#,
+    )
+    .bind(id)
+    .bind(&m.taken_at)
+    .bind(&m.camera_make)
+    .bind(&m.camera_model)
+    .bind(&m.lens)
+    .bind(&m.exposure_time)
+    .bind(m.f_number)
+    .bind(m.iso)
+    .bind(m.orientation)
+    .bind(m.gps_latitude)
+    .bind(m.gps_longitude)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+type MetadataRow = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<f64>,
+    Option<i64>,
+    Option<i64>,
+    Option<f64>,
+    Option<f64>,
+);
+
+/// EXIF metadata recorded for media `id`, if any.
+pub async fn get_media_metadata(
+    pool: &SqlitePool,
+    id: i64,
+) -> Result<Option<MediaMetadata>, sqlx::Error> {
+    let row = sqlx::query_as::<_, MetadataRow>(
+        "SELECT taken_at, camera_make, camera_model, lens, exposure_time, f_number, iso, orientation, gps_latitude, gps_longitude FROM media_metadata WHERE media_id = ?1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| MediaMetadata {
+        taken_at: r.0,
+        camera_make: r.1,
+        camera_model: r.2,
+        lens: r.3,
+        exposure_time: r.4,
+        f_number: r.5,
+        iso: r.6,
+        orientation: r.7,
+        gps_latitude: r.8,
+        gps_longitude: r.9,
+    }))
+}
+
+// Id of the artist called `name`, created on first use
+async fn artist_id_in_tx(tx: &mut Transaction<'_, Sqlite>, name: &str) -> Result<i64, sqlx::Error> {
+    query("INSERT OR IGNORE INTO artists (name) VALUES (?1)")
+        .bind(name)
+        .execute(&mut **tx)
+        .await?;
+    query_scalar("SELECT id FROM artists WHERE name = ?1")
+        .bind(name)
+        .fetch_one(&mut **tx)
+        .await
+}
+
+// Id of the album `title` by `artist_id`, created on first use. Albums are
+// matched by hand because a UNIQUE constraint would treat NULL artists as distinct.
+async fn album_id_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    title: &str,
+    artist_id: Option<i64>,
+    year: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let existing: Option<i64> =
+        query_scalar("SELECT id FROM albums WHERE title = ?1 AND artist_id IS ?2")
+            .bind(title)
+            .bind(artist_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    if let Some(id) = existing {
+        if year.is_some() {
+            query("UPDATE albums SET year = COALESCE(year, ?2) WHERE id = ?1")
+                .bind(id)
+                .bind(year)
+                .execute(&mut **tx)
+                .await?;
+        }
+        return Ok(id);
+    }
+    query_scalar("INSERT INTO albums (title, artist_id, year) VALUES (?1, ?2, ?3) RETURNING id")
+        .bind(title)
+        .bind(artist_id)
+        .bind(year)
+        .fetch_one(&mut **tx)
+        .await
+}
+
+/// Replace the music library entry for media `id`. None removes it.
+pub async fn set_audio_track_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+    tags: Option<&AudioTags>,
+) -> Result<(), sqlx::Error> {
+    let replaced = query("DELETE FROM audio_tracks WHERE media_id = ?1")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected()
+        > 0;
+    if let Some(t) = tags {
+        let artist_id = match &t.artist {
+            Some(a) => Some(artist_id_in_tx(tx, a).await?),
+            None => None,
+        };
+        let album_id = match &t.album {
+            Some(title) => {
+                let album_artist = match &t.album_artist {
+                    Some(a) => Some(artist_id_in_tx(tx, a).await?),
+                    None => artist_id,
+                };
+                Some(album_id_in_tx(tx, title, album_artist, t.year).await?)
+            }
+            None => None,
+        };
+        query(
+            r#"
+            INSERT INTO audio_tracks (media_id, title, artist_id, album_id, track_number, disc_number, year, genre, duration_secs)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+        )
+        .bind(id)
+        .bind(&t.title)
+        .bind(artist_id)
+        .bind(album_id)
+        .bind(t.track_number)
+        .bind(t.disc_number)
+        .bind(t.year)
+        .bind(&t.genre)
+        .bind(t.duration_secs)
+        .execute(&mut **tx)
+        .await?;
+    }
+    // Retagging can leave the previous album or artist without tracks
+    if replaced {
+        prune_music_library_in_tx(tx).await?;
+    }
+    Ok(())
+}
+
+// Drop albums without tracks, then artists no track or album refers to
+async fn prune_music_library_in_tx(tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+    query("DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM audio_tracks WHERE album_id IS NOT NULL)")
+        .execute(&mut **tx)
+        .await?;
+    query(
+        r#"
+        DELETE FROM artists
+        WHERE id NOT IN (SELECT artist_id FROM audio_tracks WHERE artist_id IS NOT NULL)
+          AND id NOT IN (SELECT artist_id FROM albums WHERE artist_id IS NOT NULL)
+        "#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+// Tracks `viewer` may see, as a CTE named `visible`; NULL sees them all
+fn visible_tracks_cte(param: &str) -> String {
+    format!(
+        "WITH visible AS (SELECT t.* FROM audio_tracks t JOIN media m ON m.id = t.media_id WHERE {})",
+        visible_entry_sql(param)
+    )
+}
 
-    Ok(sliced)
+/// Artists with at least one track or album, sorted by name. With a
+/// `viewer`, only tracks in directories they may see are counted, and
+/// artists left without any are dropped.
+pub async fn list_artists(
+    pool: &SqlitePool,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    viewer: Option<i64>,
+) -> Result<Vec<Artist>, sqlx::Error> {
+    let sql = format!(
+        r#"
+        {}
+        SELECT a.id, a.name,
+            (SELECT COUNT(*) FROM albums al
+                WHERE al.artist_id = a.id AND al.id IN (SELECT album_id FROM visible)),
+            (SELECT COUNT(*) FROM visible t WHERE t.artist_id = a.id)
+        FROM artists a
+        WHERE EXISTS (SELECT 1 FROM visible t WHERE t.artist_id = a.id)
+           OR EXISTS (SELECT 1 FROM albums al JOIN visible t ON t.album_id = al.id WHERE al.artist_id = a.id)
+        ORDER BY a.name COLLATE NOCASE
+        LIMIT ?1 OFFSET ?2
+        "#,
+        visible_tracks_cte("?3")
+    );
+    let rows = sqlx::query_as::<_, (i64, String, i64, i64)>(&sql)
+        .bind(limit.unwrap_or(100).max(0))
+        .bind(offset.unwrap_or(0).max(0))
+        .bind(viewer)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(id, name, album_count, track_count)| Artist {
+            id,
+            name,
+            album_count,
+            track_count,
+        })
+        .collect())
+}
+
+// Albums with their visible track count; those without any are left out.
+// The viewer is bound to `param`.
+fn album_select(param: &str) -> String {
+    format!(
+        r#"
+        {}
+        SELECT al.id, al.title, al.artist_id, ar.name, al.year,
+            (SELECT COUNT(*) FROM visible t WHERE t.album_id = al.id)
+        FROM albums al LEFT JOIN artists ar ON ar.id = al.artist_id
+        WHERE EXISTS (SELECT 1 FROM visible t WHERE t.album_id = al.id)
+        "#,
+        visible_tracks_cte(param)
+    )
+}
+
+type AlbumRow = (i64, String, Option<i64>, Option<String>, Option<i64>, i64);
+
+fn album_from_row(r: AlbumRow) -> Album {
+    Album {
+        id: r.0,
+        title: r.1,
+        artist_id: r.2,
+        artist: r.3,
+        year: r.4,
+        track_count: r.5,
+    }
+}
+
+/// Albums sorted by title, optionally only those credited to `artist_id`.
+/// With a `viewer`, only albums with tracks they may see are listed.
+pub async fn list_albums(
+    pool: &SqlitePool,
+    artist_id: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    viewer: Option<i64>,
+) -> Result<Vec<Album>, sqlx::Error> {
+    let sql = format!(
+        "{} AND (?1 IS NULL OR al.artist_id = ?1) ORDER BY al.title COLLATE NOCASE, al.id LIMIT ?2 OFFSET ?3",
+        album_select("?4")
+    );
+    let rows = sqlx::query_as::<_, AlbumRow>(&sql)
+        .bind(artist_id)
+        .bind(limit.unwrap_or(100).max(0))
+        .bind(offset.unwrap_or(0).max(0))
+        .bind(viewer)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.into_iter().map(album_from_row).collect())
+}
+
+pub async fn get_album(
+    pool: &SqlitePool,
+    id: i64,
+    viewer: Option<i64>,
+) -> Result<Option<Album>, sqlx::Error> {
+    let sql = format!("{} AND al.id = ?1", album_select("?2"));
+    let row = sqlx::query_as::<_, AlbumRow>(&sql)
+        .bind(id)
+        .bind(viewer)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(album_from_row))
+}
+
+type TrackRow = (
+    i64,
+    String,
+    String,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    Option<String>,
+    Option<f64>,
+);
+
+/// Tracks in album order (album, disc, track number, title). `artist_id`
+/// matches either the track artist or the album artist. With a `viewer`,
+/// tracks in directories hidden from them are left out.
+#[allow(clippy::too_many_arguments)]
+pub async fn list_tracks(
+    pool: &SqlitePool,
+    artist_id: Option<i64>,
+    album_id: Option<i64>,
+    genre: Option<&str>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    viewer: Option<i64>,
+) -> Result<Vec<Track>, sqlx::Error> {
+    let sql = format!(
+        r#"
+        SELECT m.id, m.path, COALESCE(t.title, m.name), t.artist_id, ar.name, t.album_id, al.title,
+            t.track_number, t.disc_number, t.year, t.genre, t.duration_secs
+        FROM audio_tracks t
+        JOIN media m ON m.id = t.media_id
+        LEFT JOIN artists ar ON ar.id = t.artist_id
+        LEFT JOIN albums al ON al.id = t.album_id
+        WHERE (?1 IS NULL OR t.artist_id = ?1 OR al.artist_id = ?1)
+          AND (?2 IS NULL OR t.album_id = ?2)
+          AND (?3 IS NULL OR t.genre = ?3 COLLATE NOCASE)
+          AND {}
+        ORDER BY al.title COLLATE NOCASE, t.album_id, t.disc_number, t.track_number, COALESCE(t.title, m.name)
+        LIMIT ?4 OFFSET ?5
+        "#,
+        visible_entry_sql("?6")
+    );
+    let rows = sqlx::query_as::<_, TrackRow>(&sql)
+        .bind(artist_id)
+        .bind(album_id)
+        .bind(genre)
+        .bind(limit.unwrap_or(100).max(0))
+        .bind(offset.unwrap_or(0).max(0))
+        .bind(viewer)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| Track {
+            id: r.0,
+            path: r.1,
+            title: r.2,
+            artist_id: r.3,
+            artist: r.4,
+            album_id: r.5,
+            album: r.6,
+            track_number: r.7,
+            disc_number: r.8,
+            year: r.9,
+            genre: r.10,
+            duration_secs: r.11,
+        })
+        .collect())
+}
+
+/// Videos the probing stage has not looked at yet, as (id, relative path).
+pub async fn list_unprobed_videos(pool: &SqlitePool) -> Result<Vec<(i64, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT m.id, m.path FROM media m WHERE m.mime_type LIKE 'video/%' AND NOT EXISTS (SELECT 1 FROM media_probe p WHERE p.media_id = m.id) ORDER BY m.id",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Forget probe results for media `id`, e.g. because the file changed.
+pub async fn clear_media_probe_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+) -> Result<(), sqlx::Error> {
+    query("DELETE FROM media_probe WHERE media_id = ?1")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Store probe results for media `id`, and fill in the row's dimensions and
+/// duration where nothing better is known yet.
+pub async fn set_media_probe(
+    pool: &SqlitePool,
+    id: i64,
+    info: &ProbeInfo,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query(
+        r#"
+        INSERT OR REPLACE INTO media_probe (media_id, container, video_codec, width, height, frame_rate, bitrate, duration_secs, audio_tracks, subtitles)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        "#,
+    )
+    .bind(id)
+    .bind(&info.container)
+    .bind(&info.video_codec)
+    .bind(info.width)
+    .bind(info.height)
+    .bind(info.frame_rate)
+    .bind(info.bitrate)
+    .bind(info.duration_secs)
+    .bind(serde_json::to_string(&info.audio_tracks).unwrap_or_else(|_| "[]".to_string()))
+    .bind(serde_json::to_string(&info.subtitles).unwrap_or_else(|_| "[]".to_string()))
+    .execute(&mut *tx)
+    .await?;
+    query(
+        r#"
+        UPDATE media SET
+            width = COALESCE(width, ?2),
+            height = COALESCE(height, ?3),
+            duration_secs = COALESCE(duration_secs, ?4)
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(info.width)
+    .bind(info.height)
+    .bind(info.duration_secs.map(|d| d.round() as i64))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+type ProbeRow = (
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<f64>,
+    Option<i64>,
+    Option<f64>,
+    String,
+    String,
+);
+
+/// Probe results recorded for media `id`, if it has been probed.
+pub async fn get_media_probe(pool: &SqlitePool, id: i64) -> Result<Option<ProbeInfo>, sqlx::Error> {
+    let row = sqlx::query_as::<_, ProbeRow>(
+        "SELECT container, video_codec, width, height, frame_rate, bitrate, duration_secs, audio_tracks, subtitles FROM media_probe WHERE media_id = ?1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| ProbeInfo {
+        container: r.0,
+        video_codec: r.1,
+        width: r.2,
+        height: r.3,
+        frame_rate: r.4,
+        bitrate: r.5,
+        duration_secs: r.6,
+        audio_tracks: serde_json::from_str(&r.7).unwrap_or_default(),
+        subtitles: serde_json::from_str(&r.8).unwrap_or_default(),
+    }))
+}
+
+/// Tags currently attached to media `id`, sorted by name.
+pub async fn get_media_tags(pool: &SqlitePool, id: i64) -> Result<Vec<String>, sqlx::Error> {
+    query_scalar(
+        "SELECT t.name FROM media_tags mt JOIN tags t ON t.id = mt.tag_id WHERE mt.media_id = ?1 ORDER BY t.name",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Add tags to media `id`, keeping the ones it already has.
+pub async fn add_media_tags(
+    pool: &SqlitePool,
+    id: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    add_media_tags_in_tx(&mut tx, id, tags).await?;
+    sync_search_index_in_tx(&mut tx, id).await?;
+    tx.commit().await
+}
+
+/// Detach tags from media `id`. Tags no longer used anywhere are deleted.
+pub async fn remove_media_tags(
+    pool: &SqlitePool,
+    id: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for t in tags.iter().map(|t| t.trim()) {
+        query("DELETE FROM media_tags WHERE media_id = ?1 AND tag_id IN (SELECT id FROM tags WHERE name = ?2)")
+            .bind(id)
+            .bind(t)
+            .execute(&mut *tx)
+            .await?;
+    }
+    query("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM media_tags)")
+        .execute(&mut *tx)
+        .await?;
+    sync_search_index_in_tx(&mut tx, id).await?;
+    tx.commit().await
+}
+
+/// All tags in use with the number of entries carrying each, most used first.
+/// With a `viewer`, only entries they may see are counted.
+pub async fn list_tags_with_counts(
+    pool: &SqlitePool,
+    viewer: Option<i64>,
+) -> Result<Vec<TagCount>, sqlx::Error> {
+    let sql = format!(
+        "SELECT t.name, COUNT(mt.media_id) AS n FROM tags t JOIN media_tags mt ON mt.tag_id = t.id JOIN media m ON m.id = mt.media_id WHERE {} GROUP BY t.id ORDER BY n DESC, t.name",
+        visible_entry_sql("?1")
+    );
+    let rows = sqlx::query_as::<_, (String, i64)>(&sql)
+        .bind(viewer)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, count)| TagCount { name, count })
+        .collect())
+}
+
+/// Forget the thumbnails at these `/thumbnails/<name>` URLs after they were
+/// evicted from the cache, so they are generated again on the next request.
+pub async fn clear_thumb_paths(pool: &SqlitePool, urls: &[String]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for url in urls {
+        query("UPDATE media SET thumb_path = NULL WHERE thumb_path = ?1")
+            .bind(url)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+/// Timestamp of the frame picked for video `id`'s thumbnail, if one was chosen.
+pub async fn get_thumb_time(pool: &SqlitePool, id: i64) -> Result<Option<f64>, sqlx::Error> {
+    let t: Option<Option<f64>> = query_scalar("SELECT thumb_time_secs FROM media WHERE id = ?1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(t.flatten())
+}
+
+/// Remember the frame picked for video `id`'s thumbnail so regenerating it
+/// at another size, or after eviction, shows the same frame.
+pub async fn set_thumb_time(pool: &SqlitePool, id: i64, secs: f64) -> Result<(), sqlx::Error> {
+    query("UPDATE media SET thumb_time_secs = ?2 WHERE id = ?1")
+        .bind(id)
+        .bind(secs)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Forget the chosen thumbnail frame of media `id`, because its content changed.
+pub async fn clear_thumb_time_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+) -> Result<(), sqlx::Error> {
+    query("UPDATE media SET thumb_time_secs = NULL WHERE id = ?1")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
 }
 
 pub async fn count_children(pool: SqlitePool, parent_id: i64) -> Result<i64, sqlx::Error> {
@@ -407,3 +1518,515 @@ pub async fn count_children(pool: SqlitePool, parent_id: i64) -> Result<i64, sql
         .await?;
     Ok(count)
 }
+
+const USER_COLUMNS: &str = "u.id, u.username, u.role, u.disabled, u.created_at";
+
+type UserRow = (i64, String, String, bool, String);
+
+fn user_from_row(r: UserRow) -> User {
+    User {
+        id: r.0,
+        username: r.1,
+        // unknown roles get the least privilege
+        role: Role::parse(&r.2).unwrap_or(Role::Viewer),
+        disabled: r.3,
+        created_at: r.4,
+    }
+}
+
+pub async fn get_user_id_by_name(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    query_scalar("SELECT id FROM users WHERE username = ?1")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Add an account. An empty `password_hash` leaves it unable to log in
+/// until an invite is accepted.
+pub async fn create_user(
+    pool: &SqlitePool,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> Result<i64, sqlx::Error> {
+    let res = query("INSERT INTO users (username, password_hash, role) VALUES (?1, ?2, ?3)")
+        .bind(username)
+        .bind(password_hash)
+        .bind(role.as_str())
+        .execute(pool)
+        .await?;
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn get_user(pool: &SqlitePool, id: i64) -> Result<Option<User>, sqlx::Error> {
+    let row = sqlx::query_as::<_, UserRow>(&format!(
+        "SELECT {} FROM users u WHERE u.id = ?1",
+        USER_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(user_from_row))
+}
+
+pub async fn list_users(pool: &SqlitePool) -> Result<Vec<User>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, UserRow>(&format!(
+        "SELECT {} FROM users u ORDER BY u.username COLLATE NOCASE",
+        USER_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(user_from_row).collect())
+}
+
+/// Change an account's role and/or disabled flag. Disabling it also ends
+/// its sessions.
+pub async fn update_user(
+    pool: &SqlitePool,
+    id: i64,
+    role: Option<Role>,
+    disabled: Option<bool>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query("UPDATE users SET role = COALESCE(?2, role), disabled = COALESCE(?3, disabled) WHERE id = ?1")
+        .bind(id)
+        .bind(role.map(|r| r.as_str()))
+        .bind(disabled)
+        .execute(&mut *tx)
+        .await?;
+    if disabled == Some(true) {
+        query("DELETE FROM sessions WHERE user_id = ?1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+pub async fn delete_user(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    query("DELETE FROM users WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Number of admins that can still log in, leaving out `except`.
+pub async fn count_active_admins(pool: &SqlitePool, except: i64) -> Result<i64, sqlx::Error> {
+    query_scalar("SELECT COUNT(*) FROM users WHERE role = 'admin' AND disabled = 0 AND id != ?1")
+        .bind(except)
+        .fetch_one(pool)
+        .await
+}
+
+/// Replace an account's password and end its sessions and pending invites.
+pub async fn set_password(
+    pool: &SqlitePool,
+    id: i64,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query("UPDATE users SET password_hash = ?2 WHERE id = ?1")
+        .bind(id)
+        .bind(password_hash)
+        .execute(&mut *tx)
+        .await?;
+    for sql in [
+        "DELETE FROM sessions WHERE user_id = ?1",
+        "DELETE FROM user_invites WHERE user_id = ?1",
+    ] {
+        query(sql).bind(id).execute(&mut *tx).await?;
+    }
+    tx.commit().await
+}
+
+/// Record an invite for `user_id`, replacing any earlier one.
+pub async fn create_invite(
+    pool: &SqlitePool,
+    token_hash: &str,
+    user_id: i64,
+    expires_at: i64,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query("DELETE FROM user_invites WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    query("INSERT INTO user_invites (token_hash, user_id, expires_at) VALUES (?1, ?2, ?3)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+/// The account an unexpired invite `token_hash` belongs to.
+pub async fn get_invite_user(
+    pool: &SqlitePool,
+    token_hash: &str,
+    now: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    query_scalar("SELECT user_id FROM user_invites WHERE token_hash = ?1 AND expires_at > ?2")
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The account called `username` (case-insensitively) with its password hash.
+pub async fn get_user_credentials(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Option<(User, String)>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (i64, String, String, bool, String, String)>(&format!(
+        "SELECT {}, u.password_hash FROM users u WHERE u.username = ?1",
+        USER_COLUMNS
+    ))
+    .bind(username)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(id, username, role, disabled, created_at, hash)| {
+        (
+            user_from_row((id, username, role, disabled, created_at)),
+            hash,
+        )
+    }))
+}
+
+/// Record a login session. Expired sessions are purged at the same time.
+pub async fn create_session(
+    pool: &SqlitePool,
+    token_hash: &str,
+    user_id: i64,
+    expires_at: i64,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    query("DELETE FROM sessions WHERE expires_at <= ?1")
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    query("INSERT INTO sessions (token_hash, user_id, expires_at) VALUES (?1, ?2, ?3)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+/// The enabled user owning the unexpired session `token_hash`.
+pub async fn get_session_user(
+    pool: &SqlitePool,
+    token_hash: &str,
+    now: i64,
+) -> Result<Option<User>, sqlx::Error> {
+    let row = sqlx::query_as::<_, UserRow>(&format!(
+        r#"
+        SELECT {}
+        FROM sessions s JOIN users u ON u.id = s.user_id
+        WHERE s.token_hash = ?1 AND s.expires_at > ?2 AND u.disabled = 0
+        "#,
+        USER_COLUMNS
+    ))
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(user_from_row))
+}
+
+pub async fn delete_session(pool: &SqlitePool, token_hash: &str) -> Result<(), sqlx::Error> {
+    query("DELETE FROM sessions WHERE token_hash = ?1")
+        .bind(token_hash)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+const API_KEY_COLUMNS: &str =
+    "k.id, k.name, k.user_id, u.username, k.scopes, k.created_at, k.expires_at, k.last_used_at";
+
+type ApiKeyRow = (
+    i64,
+    String,
+    i64,
+    String,
+    String,
+    String,
+    Option<i64>,
+    Option<i64>,
+);
+
+// Scopes are stored comma-separated; unknown names are dropped
+fn parse_scopes(s: &str) -> Vec<Scope> {
+    s.split(',').filter_map(Scope::parse).collect()
+}
+
+fn api_key_from_row(r: ApiKeyRow) -> ApiKey {
+    ApiKey {
+        id: r.0,
+        name: r.1,
+        user_id: r.2,
+        username: r.3,
+        scopes: parse_scopes(&r.4),
+        created_at: r.5,
+        expires_at: r.6,
+        last_used_at: r.7,
+    }
+}
+
+pub async fn create_api_key(
+    pool: &SqlitePool,
+    name: &str,
+    key_hash: &str,
+    user_id: i64,
+    scopes: &[Scope],
+    expires_at: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let scopes: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+    let res = query(
+        "INSERT INTO api_keys (name, key_hash, user_id, scopes, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )
+    .bind(name)
+    .bind(key_hash)
+    .bind(user_id)
+    .bind(scopes.join(","))
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn get_api_key(pool: &SqlitePool, id: i64) -> Result<Option<ApiKey>, sqlx::Error> {
+    let row = sqlx::query_as::<_, ApiKeyRow>(&format!(
+        "SELECT {} FROM api_keys k JOIN users u ON u.id = k.user_id WHERE k.id = ?1",
+        API_KEY_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(api_key_from_row))
+}
+
+/// Every API key, expired ones included, newest first.
+pub async fn list_api_keys(pool: &SqlitePool) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ApiKeyRow>(&format!(
+        "SELECT {} FROM api_keys k JOIN users u ON u.id = k.user_id ORDER BY k.id DESC",
+        API_KEY_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(api_key_from_row).collect())
+}
+
+/// Revoke a key. Returns false if there was no such key.
+pub async fn delete_api_key(pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+    let res = query("DELETE FROM api_keys WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// The unexpired key `key_hash` and the enabled user it acts as.
+pub async fn get_api_key_user(
+    pool: &SqlitePool,
+    key_hash: &str,
+    now: i64,
+) -> Result<Option<(ApiKey, User)>, sqlx::Error> {
+    // The key's columns followed by the rest of its user's
+    type KeyUserRow = (
+        i64,
+        String,
+        i64,
+        String,
+        String,
+        String,
+        Option<i64>,
+        Option<i64>,
+        String,
+        bool,
+        String,
+    );
+    let row = sqlx::query_as::<_, KeyUserRow>(&format!(
+        r#"
+        SELECT {}, u.role, u.disabled, u.created_at
+        FROM api_keys k JOIN users u ON u.id = k.user_id
+        WHERE k.key_hash = ?1 AND (k.expires_at IS NULL OR k.expires_at > ?2) AND u.disabled = 0
+        "#,
+        API_KEY_COLUMNS
+    ))
+    .bind(key_hash)
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| {
+        let user = user_from_row((r.2, r.3.clone(), r.8, r.9, r.10));
+        let key = api_key_from_row((r.0, r.1, r.2, r.3, r.4, r.5, r.6, r.7));
+        (key, user)
+    }))
+}
+
+/// Record that a key was used at `now`. Writes at most once a minute per
+/// key so busy scripts do not turn every request into a database write.
+pub async fn touch_api_key(pool: &SqlitePool, id: i64, now: i64) -> Result<(), sqlx::Error> {
+    query("UPDATE api_keys SET last_used_at = ?2 WHERE id = ?1 AND (last_used_at IS NULL OR last_used_at <= ?2 - 60)")
+        .bind(id)
+        .bind(now)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+const SHARE_COLUMNS: &str = "s.id, s.media_id, m.path, s.user_id, u.username, s.created_at, \
+     s.expires_at, s.password_hash IS NOT NULL, s.max_downloads, s.downloads";
+
+const SHARE_FROM: &str =
+    "FROM shares s JOIN media m ON m.id = s.media_id JOIN users u ON u.id = s.user_id";
+
+type ShareRow = (
+    i64,
+    i64,
+    String,
+    i64,
+    String,
+    String,
+    Option<i64>,
+    bool,
+    Option<i64>,
+    i64,
+);
+
+fn share_from_row(r: ShareRow) -> Share {
+    Share {
+        id: r.0,
+        media_id: r.1,
+        path: r.2,
+        user_id: r.3,
+        username: r.4,
+        created_at: r.5,
+        expires_at: r.6,
+        has_password: r.7,
+        max_downloads: r.8,
+        downloads: r.9,
+    }
+}
+
+pub async fn create_share(
+    pool: &SqlitePool,
+    token_hash: &str,
+    media_id: i64,
+    user_id: i64,
+    expires_at: Option<i64>,
+    password_hash: Option<&str>,
+    max_downloads: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let res = query(
+        r#"
+        INSERT INTO shares (token_hash, media_id, user_id, expires_at, password_hash, max_downloads)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        "#,
+    )
+    .bind(token_hash)
+    .bind(media_id)
+    .bind(user_id)
+    .bind(expires_at)
+    .bind(password_hash)
+    .bind(max_downloads)
+    .execute(pool)
+    .await?;
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn get_share(pool: &SqlitePool, id: i64) -> Result<Option<Share>, sqlx::Error> {
+    let row = sqlx::query_as::<_, ShareRow>(&format!(
+        "SELECT {} {} WHERE s.id = ?1",
+        SHARE_COLUMNS, SHARE_FROM
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(share_from_row))
+}
+
+/// Every share, expired and used-up ones included, newest first.
+pub async fn list_shares(pool: &SqlitePool) -> Result<Vec<Share>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ShareRow>(&format!(
+        "SELECT {} {} ORDER BY s.id DESC",
+        SHARE_COLUMNS, SHARE_FROM
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(share_from_row).collect())
+}
+
+/// Revoke a share. Returns false if there was no such share.
+pub async fn delete_share(pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+    let res = query("DELETE FROM shares WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// The share with token `token_hash`, its password hash, and its enabled
+/// creator. Expiry and the download limit are left to the caller.
+pub async fn get_share_by_token(
+    pool: &SqlitePool,
+    token_hash: &str,
+) -> Result<Option<(Share, Option<String>, User)>, sqlx::Error> {
+    // The share's columns followed by its password hash and the rest of its
+    // creator's
+    type ShareUserRow = (
+        i64,
+        i64,
+        String,
+        i64,
+        String,
+        String,
+        Option<i64>,
+        bool,
+        Option<i64>,
+        i64,
+        Option<String>,
+        String,
+        bool,
+        String,
+    );
+    let row = sqlx::query_as::<_, ShareUserRow>(&format!(
+        r#"
+        SELECT {}, s.password_hash, u.role, u.disabled, u.created_at
+        {}
+        WHERE s.token_hash = ?1 AND u.disabled = 0
+        "#,
+        SHARE_COLUMNS, SHARE_FROM
+    ))
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| {
+        let user = user_from_row((r.3, r.4.clone(), r.11, r.12, r.13));
+        let share = share_from_row((r.0, r.1, r.2, r.3, r.4, r.5, r.6, r.7, r.8, r.9));
+        (share, r.10, user)
+    }))
+}
+
+/// Count one download against a share. Returns false, counting nothing,
+/// if its download limit has been reached.
+pub async fn count_share_download(pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+    let res = query(
+        r#"
+        UPDATE shares SET downloads = downloads + 1
+        WHERE id = ?1 AND (max_downloads IS NULL OR downloads < max_downloads)
+        "#,
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
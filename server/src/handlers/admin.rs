@@ -1,4 +1,5 @@
 use crate::db;
+use crate::handlers::auth::AdminUser;
 use crate::handlers::thumbnails::generate_thumbnail_for_entry;
 use crate::state::AppState;
 use axum::{extract::State, response::Json};
@@ -15,9 +16,10 @@ pub struct Progress {
     pub failed: usize,
 }
 
-// POST /admin/regenerate_thumbnails?w=200&h=200&concurrency=4
+// POST /admin/regenerate_thumbnails?w=200&h=200&concurrency=4 (admins only)
 pub async fn regenerate_thumbnails_handler(
     State(state): State<Arc<TokioMutex<AppState>>>,
+    _admin: AdminUser,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Result<Json<Progress>, (axum::http::StatusCode, String)> {
     let w = params
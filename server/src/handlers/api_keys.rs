@@ -0,0 +1,109 @@
+use crate::auth::{hash_token, new_api_key, now_secs};
+use crate::db;
+use crate::handlers::auth::AdminUser;
+use crate::models::{ApiKey, Scope};
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(serde::Deserialize)]
+pub struct CreateApiKeyBody {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // unix seconds; omitted for a key that never expires
+    pub expires_at: Option<i64>,
+    // account the key acts as; defaults to the calling admin
+    pub user_id: Option<i64>,
+}
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// GET /admin/api_keys: every key with its scopes, expiry and last use.
+pub async fn list_api_keys_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    let keys = db::list_api_keys(&pool).await.map_err(internal)?;
+    Ok(Json(json!({ "api_keys": keys })))
+}
+
+/// POST /admin/api_keys: create a key. The key itself is only in this
+/// response; the database keeps its hash.
+pub async fn create_api_key_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    AdminUser(admin): AdminUser,
+    Json(body): Json<CreateApiKeyBody>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    let bad = |msg: &str| (StatusCode::BAD_REQUEST, msg.to_string());
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(bad("name must not be empty"));
+    }
+    if body.scopes.is_empty() {
+        return Err(bad("at least one scope is required"));
+    }
+    if body.expires_at.is_some_and(|t| t <= now_secs()) {
+        return Err(bad("expires_at must be in the future"));
+    }
+    let owner = match body.user_id {
+        Some(id) => db::get_user(&pool, id)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| bad("no such user"))?,
+        None => admin,
+    };
+    // Scanning and administration are refused to viewers whatever the key says
+    if !owner.is_admin()
+        && body
+            .scopes
+            .iter()
+            .any(|s| matches!(s, Scope::Scan | Scope::Admin))
+    {
+        return Err(bad("scan and admin scopes need an admin user"));
+    }
+
+    let mut scopes = body.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let key = new_api_key();
+    let id = db::create_api_key(
+        &pool,
+        name,
+        &hash_token(&key),
+        owner.id,
+        &scopes,
+        body.expires_at,
+    )
+    .await
+    .map_err(internal)?;
+    let api_key: ApiKey = db::get_api_key(&pool, id).await.map_err(internal)?.ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "key vanished".to_string(),
+    ))?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "key": key, "api_key": api_key })),
+    ))
+}
+
+/// DELETE /admin/api_keys/{id}: revoke a key immediately.
+pub async fn revoke_api_key_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    if db::delete_api_key(&pool, id).await.map_err(internal)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "No such API key".to_string()))
+    }
+}
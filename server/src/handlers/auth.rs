@@ -0,0 +1,259 @@
+use crate::auth::{
+    dummy_password_hash, hash_password, hash_token, new_token, now_secs, request_token,
+    required_scope, verify_password, API_KEY_PREFIX, SESSION_COOKIE, SESSION_TTL_SECS,
+};
+use crate::db;
+use crate::models::User;
+use crate::state::AppState;
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{Extension, FromRequestParts, State};
+use axum::http::header::{SET_COOKIE, WWW_AUTHENTICATE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(serde::Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct LoginResponse {
+    pub token: String,
+    // unix seconds
+    pub expires_at: i64,
+    pub user: User,
+}
+
+// Browsers only send Secure cookies over HTTPS; plain-HTTP setups turn it off
+pub(crate) fn secure_attr(secure: bool) -> &'static str {
+    if secure {
+        "; Secure"
+    } else {
+        ""
+    }
+}
+
+/// POST /auth/login: check a username and password and start a session.
+/// The token is returned in the body for API clients and set as an
+/// HttpOnly cookie for the browser.
+pub async fn login_handler(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<LoginRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.lock().await;
+    let pool = guard.pool.clone();
+    let cookie_secure = guard.cookie_secure;
+    drop(guard);
+    let invalid = (
+        StatusCode::UNAUTHORIZED,
+        "invalid username or password".to_string(),
+    );
+    let (user, hash) = match db::get_user_credentials(&pool, &req.username)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    {
+        Some((user, hash)) if !hash.is_empty() => (Some(user), hash),
+        // Unknown users and pending invites take as long to fail as a wrong password
+        _ => (None, dummy_password_hash().to_string()),
+    };
+    // argon2 is deliberately slow; keep it off the async workers
+    let ok = tokio::task::spawn_blocking(move || verify_password(&req.password, &hash))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let user = match user {
+        Some(user) if ok => user,
+        _ => return Err(invalid),
+    };
+    if user.disabled {
+        return Err((StatusCode::FORBIDDEN, "account disabled".to_string()));
+    }
+
+    let token = new_token();
+    let now = now_secs();
+    let expires_at = now + SESSION_TTL_SECS;
+    db::create_session(&pool, &hash_token(&token), user.id, expires_at, now)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SESSION_COOKIE,
+        token,
+        SESSION_TTL_SECS,
+        secure_attr(cookie_secure)
+    );
+    Ok((
+        [(SET_COOKIE, cookie)],
+        Json(LoginResponse {
+            token,
+            expires_at,
+            user,
+        }),
+    )
+        .into_response())
+}
+
+/// POST /auth/logout: end the session the request was made with.
+pub async fn logout_handler(
+    State(state): State<Arc<Mutex<AppState>>>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.lock().await;
+    let pool = guard.pool.clone();
+    let cookie_secure = guard.cookie_secure;
+    drop(guard);
+    if let Some(token) = request_token(&headers) {
+        db::delete_session(&pool, &hash_token(&token))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    let cookie = format!(
+        "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0{}",
+        SESSION_COOKIE,
+        secure_attr(cookie_secure)
+    );
+    Ok((StatusCode::NO_CONTENT, [(SET_COOKIE, cookie)]).into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub struct AcceptInviteRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// POST /auth/accept_invite: set the password of an invited or reset
+/// account. The invite is used up; log in afterwards as usual.
+pub async fn accept_invite_handler(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(req): Json<AcceptInviteRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let pool = state.lock().await.pool.clone();
+    if req.password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "password must not be empty".to_string(),
+        ));
+    }
+    let user_id = db::get_invite_user(&pool, &hash_token(&req.token), now_secs())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            "invalid or expired invite".to_string(),
+        ))?;
+    let hash = tokio::task::spawn_blocking(move || hash_password(&req.password))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    db::set_password(&pool, user_id, &hash)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /auth/me: the logged-in user.
+pub async fn me_handler(Extension(user): Extension<User>) -> Json<User> {
+    Json(user)
+}
+
+/// Middleware for the API routes: rejects requests without a valid session
+/// or API key with 401 and makes the caller's `User` available as an
+/// extension. API keys outside their scopes get 403.
+pub async fn require_auth(
+    State(state): State<Arc<Mutex<AppState>>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let pool = state.lock().await.pool.clone();
+    let now = now_secs();
+    let user = match request_token(req.headers()) {
+        Some(token) if token.starts_with(API_KEY_PREFIX) => {
+            match db::get_api_key_user(&pool, &hash_token(&token), now).await {
+                Ok(Some((key, user))) => {
+                    let scope = required_scope(req.method(), req.uri().path());
+                    if !key.scopes.contains(&scope) {
+                        return (
+                            StatusCode::FORBIDDEN,
+                            format!("API key lacks the {} scope", scope.as_str()),
+                        )
+                            .into_response();
+                    }
+                    if let Err(e) = db::touch_api_key(&pool, key.id, now).await {
+                        tracing::warn!("Failed to record use of API key {}: {}", key.id, e);
+                    }
+                    Some(user)
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                }
+            }
+        }
+        Some(token) => match db::get_session_user(&pool, &hash_token(&token), now).await {
+            Ok(user) => user,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => None,
+    };
+    match user {
+        Some(user) => {
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            "authentication required",
+        )
+            .into_response(),
+    }
+}
+
+/// Extractor for handlers only administrators may call: 403 for viewers.
+/// Relies on `require_auth` having run first.
+pub struct AdminUser(pub User);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts.extensions.get::<User>().cloned().ok_or((
+            StatusCode::UNAUTHORIZED,
+            "authentication required".to_string(),
+        ))?;
+        if !user.is_admin() {
+            return Err((StatusCode::FORBIDDEN, "admin role required".to_string()));
+        }
+        Ok(AdminUser(user))
+    }
+}
+
+/// The user whose grants filter listings, or None for admins, who see
+/// every directory.
+pub(crate) fn acl_viewer(user: &User) -> Option<i64> {
+    (!user.is_admin()).then_some(user.id)
+}
+
+/// Fail with 404 unless `user` may see media `id`, so entries outside the
+/// user's grants look the same as ones that do not exist.
+pub(crate) async fn ensure_access(
+    pool: &SqlitePool,
+    user: &User,
+    id: i64,
+) -> Result<(), (StatusCode, String)> {
+    if user.is_admin() {
+        return Ok(());
+    }
+    match db::can_access(pool, user.id, id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
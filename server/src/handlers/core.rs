@@ -1,25 +1,95 @@
 use crate::db;
-use crate::models::MediaEntry;
+use crate::handlers::auth::{acl_viewer, ensure_access, AdminUser};
+use crate::jobs::ScanJobStatus;
+use crate::models::{MediaEntry, User};
+use crate::probe;
 use crate::scanner;
 use crate::state::AppState;
-use axum::{extract::State, http::StatusCode, Json};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    Json,
+};
 use serde_json::json;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// POST /scan: start a background scan and return its job right away. If a
+/// scan is already running, that job is returned instead of starting another.
+/// Admins only.
 pub async fn trigger_scan_handler(
     state: State<Arc<Mutex<AppState>>>,
-) -> Result<Json<&'static str>, (StatusCode, String)> {
+    _admin: AdminUser,
+) -> (StatusCode, Json<ScanJobStatus>) {
     let guard = state.0.lock().await;
     let pool = guard.pool.clone();
     let dir = guard.directory_to_scan.clone();
+    let thumbs_dir = guard.thumbnails_dir.clone().map(std::path::PathBuf::from);
+    let jobs = guard.scan_jobs.clone();
+    // ffprobe stage runs after the walk only when ffmpeg is enabled
+    let ffprobe_path = guard.ffmpeg_enabled.then(|| {
+        guard
+            .ffprobe_path
+            .clone()
+            .unwrap_or_else(|| "ffprobe".to_string())
+    });
     drop(guard);
 
-    scanner::scan_directory_and_index(pool, dir, None)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let mut registry = jobs.lock().await;
+    if let Some(job) = registry.running() {
+        return (StatusCode::ACCEPTED, Json(job.status()));
+    }
+    let job = registry.start();
+    let id = job.id;
+    let progress = job.progress.clone();
+    let status = job.status();
+    drop(registry);
+
+    tokio::spawn(async move {
+        // The scan runs on its own task so that a panic still finishes the
+        // job instead of leaving it running and refusing every later scan
+        let scan = tokio::spawn(async move {
+            let root = std::path::PathBuf::from(&dir);
+            let mut result = scanner::scan_directory_and_index(
+                pool.clone(),
+                dir,
+                None,
+                thumbs_dir,
+                Some(&progress),
+            )
+            .await;
+            if let (Ok(summary), Some(ffprobe)) = (&mut result, ffprobe_path) {
+                match probe::probe_pending(&pool, &root, &ffprobe).await {
+                    Ok(n) => summary.probed = n,
+                    Err(e) => result = Err(e),
+                }
+            }
+            result
+        });
+        let result = scan
+            .await
+            .unwrap_or_else(|e| Err(format!("scan aborted: {}", e)));
+        if let Err(e) = &result {
+            tracing::error!("Scan job {} failed: {}", id, e);
+        }
+        jobs.lock().await.finish(id, result);
+    });
+
+    (StatusCode::ACCEPTED, Json(status))
+}
 
-    Ok(Json("Directory scan completed."))
+/// GET /scan/{id}: progress and outcome of a scan job.
+pub async fn get_scan_job_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> Result<Json<ScanJobStatus>, (StatusCode, String)> {
+    let jobs = state.0.lock().await.scan_jobs.clone();
+    let registry = jobs.lock().await;
+    match registry.get(id) {
+        Some(job) => Ok(Json(job.status())),
+        None => Err((StatusCode::NOT_FOUND, "Scan job not found".to_string())),
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -75,6 +145,7 @@ fn to_enriched_json(e: &MediaEntry) -> serde_json::Value {
 
 pub async fn list_directory_handler(
     state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
     axum::extract::Query(q): axum::extract::Query<ListQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let guard = state.0.lock().await;
@@ -106,6 +177,7 @@ pub async fn list_directory_handler(
 
         match opt {
             Some(entry) => {
+                ensure_access(&pool, &user, entry.id).await?;
                 // if directory (mime_type is None), list its children
                 if entry.mime_type.is_none() {
                     let rows = db::list_children_advanced(
@@ -118,6 +190,7 @@ pub async fn list_directory_handler(
                         q.offset,
                         q.sort.as_deref(),
                         q.order.as_deref(),
+                        acl_viewer(&user),
                     )
                     .await
                     .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
@@ -134,6 +207,9 @@ pub async fn list_directory_handler(
         }
     } else {
         // use parent_id (may be None) to list children
+        if let Some(parent_id) = q.parent_id {
+            ensure_access(&pool, &user, parent_id).await?;
+        }
         let rows = db::list_children_advanced(
             pool.clone(),
             q.parent_id,
@@ -144,6 +220,7 @@ pub async fn list_directory_handler(
             q.offset,
             q.sort.as_deref(),
             q.order.as_deref(),
+            acl_viewer(&user),
         )
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
@@ -161,6 +238,7 @@ pub struct DetailsQuery {
 
 pub async fn get_file_details_handler(
     state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
     axum::extract::Query(q): axum::extract::Query<DetailsQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let key = q.path.clone().unwrap_or_default();
@@ -170,17 +248,10 @@ pub async fn get_file_details_handler(
     drop(guard);
 
     // try id then path
-    if let Ok(id) = key.parse::<i64>() {
-        let opt = db::get_media_by_id(pool, id)
+    let opt = if let Ok(id) = key.parse::<i64>() {
+        db::get_media_by_id(pool.clone(), id)
             .await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-        match opt {
-            Some(entry) => {
-                let val = to_enriched_json(&entry);
-                Ok(Json(val))
-            }
-            _ => Err((StatusCode::NOT_FOUND, "File not found".to_string())),
-        }
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
     } else {
         // validate relative path
         if key.starts_with('/') || key.contains("..") {
@@ -190,12 +261,65 @@ pub async fn get_file_details_handler(
             ));
         }
 
-        let opt = db::get_media_by_path(pool, key)
+        db::get_media_by_path(pool.clone(), key)
             .await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-        match opt {
-            Some(entry) => Ok(Json(to_enriched_json(&entry))),
-            _ => Err((StatusCode::NOT_FOUND, "File not found".to_string())),
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    };
+    let entry = opt.ok_or((StatusCode::NOT_FOUND, "File not found".to_string()))?;
+    ensure_access(&pool, &user, entry.id).await?;
+
+    let mut val = to_enriched_json(&entry);
+    let metadata = db::get_media_metadata(&pool, entry.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let probe = db::get_media_probe(&pool, entry.id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let serde_json::Value::Object(map) = &mut val {
+        if let Some(m) = metadata {
+            map.insert("metadata".to_string(), json!(m));
+        }
+        if let Some(p) = probe {
+            map.insert("probe".to_string(), json!(p));
         }
     }
+    Ok(Json(val))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub r#type: Option<String>, // "file" | "directory"
+    pub kind: Option<String>,   // "image" | "video" | "audio" | "other"
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+pub async fn search_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    axum::extract::Query(q): axum::extract::Query<SearchQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    drop(guard);
+
+    let text = q.q.unwrap_or_default();
+    if text.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "q must not be empty".to_string()));
+    }
+
+    let rows = db::search_media(
+        pool,
+        &text,
+        q.r#type.as_deref(),
+        q.kind.as_deref(),
+        q.limit,
+        q.offset,
+        acl_viewer(&user),
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let enriched: Vec<serde_json::Value> = rows.into_iter().map(|e| to_enriched_json(&e)).collect();
+    Ok(Json(json!({ "files": enriched })))
 }
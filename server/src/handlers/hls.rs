@@ -0,0 +1,439 @@
+use crate::db;
+use crate::handlers::auth::ensure_access;
+use crate::models::{MediaEntry, User};
+use crate::state::AppState;
+use axum::body::StreamBody;
+use axum::extract::{Extension, Path as UrlPath, State};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::Response;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
+
+/// Target length of each HLS segment, in seconds.
+pub const SEGMENT_SECS: u32 = 6;
+
+// Advertised in the master playlist; segments are encoded well under it
+const VARIANT_BANDWIDTH: u64 = 5_000_000;
+
+// Segments are scaled down to fit 1080p (either orientation) and encoded as
+// High profile level 4.2, which covers that size up to 60 fps
+const MAX_LONG_SIDE: i64 = 1920;
+const MAX_SHORT_SIDE: i64 = 1080;
+const VIDEO_CODEC: &str = "avc1.64002a";
+const X264_LEVEL: &str = "4.2";
+
+// ffmpeg scale filter applying the same bound as `output_size`
+const SCALE_FILTER: &str = "scale=w='min(iw,if(gte(iw,ih),1920,1080))':h='min(ih,if(gte(iw,ih),1080,1920))':force_original_aspect_ratio=decrease:force_divisible_by=2";
+
+/// Size of the transcoded video for a `width` x `height` source: the source
+/// size, scaled down to fit 1080p if larger.
+pub fn output_size(width: i64, height: i64) -> (i64, i64) {
+    let (max_w, max_h) = if width >= height {
+        (MAX_LONG_SIDE, MAX_SHORT_SIDE)
+    } else {
+        (MAX_SHORT_SIDE, MAX_LONG_SIDE)
+    };
+    if width <= max_w && height <= max_h {
+        return (width, height);
+    }
+    let scale = (max_w as f64 / width as f64).min(max_h as f64 / height as f64);
+    let even = |v: f64| (v as i64) & !1;
+    (even(width as f64 * scale), even(height as f64 * scale))
+}
+
+/// Master playlist pointing at the single H.264/AAC variant.
+pub fn master_playlist(width: Option<i64>, height: Option<i64>) -> String {
+    let mut inf = format!(
+        "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{},mp4a.40.2\"",
+        VARIANT_BANDWIDTH, VIDEO_CODEC
+    );
+    if let (Some(w), Some(h)) = (width, height) {
+        let (w, h) = output_size(w, h);
+        inf.push_str(&format!(",RESOLUTION={}x{}", w, h));
+    }
+    format!("#EXTM3U\n#EXT-X-VERSION:3\n{}\nindex.m3u8\n", inf)
+}
+
+/// VOD media playlist splitting `duration` seconds into fixed-length segments
+/// named `seg<N>.ts`. The last segment carries the remainder.
+pub fn media_playlist(duration: f64, segment_secs: u32) -> String {
+    let seg = segment_secs as f64;
+    let count = segment_count(duration, segment_secs);
+    let mut out = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        segment_secs
+    );
+    for n in 0..count {
+        let len = (duration - n as f64 * seg).min(seg);
+        out.push_str(&format!("#EXTINF:{:.3},\nseg{}.ts\n", len, n));
+    }
+    out.push_str("#EXT-X-ENDLIST\n");
+    out
+}
+
+fn segment_count(duration: f64, segment_secs: u32) -> u32 {
+    if duration <= 0.0 {
+        return 0;
+    }
+    (duration / segment_secs as f64).ceil() as u32
+}
+
+/// Parse a segment file name (`seg<N>.ts`) into its index.
+pub fn parse_segment_name(name: &str) -> Option<u32> {
+    let n = name.strip_prefix("seg")?.strip_suffix(".ts")?;
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    n.parse().ok()
+}
+
+/// GET /media/hls/{id}/{file}: `master.m3u8`, `index.m3u8` or `seg<N>.ts`.
+/// Segments are transcoded to H.264/AAC on first request and cached.
+pub async fn hls_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    UrlPath((id, file)): UrlPath<(i64, String)>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    let media_root = guard.directory_to_scan.clone();
+    let ffmpeg_enabled = guard.ffmpeg_enabled;
+    let ffmpeg_path = guard
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| "ffmpeg".to_string());
+    let ffprobe_path = guard
+        .ffprobe_path
+        .clone()
+        .unwrap_or_else(|| "ffprobe".to_string());
+    let transcode_dir = guard.transcode_dir.clone().map(PathBuf::from);
+    let transcode_sem = guard.transcode_semaphore.clone();
+    drop(guard);
+
+    let entry = db::get_media_by_id(pool.clone(), id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+    ensure_access(&pool, &user, entry.id).await?;
+    if !entry
+        .mime_type
+        .as_deref()
+        .unwrap_or("")
+        .starts_with("video/")
+    {
+        return Err((StatusCode::BAD_REQUEST, "Not a video".to_string()));
+    }
+    if !ffmpeg_enabled {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ffmpeg disabled".to_string(),
+        ));
+    }
+    let transcode_dir = transcode_dir.ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "transcode cache not configured".to_string(),
+    ))?;
+
+    if file == "master.m3u8" {
+        return Ok(playlist_response(master_playlist(
+            entry.width,
+            entry.height,
+        )));
+    }
+
+    let src = Path::new(&media_root).join(&entry.path);
+    let cache_dir = cache_dir_for(&transcode_dir, &entry, &src).await?;
+    if !cache_dir.exists() {
+        remove_old_cache_dirs(&transcode_dir, entry.id, &cache_dir).await;
+    }
+    tokio::fs::create_dir_all(&cache_dir)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if file == "index.m3u8" {
+        return Ok(playlist_response(
+            index_playlist(&cache_dir, &ffprobe_path, &src).await?,
+        ));
+    }
+
+    let n = parse_segment_name(&file).ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+    let seg_path = cache_dir.join(&file);
+    if !seg_path.exists() {
+        // Only segments listed in the media playlist are transcoded; anything
+        // past the end would just make ffmpeg seek beyond the input
+        let index = index_playlist(&cache_dir, &ffprobe_path, &src).await?;
+        if !index.lines().any(|l| l == file) {
+            return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+        }
+        let _permit = transcode_sem
+            .acquire()
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        // Another request may have produced it while we waited for a permit
+        if !seg_path.exists() {
+            transcode_segment(&ffmpeg_path, &src, &seg_path, n)
+                .await
+                .map_err(|e| {
+                    tracing::error!("HLS transcode failed for {} seg {}: {}", entry.path, n, e);
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "transcode failed".to_string(),
+                    )
+                })?;
+        }
+    }
+
+    let f = tokio::fs::File::open(&seg_path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut res = Response::new(axum::body::boxed(StreamBody::new(ReaderStream::new(f))));
+    res.headers_mut()
+        .insert("content-type", HeaderValue::from_static("video/mp2t"));
+    Ok(res)
+}
+
+// The media playlist of a cache directory, probing the source and caching
+// the result on first use. Segment requests are checked against the same
+// playlist so both agree on where the video ends.
+async fn index_playlist(
+    cache_dir: &Path,
+    ffprobe_path: &str,
+    src: &Path,
+) -> Result<String, (StatusCode, String)> {
+    let index_path = cache_dir.join("index.m3u8");
+    if let Ok(body) = tokio::fs::read_to_string(&index_path).await {
+        return Ok(body);
+    }
+    let duration = probe_duration(ffprobe_path, src).await.ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "could not determine duration".to_string(),
+    ))?;
+    let body = media_playlist(duration, SEGMENT_SECS);
+    write_atomic(&index_path, body.as_bytes()).await;
+    Ok(body)
+}
+
+fn playlist_response(body: String) -> Response {
+    let mut res = Response::new(axum::body::boxed(axum::body::Full::from(body)));
+    res.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_static("application/vnd.apple.mpegurl"),
+    );
+    res
+}
+
+// Cache directory for an entry, keyed by id plus the source's size and mtime
+// so an edited file does not reuse segments of the old one.
+async fn cache_dir_for(
+    transcode_dir: &Path,
+    entry: &MediaEntry,
+    src: &Path,
+) -> Result<PathBuf, (StatusCode, String)> {
+    let meta = tokio::fs::metadata(src).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            (
+                StatusCode::NOT_FOUND,
+                "File no longer exists on disk".to_string(),
+            )
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    })?;
+    Ok(transcode_dir.join(cache_dir_name(entry.id, &meta)))
+}
+
+fn cache_dir_name(id: i64, meta: &std::fs::Metadata) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(meta.len().to_le_bytes());
+    if let Some(dur) = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    {
+        hasher.update(dur.as_nanos().to_le_bytes());
+    }
+    let fp = format!("{:x}", hasher.finalize());
+    format!("{}_{}", id, &fp[..16])
+}
+
+// Drop the segments of older versions of entry `id` once a new cache
+// directory is started for it
+async fn remove_old_cache_dirs(transcode_dir: &Path, id: i64, keep: &Path) {
+    let prefix = format!("{}_", id);
+    let mut read_dir = match tokio::fs::read_dir(transcode_dir).await {
+        Ok(rd) => rd,
+        Err(_) => return,
+    };
+    while let Ok(Some(e)) = read_dir.next_entry().await {
+        let is_old = e
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.starts_with(&prefix));
+        if is_old && e.path() != keep {
+            let _ = tokio::fs::remove_dir_all(e.path()).await;
+        }
+    }
+}
+
+/// Delete transcode cache directories whose media row is gone or whose
+/// source has changed since they were made, and segment temp files older
+/// than an hour. Returns how many directories were removed.
+pub async fn remove_stale_transcodes(
+    pool: &SqlitePool,
+    transcode_dir: &Path,
+    media_root: &Path,
+) -> usize {
+    let mut read_dir = match tokio::fs::read_dir(transcode_dir).await {
+        Ok(rd) => rd,
+        Err(_) => return 0,
+    };
+    let mut removed = 0;
+    while let Ok(Some(e)) = read_dir.next_entry().await {
+        let name = e.file_name().to_string_lossy().to_string();
+        let id = match name
+            .split_once('_')
+            .and_then(|(id, _)| id.parse::<i64>().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let live = match db::get_media_by_id(pool.clone(), id).await {
+            Ok(Some(entry)) => tokio::fs::metadata(media_root.join(&entry.path))
+                .await
+                .is_ok_and(|m| cache_dir_name(id, &m) == name),
+            Ok(None) => false,
+            // Keep the cache as is rather than guess on a DB error
+            Err(_) => continue,
+        };
+        if !live {
+            if tokio::fs::remove_dir_all(e.path()).await.is_ok() {
+                removed += 1;
+            }
+            continue;
+        }
+        if let Ok(files) = std::fs::read_dir(e.path()) {
+            let now = std::time::SystemTime::now();
+            for f in files.flatten() {
+                let old = f
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|m| now.duration_since(m).ok())
+                    .is_some_and(|age| age.as_secs() > 60 * 60);
+                if old && f.file_name().to_string_lossy().ends_with(".tmp") {
+                    let _ = std::fs::remove_file(f.path());
+                }
+            }
+        }
+    }
+    removed
+}
+
+pub(crate) async fn probe_duration(ffprobe_path: &str, src: &Path) -> Option<f64> {
+    let output = Command::new(ffprobe_path)
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            src.to_string_lossy().as_ref(),
+        ])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+// Encode segment `n` on its own. Timestamps are offset to the segment start
+// so consecutive segments play back as one continuous stream.
+async fn transcode_segment(
+    ffmpeg_path: &str,
+    src: &Path,
+    out: &Path,
+    n: u32,
+) -> Result<(), String> {
+    let start = (n as f64 * SEGMENT_SECS as f64).to_string();
+    let tmp = out.with_extension(format!(
+        "{}.tmp",
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    let output = Command::new(ffmpeg_path)
+        .args([
+            "-v",
+            "error",
+            "-ss",
+            &start,
+            "-t",
+            &SEGMENT_SECS.to_string(),
+            "-i",
+            src.to_string_lossy().as_ref(),
+            "-map",
+            "0:v:0",
+            "-map",
+            "0:a:0?",
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-vf",
+            SCALE_FILTER,
+            "-profile:v",
+            "high",
+            "-level:v",
+            X264_LEVEL,
+            "-pix_fmt",
+            "yuv420p",
+            "-c:a",
+            "aac",
+            "-ac",
+            "2",
+            "-b:a",
+            "160k",
+            "-output_ts_offset",
+            &start,
+            "-muxdelay",
+            "0",
+            "-f",
+            "mpegts",
+            "-y",
+            tmp.to_string_lossy().as_ref(),
+        ])
+        .output()
+        .await
+        .map_err(|e| format!("failed to spawn ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    tokio::fs::rename(&tmp, out)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn write_atomic(path: &Path, data: &[u8]) {
+    // Unique per writer, since concurrent first requests all write the index
+    let tmp = path.with_extension(format!(
+        "m3u8.{}.tmp",
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    if tokio::fs::write(&tmp, data).await.is_ok() {
+        let _ = tokio::fs::rename(&tmp, path).await;
+    }
+}
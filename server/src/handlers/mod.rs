@@ -1,8 +1,39 @@
 pub mod admin;
+pub mod api_keys;
+pub mod auth;
 pub mod core;
+pub mod hls;
+pub mod music;
+pub mod preview;
+pub mod shares;
+pub mod sprites;
 pub mod streaming;
+pub mod tags;
 pub mod thumbnails;
+pub mod users;
 
-pub use core::{get_file_details_handler, list_directory_handler, trigger_scan_handler};
+pub use api_keys::{create_api_key_handler, list_api_keys_handler, revoke_api_key_handler};
+pub use auth::{
+    accept_invite_handler, login_handler, logout_handler, me_handler, require_auth, AdminUser,
+};
+pub use core::{
+    get_file_details_handler, get_scan_job_handler, list_directory_handler, search_handler,
+    trigger_scan_handler,
+};
+pub use hls::hls_handler;
+pub use music::{
+    get_album_handler, list_albums_handler, list_artists_handler, list_tracks_handler,
+};
+pub use preview::preview_handler;
+pub use shares::{
+    create_share_handler, list_shares_handler, revoke_share_handler, shared_handler,
+    unlock_share_handler,
+};
+pub use sprites::sprite_handler;
 pub use streaming::stream_handler;
+pub use tags::{add_tags_handler, list_tags_handler, remove_tags_handler};
 pub use thumbnails::{generate_thumbnail_handler, thumbnail_handler};
+pub use users::{
+    delete_user_handler, get_user_handler, invite_user_handler, list_users_handler,
+    reset_user_handler, update_user_handler,
+};
@@ -0,0 +1,105 @@
+use crate::db;
+use crate::handlers::auth::acl_viewer;
+use crate::models::User;
+use crate::state::AppState;
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(serde::Deserialize)]
+pub struct MusicQuery {
+    pub artist_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub genre: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+async fn pool_of(state: &State<Arc<Mutex<AppState>>>) -> sqlx::SqlitePool {
+    state.0.lock().await.pool.clone()
+}
+
+/// GET /music/artists
+pub async fn list_artists_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    Query(q): Query<MusicQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let artists = db::list_artists(&pool, q.limit, q.offset, acl_viewer(&user))
+        .await
+        .map_err(internal)?;
+    Ok(Json(json!({ "artists": artists })))
+}
+
+/// GET /music/albums?artist_id={id}
+pub async fn list_albums_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    Query(q): Query<MusicQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let albums = db::list_albums(&pool, q.artist_id, q.limit, q.offset, acl_viewer(&user))
+        .await
+        .map_err(internal)?;
+    Ok(Json(json!({ "albums": albums })))
+}
+
+/// GET /music/albums/{id}: the album with its tracks in play order.
+pub async fn get_album_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let album = db::get_album(&pool, id, acl_viewer(&user))
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Album not found".to_string()))?;
+    let tracks = db::list_tracks(
+        &pool,
+        None,
+        Some(id),
+        None,
+        Some(i64::MAX),
+        None,
+        acl_viewer(&user),
+    )
+    .await
+    .map_err(internal)?;
+    let mut val = json!(album);
+    if let serde_json::Value::Object(map) = &mut val {
+        map.insert("tracks".to_string(), json!(tracks));
+    }
+    Ok(Json(val))
+}
+
+/// GET /music/tracks?artist_id={id}&album_id={id}&genre={name}
+pub async fn list_tracks_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    Query(q): Query<MusicQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let tracks = db::list_tracks(
+        &pool,
+        q.artist_id,
+        q.album_id,
+        q.genre.as_deref(),
+        q.limit,
+        q.offset,
+        acl_viewer(&user),
+    )
+    .await
+    .map_err(internal)?;
+    Ok(Json(json!({ "tracks": tracks })))
+}
@@ -0,0 +1,300 @@
+use crate::db;
+use crate::handlers::auth::ensure_access;
+use crate::handlers::hls::probe_duration;
+use crate::handlers::streaming::serve_file;
+use crate::handlers::thumbnails::{run_deduped, source_fingerprint, touch_thumbnail};
+use crate::models::User;
+use crate::state::AppState;
+use axum::extract::{Extension, Query, State};
+use axum::http::{Request, StatusCode};
+use axum::response::Response;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+/// Number of evenly spaced clips stitched into a preview.
+pub const PREVIEW_CLIPS: u32 = 5;
+/// Length of each clip, in seconds.
+pub const PREVIEW_CLIP_SECS: f64 = 1.0;
+/// Width of the preview; the height keeps the aspect ratio.
+pub const PREVIEW_WIDTH: u32 = 320;
+const PREVIEW_FPS: u32 = 12;
+
+#[derive(serde::Deserialize)]
+pub struct PreviewQuery {
+    pub id: Option<i64>,
+    pub path: Option<String>,
+    /// `mp4` (default) or `webp`
+    pub format: Option<String>,
+}
+
+/// Container of an animated preview.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewFormat {
+    Mp4,
+    WebP,
+}
+
+impl PreviewFormat {
+    pub fn parse(s: &str) -> Option<PreviewFormat> {
+        match s {
+            "mp4" => Some(PreviewFormat::Mp4),
+            "webp" => Some(PreviewFormat::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PreviewFormat::Mp4 => "mp4",
+            PreviewFormat::WebP => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PreviewFormat::Mp4 => "video/mp4",
+            PreviewFormat::WebP => "image/webp",
+        }
+    }
+}
+
+/// Start and length of each clip in a preview of a `duration`-second video.
+/// Clips are centred on evenly spaced points, skipping the very start and
+/// end; videos too short to split are previewed from the beginning.
+pub fn preview_clips(duration: f64) -> Vec<(f64, f64)> {
+    let total = PREVIEW_CLIPS as f64 * PREVIEW_CLIP_SECS;
+    if duration <= total * 2.0 {
+        return vec![(0.0, duration.clamp(0.0, total).max(PREVIEW_CLIP_SECS))];
+    }
+    (1..=PREVIEW_CLIPS)
+        .map(|i| {
+            let centre = duration * i as f64 / (PREVIEW_CLIPS + 1) as f64;
+            let start = (centre - PREVIEW_CLIP_SECS / 2.0).clamp(0.0, duration - PREVIEW_CLIP_SECS);
+            ((start * 1000.0).round() / 1000.0, PREVIEW_CLIP_SECS)
+        })
+        .collect()
+}
+
+/// ffmpeg arguments that cut `clips` out of `src`, scale them down, join
+/// them and write a muted `format` preview to `out`.
+pub fn preview_ffmpeg_args(
+    src: &Path,
+    clips: &[(f64, f64)],
+    format: PreviewFormat,
+    out: &Path,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec!["-v".into(), "error".into()];
+    // Seeking before each input keeps it fast on long videos
+    for (start, len) in clips {
+        args.extend([
+            "-ss".into(),
+            format!("{:.3}", start),
+            "-t".into(),
+            format!("{:.3}", len),
+            "-i".into(),
+            src.to_string_lossy().to_string(),
+        ]);
+    }
+    let mut filter = String::new();
+    for i in 0..clips.len() {
+        filter.push_str(&format!(
+            "[{}:v:0]scale={}:-2,fps={},setsar=1[v{}];",
+            i, PREVIEW_WIDTH, PREVIEW_FPS, i
+        ));
+    }
+    for i in 0..clips.len() {
+        filter.push_str(&format!("[v{}]", i));
+    }
+    filter.push_str(&format!("concat=n={}:v=1:a=0[out]", clips.len()));
+    args.extend([
+        "-filter_complex".into(),
+        filter,
+        "-map".into(),
+        "[out]".into(),
+        "-an".into(),
+    ]);
+    let codec: &[&str] = match format {
+        PreviewFormat::Mp4 => &[
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-crf",
+            "28",
+            "-pix_fmt",
+            "yuv420p",
+            "-movflags",
+            "+faststart",
+        ],
+        PreviewFormat::WebP => &["-c:v", "libwebp", "-loop", "0", "-quality", "60"],
+    };
+    args.extend(codec.iter().map(|s| s.to_string()));
+    args.extend(["-y".into(), out.to_string_lossy().to_string()]);
+    args
+}
+
+/// GET /media/preview?id={id}&format=mp4|webp: a short, muted preview of a
+/// video stitched from several points in it. Generated on first request,
+/// bounded by the thumbnail semaphore, and cached with thumbnails.
+pub async fn preview_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    Query(q): Query<PreviewQuery>,
+    req: Request<axum::body::Body>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    let media_root = guard.directory_to_scan.clone();
+    let ffmpeg_enabled = guard.ffmpeg_enabled;
+    let ffmpeg_path = guard
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| "ffmpeg".to_string());
+    let ffprobe_path = guard
+        .ffprobe_path
+        .clone()
+        .unwrap_or_else(|| "ffprobe".to_string());
+    let thumbs_dir = guard.thumbnails_dir.clone().map(PathBuf::from);
+    let regen_sem = guard.regen_semaphore.clone();
+    let in_flight = guard.in_flight.clone();
+    drop(guard);
+
+    let format = match q.format.as_deref() {
+        None => PreviewFormat::Mp4,
+        Some(f) => PreviewFormat::parse(f).ok_or((
+            StatusCode::BAD_REQUEST,
+            "format must be mp4 or webp".to_string(),
+        ))?,
+    };
+
+    // Locate entry by id or path
+    let opt = if let Some(id) = q.id {
+        db::get_media_by_id(pool.clone(), id)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    } else if let Some(p) = q.path.clone() {
+        if p.starts_with('/') || p.contains("..") {
+            return Err((StatusCode::BAD_REQUEST, "path must be relative".to_string()));
+        }
+        db::get_media_by_path(pool.clone(), p)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    } else {
+        None
+    };
+    let entry = opt.ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+    ensure_access(&pool, &user, entry.id).await?;
+    if !entry
+        .mime_type
+        .as_deref()
+        .unwrap_or("")
+        .starts_with("video/")
+    {
+        return Err((StatusCode::BAD_REQUEST, "Not a video".to_string()));
+    }
+    if !ffmpeg_enabled {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ffmpeg disabled".to_string(),
+        ));
+    }
+    let thumbs_dir = thumbs_dir.ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "thumbnails cache not configured".to_string(),
+    ))?;
+
+    let src = Path::new(&media_root).join(&entry.path);
+    let meta = tokio::fs::metadata(&src).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            (
+                StatusCode::NOT_FOUND,
+                "File no longer exists on disk".to_string(),
+            )
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    })?;
+    let name = format!(
+        "{}_{}_preview.{}",
+        entry.id,
+        source_fingerprint(&entry.path, &meta),
+        format.extension()
+    );
+    let out_path = thumbs_dir.join(&name);
+
+    if !out_path.exists() {
+        let out_path = out_path.clone();
+        let duration_secs = entry.duration_secs;
+        let work = async move {
+            let _permit = regen_sem.acquire().await.map_err(|e| e.to_string())?;
+            if out_path.exists() {
+                return Ok(());
+            }
+            let duration = match duration_secs {
+                Some(d) if d > 0 => d as f64,
+                _ => probe_duration(&ffprobe_path, &src)
+                    .await
+                    .ok_or("could not determine duration")?,
+            };
+            render_preview(
+                &ffmpeg_path,
+                &src,
+                &preview_clips(duration),
+                format,
+                &out_path,
+            )
+            .await
+        };
+        let key = format!("{}:preview.{}", entry.id, format.extension());
+        run_deduped(&in_flight, key, work).await.map_err(|e| {
+            tracing::error!("preview generation failed for {}: {}", entry.path, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "preview generation failed".to_string(),
+            )
+        })?;
+    }
+
+    touch_thumbnail(&out_path);
+    serve_file(
+        req.headers(),
+        req.method(),
+        &out_path,
+        &name,
+        format.content_type(),
+    )
+    .await
+}
+
+async fn render_preview(
+    ffmpeg_path: &str,
+    src: &Path,
+    clips: &[(f64, f64)],
+    format: PreviewFormat,
+    out: &Path,
+) -> Result<(), String> {
+    // ffmpeg picks the muxer from the extension, so keep it last
+    let tmp = out.with_extension(format!(
+        "{}.{}",
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos(),
+        format.extension()
+    ));
+    let output = Command::new(ffmpeg_path)
+        .args(preview_ffmpeg_args(src, clips, format, &tmp))
+        .output()
+        .await
+        .map_err(|e| format!("failed to spawn ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    tokio::fs::rename(&tmp, out)
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,332 @@
+use crate::auth::{
+    cookie_value, hash_password, hash_token, new_token, now_secs, share_access_token,
+    verify_password, verify_share_access, SHARE_ACCESS_COOKIE, SHARE_ACCESS_TTL_SECS,
+};
+use crate::db;
+use crate::handlers::auth::{acl_viewer, ensure_access, secure_attr, AdminUser};
+use crate::handlers::streaming::{parse_range_header, serve_file};
+use crate::models::{MediaEntry, Share, User};
+use crate::state::AppState;
+use axum::body::Body;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::header::{RANGE, SET_COOKIE};
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(serde::Deserialize)]
+pub struct CreateShareBody {
+    // media id of the file or directory to share
+    pub id: i64,
+    // unix seconds; omitted for a link that never expires
+    pub expires_at: Option<i64>,
+    pub password: Option<String>,
+    // number of file downloads allowed before the link stops working
+    pub max_downloads: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SharedQuery {
+    // path below the shared directory, e.g. path=renders/final.mp4
+    pub path: Option<String>,
+    // token from `POST /s/{token}/unlock`, for clients that cannot keep
+    // the cookie it sets
+    pub access: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnlockShareBody {
+    pub password: String,
+}
+
+/// Header carrying the password of a protected share.
+pub const SHARE_PASSWORD_HEADER: &str = "x-share-password";
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// POST /shares: create a public link to an entry. Admins only, since a
+/// share publishes the entry to anyone holding the link.
+pub async fn create_share_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    AdminUser(user): AdminUser,
+    Json(body): Json<CreateShareBody>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    let bad = |msg: &str| (StatusCode::BAD_REQUEST, msg.to_string());
+    if db::get_media_by_id(pool.clone(), body.id)
+        .await
+        .map_err(internal)?
+        .is_none()
+    {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+    if body.expires_at.is_some_and(|t| t <= now_secs()) {
+        return Err(bad("expires_at must be in the future"));
+    }
+    if body.max_downloads.is_some_and(|n| n < 1) {
+        return Err(bad("max_downloads must be at least 1"));
+    }
+    let password_hash = match body.password {
+        Some(p) if p.is_empty() => return Err(bad("password must not be empty")),
+        Some(p) => Some(
+            tokio::task::spawn_blocking(move || hash_password(&p))
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?,
+        ),
+        None => None,
+    };
+
+    let token = new_token();
+    let id = db::create_share(
+        &pool,
+        &hash_token(&token),
+        body.id,
+        user.id,
+        body.expires_at,
+        password_hash.as_deref(),
+        body.max_downloads,
+    )
+    .await
+    .map_err(internal)?;
+    let share: Option<Share> = db::get_share(&pool, id).await.map_err(internal)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "token": token, "url": format!("/s/{}", token), "share": share })),
+    ))
+}
+
+/// GET /admin/shares: every share with its expiry and download count.
+pub async fn list_shares_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    let shares = db::list_shares(&pool).await.map_err(internal)?;
+    Ok(Json(json!({ "shares": shares })))
+}
+
+/// DELETE /admin/shares/{id}: revoke a share immediately.
+pub async fn revoke_share_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    UrlPath(id): UrlPath<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let pool = state.0.lock().await.pool.clone();
+    if db::delete_share(&pool, id).await.map_err(internal)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "No such share".to_string()))
+    }
+}
+
+// Directory entries as seen through a share: paths are relative to the
+// shared entry and ids are left out, so nothing above it is revealed
+fn shared_entry_json(e: &MediaEntry, root: &str) -> serde_json::Value {
+    let rel = e
+        .path
+        .strip_prefix(root)
+        .map(|p| p.trim_start_matches('/'))
+        .unwrap_or(&e.name);
+    json!({
+        "name": e.name,
+        "path": rel,
+        "type": if e.mime_type.is_none() { "directory" } else { "file" },
+        "mime_type": e.mime_type,
+        "size": e.size,
+        "width": e.width,
+        "height": e.height,
+        "duration_secs": e.duration_secs,
+    })
+}
+
+// A GET counts as a download when its response carries the start of the
+// file: any full 200, or a 206 whose merged ranges cover byte 0. HEADs, 304s
+// and the follow-up ranges a player requests while seeking do not
+fn is_download(method: &Method, headers: &HeaderMap, status: StatusCode, total_size: u64) -> bool {
+    if method != Method::GET {
+        return false;
+    }
+    match status {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => headers
+            .get(RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|range| parse_range_header(range, total_size).ok().flatten())
+            .is_some_and(|ranges| ranges.first().is_some_and(|&(start, _)| start == 0)),
+        _ => false,
+    }
+}
+
+// The share behind a public token: 404 if unknown or its creator is
+// disabled, 410 once expired or used up
+async fn live_share(
+    pool: &sqlx::SqlitePool,
+    token: &str,
+) -> Result<(Share, Option<String>, User), (StatusCode, String)> {
+    let (share, password_hash, owner) = db::get_share_by_token(pool, &hash_token(token))
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+    let used_up = share.max_downloads.is_some_and(|n| share.downloads >= n);
+    if share.expires_at.is_some_and(|t| t <= now_secs()) || used_up {
+        return Err((StatusCode::GONE, "share has expired".to_string()));
+    }
+    Ok((share, password_hash, owner))
+}
+
+async fn check_password(password: String, hash: String) -> Result<bool, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// POST /s/{token}/unlock: exchange the password of a protected share for
+/// a short-lived access token, set as an HttpOnly cookie scoped to the
+/// share and returned in the body for use as `?access=`.
+pub async fn unlock_share_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    UrlPath(token): UrlPath<String>,
+    Json(body): Json<UnlockShareBody>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    let cookie_secure = guard.cookie_secure;
+    drop(guard);
+
+    let (share, password_hash, _) = live_share(&pool, &token).await?;
+    let hash =
+        password_hash.ok_or((StatusCode::BAD_REQUEST, "share has no password".to_string()))?;
+    if !check_password(body.password, hash.clone()).await? {
+        return Err((StatusCode::UNAUTHORIZED, "wrong password".to_string()));
+    }
+    let now = now_secs();
+    let expires_at = share.expires_at.map_or(now + SHARE_ACCESS_TTL_SECS, |t| {
+        t.min(now + SHARE_ACCESS_TTL_SECS)
+    });
+    let access = share_access_token(share.id, &hash, expires_at);
+    let cookie = format!(
+        "{}={}; Path=/s/{}; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SHARE_ACCESS_COOKIE,
+        access,
+        token,
+        expires_at - now,
+        secure_attr(cookie_secure)
+    );
+    Ok((
+        [(SET_COOKIE, cookie)],
+        Json(json!({ "access": access, "expires_at": expires_at })),
+    )
+        .into_response())
+}
+
+/// GET /s/{token}: public access to a share, no login needed. A shared
+/// directory is listed as JSON, and `path` selects a directory or file
+/// below it; files are streamed like `/media/stream`.
+pub async fn shared_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    UrlPath(token): UrlPath<String>,
+    Query(q): Query<SharedQuery>,
+    req: Request<Body>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    let media_root = guard.directory_to_scan.clone();
+    drop(guard);
+
+    let not_found = (StatusCode::NOT_FOUND, "Not found".to_string());
+    let (share, password_hash, owner) = live_share(&pool, &token).await?;
+    if let Some(hash) = password_hash {
+        // Links, downloads and <video> elements cannot send the header, so
+        // an access token from the unlock endpoint stands in for it
+        let access = q
+            .access
+            .clone()
+            .or_else(|| cookie_value(req.headers(), SHARE_ACCESS_COOKIE));
+        if !access.is_some_and(|a| verify_share_access(&a, share.id, &hash, now_secs())) {
+            // Sent as a header so it stays out of access logs and browser history
+            let password = req
+                .headers()
+                .get(SHARE_PASSWORD_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            if !check_password(password, hash).await? {
+                return Err((StatusCode::UNAUTHORIZED, "password required".to_string()));
+            }
+        }
+    }
+
+    let target = match q.path.as_deref().filter(|p| !p.is_empty()) {
+        Some(rel) => {
+            if rel.starts_with('/') || rel.split('/').any(|c| c == "..") {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "path must be relative to the share".to_string(),
+                ));
+            }
+            format!("{}/{}", share.path, rel.trim_end_matches('/'))
+        }
+        None => share.path.clone(),
+    };
+    let entry = db::get_media_by_path(pool.clone(), target)
+        .await
+        .map_err(internal)?
+        .ok_or(not_found)?;
+    // The link sees no more than the user who created it
+    ensure_access(&pool, &owner, entry.id).await?;
+
+    if entry.mime_type.is_none() {
+        let rows = db::list_children_advanced(
+            pool.clone(),
+            Some(entry.id),
+            None,
+            None,
+            None,
+            q.limit,
+            q.offset,
+            None,
+            None,
+            acl_viewer(&owner),
+        )
+        .await
+        .map_err(internal)?;
+        let files: Vec<serde_json::Value> = rows
+            .iter()
+            .map(|e| shared_entry_json(e, &share.path))
+            .collect();
+        return Ok(Json(json!({
+            "name": entry.name,
+            "expires_at": share.expires_at,
+            "files": files,
+        }))
+        .into_response());
+    }
+
+    let file_path = Path::new(&media_root).join(&entry.path);
+    let ctype = entry
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let res = serve_file(req.headers(), req.method(), &file_path, &entry.path, &ctype).await?;
+    let total_size = tokio::fs::metadata(&file_path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if is_download(req.method(), req.headers(), res.status(), total_size)
+        && !db::count_share_download(&pool, share.id)
+            .await
+            .map_err(internal)?
+    {
+        return Err((StatusCode::GONE, "share has expired".to_string()));
+    }
+    Ok(res)
+}
@@ -0,0 +1,272 @@
+use crate::db;
+use crate::handlers::auth::ensure_access;
+use crate::handlers::hls::probe_duration;
+use crate::handlers::thumbnails::{run_deduped, source_fingerprint, touch_thumbnail};
+use crate::models::User;
+use crate::state::AppState;
+use axum::body::StreamBody;
+use axum::extract::{Extension, Path as UrlPath, State};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::Response;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
+
+/// Seconds between sprite frames, raised for long videos to stay under
+/// `MAX_TILES`.
+pub const SPRITE_INTERVAL_SECS: u32 = 10;
+/// Width of one tile; the height follows the video's aspect ratio.
+pub const TILE_WIDTH: u32 = 160;
+/// Tiles per sprite sheet row.
+pub const SPRITE_COLUMNS: u32 = 10;
+/// Most tiles one sheet holds, keeping the image well inside JPEG limits.
+pub const MAX_TILES: u32 = 400;
+
+/// How a video's frames are laid out on its sprite sheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteLayout {
+    pub interval_secs: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    pub count: u32,
+}
+
+/// Layout for a video of `duration` seconds. Tiles keep the aspect ratio of
+/// `width`x`height` (16:9 when unknown) with an even height for the encoder.
+pub fn sprite_layout(duration: f64, width: Option<i64>, height: Option<i64>) -> SpriteLayout {
+    let interval_secs = SPRITE_INTERVAL_SECS.max((duration / MAX_TILES as f64).ceil() as u32);
+    let count = ((duration / interval_secs as f64).ceil() as u32).max(1);
+    let tile_height = match (width, height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => {
+            let h = (TILE_WIDTH as f64 * h as f64 / w as f64).round() as u32;
+            (h + h % 2).max(2)
+        }
+        _ => TILE_WIDTH * 9 / 16,
+    };
+    let columns = SPRITE_COLUMNS.min(count);
+    SpriteLayout {
+        interval_secs,
+        tile_width: TILE_WIDTH,
+        tile_height,
+        columns,
+        rows: count.div_ceil(columns),
+        count,
+    }
+}
+
+/// WebVTT track mapping each interval to its tile on `image_url` through
+/// media fragments (`#xywh=x,y,w,h`). The last cue ends at `duration`.
+pub fn sprite_vtt(layout: &SpriteLayout, duration: f64, image_url: &str) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for n in 0..layout.count {
+        let start = (n * layout.interval_secs) as f64;
+        let end = (start + layout.interval_secs as f64).min(duration.max(start));
+        let x = (n % layout.columns) * layout.tile_width;
+        let y = (n / layout.columns) * layout.tile_height;
+        out.push_str(&format!(
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            image_url,
+            x,
+            y,
+            layout.tile_width,
+            layout.tile_height
+        ));
+    }
+    out
+}
+
+fn vtt_timestamp(secs: f64) -> String {
+    let ms = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// GET /media/sprites/{id}/{file}: `sprite.jpg`, a tiled sheet of frames
+/// every few seconds, or `sprite.vtt`, the WebVTT track pointing into it.
+/// Both are generated together on first request and cached with thumbnails.
+pub async fn sprite_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    UrlPath((id, file)): UrlPath<(i64, String)>,
+) -> Result<Response, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    let media_root = guard.directory_to_scan.clone();
+    let ffmpeg_enabled = guard.ffmpeg_enabled;
+    let ffmpeg_path = guard
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| "ffmpeg".to_string());
+    let ffprobe_path = guard
+        .ffprobe_path
+        .clone()
+        .unwrap_or_else(|| "ffprobe".to_string());
+    let thumbs_dir = guard.thumbnails_dir.clone().map(PathBuf::from);
+    let regen_sem = guard.regen_semaphore.clone();
+    let in_flight = guard.in_flight.clone();
+    drop(guard);
+
+    let content_type = match file.as_str() {
+        "sprite.jpg" => "image/jpeg",
+        "sprite.vtt" => "text/vtt; charset=utf-8",
+        _ => return Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+    };
+    let entry = db::get_media_by_id(pool.clone(), id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+    ensure_access(&pool, &user, entry.id).await?;
+    if !entry
+        .mime_type
+        .as_deref()
+        .unwrap_or("")
+        .starts_with("video/")
+    {
+        return Err((StatusCode::BAD_REQUEST, "Not a video".to_string()));
+    }
+    if !ffmpeg_enabled {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ffmpeg disabled".to_string(),
+        ));
+    }
+    let thumbs_dir = thumbs_dir.ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "thumbnails cache not configured".to_string(),
+    ))?;
+
+    let src = Path::new(&media_root).join(&entry.path);
+    let meta = tokio::fs::metadata(&src).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            (
+                StatusCode::NOT_FOUND,
+                "File no longer exists on disk".to_string(),
+            )
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    })?;
+    // Named like thumbnails so orphan GC and eviction cover sprites as well
+    let stem = format!("{}_{}_sprite", id, source_fingerprint(&entry.path, &meta));
+    let jpg_path = thumbs_dir.join(format!("{}.jpg", stem));
+    let vtt_path = thumbs_dir.join(format!("{}.vtt", stem));
+
+    if !(jpg_path.exists() && vtt_path.exists()) {
+        let (jpg_path, vtt_path) = (jpg_path.clone(), vtt_path.clone());
+        let (duration_secs, width, height) = (entry.duration_secs, entry.width, entry.height);
+        let work = async move {
+            let _permit = regen_sem.acquire().await.map_err(|e| e.to_string())?;
+            // Another request may have finished the sheet while this one queued
+            if jpg_path.exists() && vtt_path.exists() {
+                return Ok(());
+            }
+            let duration = match duration_secs {
+                Some(d) if d > 0 => d as f64,
+                _ => probe_duration(&ffprobe_path, &src)
+                    .await
+                    .ok_or("could not determine duration")?,
+            };
+            let layout = sprite_layout(duration, width, height);
+            render_sprite(&ffmpeg_path, &src, &jpg_path, &layout).await?;
+            let vtt = sprite_vtt(&layout, duration, "sprite.jpg");
+            write_atomic(&vtt_path, vtt.as_bytes()).await
+        };
+        run_deduped(&in_flight, format!("{}:sprite", id), work)
+            .await
+            .map_err(|e| {
+                tracing::error!("sprite generation failed for {}: {}", entry.path, e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "sprite generation failed".to_string(),
+                )
+            })?;
+    }
+
+    let path = if file == "sprite.jpg" {
+        jpg_path
+    } else {
+        vtt_path
+    };
+    touch_thumbnail(&path);
+    let f = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut res = Response::new(axum::body::boxed(StreamBody::new(ReaderStream::new(f))));
+    res.headers_mut()
+        .insert("content-type", HeaderValue::from_static(content_type));
+    Ok(res)
+}
+
+// Grab one frame per interval, scale it to the tile size and tile the lot
+// into a single JPEG.
+async fn render_sprite(
+    ffmpeg_path: &str,
+    src: &Path,
+    out: &Path,
+    layout: &SpriteLayout,
+) -> Result<(), String> {
+    let tmp = out.with_extension(format!(
+        "{}.jpg",
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    let filter = format!(
+        "fps=1/{},scale={}:{},tile={}x{}",
+        layout.interval_secs, layout.tile_width, layout.tile_height, layout.columns, layout.rows
+    );
+    let output = Command::new(ffmpeg_path)
+        .args([
+            "-v",
+            "error",
+            "-i",
+            src.to_string_lossy().as_ref(),
+            "-vf",
+            &filter,
+            "-frames:v",
+            "1",
+            "-q:v",
+            "4",
+            "-y",
+            tmp.to_string_lossy().as_ref(),
+        ])
+        .output()
+        .await
+        .map_err(|e| format!("failed to spawn ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    tokio::fs::rename(&tmp, out)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension(format!(
+        "{}.vtt",
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    tokio::fs::write(&tmp, data)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -1,15 +1,21 @@
 use crate::db;
+use crate::handlers::auth::ensure_access;
+use crate::models::User;
 use crate::state::AppState;
-use axum::body::StreamBody;
-use axum::http::{HeaderValue, Request, StatusCode as AxumStatusCode};
+use axum::body::{Bytes, StreamBody};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode as AxumStatusCode};
 use axum::response::Response;
-use axum::{extract::State, http::StatusCode};
-use httpdate::fmt_http_date;
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+};
+use futures::{future, stream, StreamExt};
+use httpdate::{fmt_http_date, parse_http_date};
 use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
-use tokio::io::AsyncSeekExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::Mutex;
 use tokio_util::io::ReaderStream;
 
@@ -21,6 +27,7 @@ pub struct StreamQuery {
 
 pub async fn stream_handler(
     state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
     axum::extract::Query(q): axum::extract::Query<StreamQuery>,
     req: Request<axum::body::Body>,
 ) -> Result<Response, (StatusCode, String)> {
@@ -49,105 +56,130 @@ pub async fn stream_handler(
         Some(e) => e,
         None => return Err((StatusCode::NOT_FOUND, "Not found".to_string())),
     };
+    ensure_access(&pool, &user, entry.id).await?;
 
     let file_path = Path::new(&media_root).join(&entry.path);
-    let meta = tokio::fs::metadata(&file_path)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let ctype = entry
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    serve_file(req.headers(), req.method(), &file_path, &entry.path, &ctype).await
+}
+
+/// Serve `file_path` with the same validators, conditional request handling,
+/// byte ranges and HEAD support as `/media/stream`. The ETag is derived from
+/// `etag_key` plus the file's size and mtime.
+pub async fn serve_file(
+    headers: &HeaderMap,
+    method: &Method,
+    file_path: &Path,
+    etag_key: &str,
+    ctype: &str,
+) -> Result<Response, (StatusCode, String)> {
+    let meta = tokio::fs::metadata(file_path).await.map_err(|e| {
+        // The row may outlive the file until the next scan prunes it
+        if e.kind() == std::io::ErrorKind::NotFound {
+            (
+                StatusCode::NOT_FOUND,
+                "File no longer exists on disk".to_string(),
+            )
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    })?;
     let total_size = meta.len();
     // Try to get modified time
     let modified = meta.modified().ok();
 
     // Compute a simple ETag using size + mtime (if available) + path
     let mut hasher = Sha256::new();
-    hasher.update(entry.path.as_bytes());
-    hasher.update(&total_size.to_le_bytes());
+    hasher.update(etag_key.as_bytes());
+    hasher.update(total_size.to_le_bytes());
     if let Some(m) = modified {
         if let Ok(dur) = m.duration_since(UNIX_EPOCH) {
-            hasher.update(&dur.as_secs().to_le_bytes());
-            hasher.update(&dur.subsec_nanos().to_le_bytes());
+            hasher.update(dur.as_secs().to_le_bytes());
+            hasher.update(dur.subsec_nanos().to_le_bytes());
         }
     }
     let result = hasher.finalize();
     let etag = format!("\"{:x}\"", result);
 
-    // Honor If-None-Match
-    if let Some(if_none) = req.headers().get("if-none-match") {
-        if if_none.to_str().unwrap_or("") == etag {
+    // Conditional request handling (RFC 7232)
+    match evaluate_preconditions(headers, &etag, modified) {
+        Precondition::Proceed => {}
+        Precondition::Failed => {
+            return Err((
+                StatusCode::PRECONDITION_FAILED,
+                "Precondition failed".to_string(),
+            ))
+        }
+        Precondition::NotModified => {
             let mut resp = Response::new(axum::body::boxed(axum::body::Empty::new()));
             *resp.status_mut() = axum::http::StatusCode::NOT_MODIFIED;
             resp.headers_mut()
                 .insert("ETag", HeaderValue::from_str(&etag).unwrap());
+            if let Some(m) = modified {
+                if let Ok(v) = HeaderValue::from_str(&fmt_http_date(m)) {
+                    resp.headers_mut().insert("Last-Modified", v);
+                }
+            }
             return Ok(resp);
         }
     }
 
-    // Parse and validate Range header (single range only)
-    let (range_start, range_end, is_partial) = if let Some(hv) = req.headers().get("range") {
-        if let Ok(s) = hv.to_str() {
-            if s.starts_with("bytes=") {
-                let rest = &s[6..];
-                let parts: Vec<&str> = rest.split('-').collect();
-                if parts.len() == 2 {
-                    let start_opt = if !parts[0].is_empty() {
-                        parts[0].parse::<u64>().ok()
-                    } else {
-                        None
-                    };
-                    let end_opt = if !parts[1].is_empty() {
-                        parts[1].parse::<u64>().ok()
-                    } else {
-                        None
-                    };
-                    match (start_opt, end_opt) {
-                        (Some(start), Some(end)) if start <= end && end < total_size => {
-                            (start, end, true)
-                        }
-                        (Some(start), None) if start < total_size => (start, total_size - 1, true),
-                        (None, Some(end)) if end != 0 && end < total_size => {
-                            (total_size - end, total_size - 1, true)
-                        }
-                        _ => {
-                            // Invalid range
-                            return Err((
-                                StatusCode::RANGE_NOT_SATISFIABLE,
-                                format!("Invalid Range: {}", s),
-                            ));
-                        }
-                    }
-                } else {
-                    // Malformed range
-                    return Err((
-                        StatusCode::RANGE_NOT_SATISFIABLE,
-                        format!("Malformed Range: {}", s),
-                    ));
-                }
-            } else {
-                // Not a bytes range
-                (0, total_size - 1, false)
-            }
-        } else {
-            (0, total_size - 1, false)
+    // Parse and validate Range header
+    // A stale If-Range means the client's partial copy is outdated: send it all
+    let range_header = headers
+        .get("range")
+        .and_then(|hv| hv.to_str().ok())
+        .filter(|_| if_range_allows(headers, &etag, modified));
+    let ranges = match range_header {
+        Some(s) => {
+            parse_range_header(s, total_size).map_err(|e| (StatusCode::RANGE_NOT_SATISFIABLE, e))?
         }
-    } else {
-        (0, total_size - 1, false)
+        None => None,
     };
 
-    let length = range_end.saturating_sub(range_start) + 1;
-    let file = tokio::fs::File::open(&file_path)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let mut reader = tokio::io::BufReader::new(file);
-    reader
-        .seek(std::io::SeekFrom::Start(range_start))
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    use tokio::io::AsyncReadExt;
-    let limited = reader.take(length);
-    let stream = ReaderStream::new(limited);
-    let body = StreamBody::new(stream);
-    let boxed = axum::body::boxed(body);
-    let mut res = Response::new(boxed);
+    let mut res = match ranges.as_deref() {
+        Some([(start, end)]) => {
+            let mut res = Response::new(axum::body::boxed(StreamBody::new(
+                open_range(file_path, *start, *end).await?,
+            )));
+            *res.status_mut() = AxumStatusCode::PARTIAL_CONTENT;
+            let content_range = format!("bytes {}-{}/{}", start, end, total_size);
+            res.headers_mut().insert(
+                "Content-Range",
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            res.headers_mut().insert(
+                "Content-Length",
+                HeaderValue::from_str(&(end - start + 1).to_string()).unwrap(),
+            );
+            res.headers_mut().insert(
+                "content-type",
+                HeaderValue::from_str(ctype)
+                    .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+            );
+            res
+        }
+        Some(ranges) => multipart_response(file_path, ranges, total_size, ctype, &etag).await?,
+        None => {
+            let mut res = Response::new(axum::body::boxed(StreamBody::new(
+                open_range(file_path, 0, total_size.saturating_sub(1)).await?,
+            )));
+            res.headers_mut().insert(
+                "Content-Length",
+                HeaderValue::from_str(&total_size.to_string()).unwrap(),
+            );
+            res.headers_mut().insert(
+                "content-type",
+                HeaderValue::from_str(ctype)
+                    .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+            );
+            res
+        }
+    };
     res.headers_mut()
         .insert("Accept-Ranges", HeaderValue::from_static("bytes"));
     res.headers_mut()
@@ -159,26 +191,260 @@ pub async fn stream_handler(
             HeaderValue::from_str(&s).unwrap_or(HeaderValue::from_static("")),
         );
     }
-    let ctype = entry
-        .mime_type
-        .clone()
-        .unwrap_or_else(|| "application/octet-stream".to_string());
+    // HEAD gets the same headers, including Content-Length, without the body
+    if method == Method::HEAD {
+        let (parts, _) = res.into_parts();
+        return Ok(Response::from_parts(
+            parts,
+            axum::body::boxed(axum::body::Empty::new()),
+        ));
+    }
+    Ok(res)
+}
+
+/// Outcome of evaluating the conditional request headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluate If-Match, If-Unmodified-Since, If-None-Match and
+/// If-Modified-Since in the order given by RFC 7232 section 6, for a GET or
+/// HEAD of an existing representation.
+pub fn evaluate_preconditions(
+    headers: &HeaderMap,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> Precondition {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(v) = header("if-match") {
+        if !etag_list_matches(v, etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(v), Some(m)) = (header("if-unmodified-since"), modified) {
+        if let Ok(since) = parse_http_date(v) {
+            if unix_secs(m) > unix_secs(since) {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(v) = header("if-none-match") {
+        if etag_list_matches(v, etag, true) {
+            return Precondition::NotModified;
+        }
+    } else if let (Some(v), Some(m)) = (header("if-modified-since"), modified) {
+        if let Ok(since) = parse_http_date(v) {
+            if unix_secs(m) <= unix_secs(since) {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Whether a Range header should be honored given If-Range. Without If-Range
+/// it always is; otherwise the validator must still match the current file.
+pub fn if_range_allows(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let v = match headers.get("if-range").and_then(|v| v.to_str().ok()) {
+        Some(v) => v.trim(),
+        None => return true,
+    };
+    if v.starts_with('"') || v.starts_with("W/") {
+        // Weak validators never match for If-Range
+        return !v.starts_with("W/") && v == etag;
+    }
+    match (parse_http_date(v), modified) {
+        (Ok(date), Some(m)) => unix_secs(m) == unix_secs(date),
+        _ => false,
+    }
+}
+
+// Match a comma-separated list of entity tags (or "*") against our strong
+// `etag`. Weak comparison ignores the W/ prefix; strong comparison rejects it.
+fn etag_list_matches(list: &str, etag: &str, weak: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',')
+        .map(|t| t.trim())
+        .any(|t| match t.strip_prefix("W/") {
+            Some(opaque) => weak && opaque == etag,
+            None => t == etag,
+        })
+}
+
+// HTTP dates have one-second resolution, so compare whole seconds
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Upper bound on the number of ranges accepted in one Range header.
+pub const MAX_RANGES: usize = 16;
+
+/// Parse a `Range` header against a file of `total_size` bytes.
+///
+/// Returns `Ok(None)` when the header does not use the `bytes` unit and should
+/// be ignored, or the satisfiable ranges as inclusive `(start, end)` pairs,
+/// sorted and with overlapping or adjacent ranges merged. Errors map to 416.
+pub fn parse_range_header(
+    header: &str,
+    total_size: u64,
+) -> Result<Option<Vec<(u64, u64)>>, String> {
+    let rest = match header.trim().strip_prefix("bytes=") {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let specs: Vec<&str> = rest
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if specs.is_empty() {
+        return Err(format!("Malformed Range: {}", header));
+    }
+    if specs.len() > MAX_RANGES {
+        return Err(format!("Too many ranges (max {})", MAX_RANGES));
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec
+            .split_once('-')
+            .ok_or_else(|| format!("Malformed Range: {}", header))?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Malformed Range: {}", header))
+        };
+        match (first.trim().is_empty(), last.trim().is_empty()) {
+            // "start-end" and "start-"
+            (false, _) => {
+                let start = parse(first)?;
+                let end = if last.trim().is_empty() {
+                    u64::MAX
+                } else {
+                    parse(last)?
+                };
+                if start > end {
+                    return Err(format!("Malformed Range: {}", header));
+                }
+                if start < total_size {
+                    ranges.push((start, end.min(total_size - 1)));
+                }
+            }
+            // "-suffix": the last N bytes
+            (true, false) => {
+                let suffix = parse(last)?;
+                if suffix > 0 && total_size > 0 {
+                    ranges.push((total_size.saturating_sub(suffix), total_size - 1));
+                }
+            }
+            (true, true) => return Err(format!("Malformed Range: {}", header)),
+        }
+    }
+
+    if ranges.is_empty() {
+        return Err(format!("Invalid Range: {}", header));
+    }
+    Ok(Some(coalesce_ranges(ranges)))
+}
+
+// Sort ranges and merge the ones that overlap or touch
+fn coalesce_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Stream bytes `start..=end` of the file
+async fn open_range(
+    file_path: &Path,
+    start: u64,
+    end: u64,
+) -> Result<
+    ReaderStream<tokio::io::Take<tokio::io::BufReader<tokio::fs::File>>>,
+    (StatusCode, String),
+> {
+    let file = tokio::fs::File::open(file_path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut reader = tokio::io::BufReader::new(file);
+    reader
+        .seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let length = if end >= start { end - start + 1 } else { 0 };
+    Ok(ReaderStream::new(reader.take(length)))
+}
+
+// Build a 206 multipart/byteranges response with one part per range
+async fn multipart_response(
+    file_path: &Path,
+    ranges: &[(u64, u64)],
+    total_size: u64,
+    ctype: &str,
+    etag: &str,
+) -> Result<Response, (StatusCode, String)> {
+    // The boundary only has to be absent from the payload; tie it to this
+    // file version and request time so it cannot be predicted from the file.
+    let mut hasher = Sha256::new();
+    hasher.update(etag.as_bytes());
+    hasher.update(
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+            .to_le_bytes(),
+    );
+    let boundary = format!("{:x}", hasher.finalize())[..32].to_string();
+
+    let mut parts = Vec::with_capacity(ranges.len() + 1);
+    let mut content_length: u64 = 0;
+    for (i, (start, end)) in ranges.iter().enumerate() {
+        let head = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            ctype,
+            start,
+            end,
+            total_size
+        );
+        content_length += head.len() as u64 + (end - start + 1);
+        let data = open_range(file_path, *start, *end).await?;
+        let part = stream::once(future::ready(Ok::<_, std::io::Error>(Bytes::from(head))))
+            .chain(data)
+            .boxed();
+        parts.push(part);
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    content_length += tail.len() as u64;
+    parts.push(stream::once(future::ready(Ok(Bytes::from(tail)))).boxed());
+
+    let body = StreamBody::new(stream::iter(parts).flatten());
+    let mut res = Response::new(axum::body::boxed(body));
+    *res.status_mut() = AxumStatusCode::PARTIAL_CONTENT;
     res.headers_mut().insert(
         "content-type",
-        HeaderValue::from_str(&ctype)
-            .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+        HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
     );
     res.headers_mut().insert(
         "Content-Length",
-        HeaderValue::from_str(&length.to_string()).unwrap(),
+        HeaderValue::from_str(&content_length.to_string()).unwrap(),
     );
-    if is_partial {
-        *res.status_mut() = AxumStatusCode::PARTIAL_CONTENT;
-        let content_range = format!("bytes {}-{}/{}", range_start, range_end, total_size);
-        res.headers_mut().insert(
-            "Content-Range",
-            HeaderValue::from_str(&content_range).unwrap(),
-        );
-    }
     Ok(res)
 }
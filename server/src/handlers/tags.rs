@@ -0,0 +1,88 @@
+use crate::db;
+use crate::handlers::auth::{acl_viewer, AdminUser};
+use crate::models::User;
+use crate::state::AppState;
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct TagsBody {
+    pub tags: Vec<String>,
+}
+
+// Shared body of the add/remove handlers: checks the entry exists, applies
+// the change and returns the entry's resulting tags.
+async fn update_tags(
+    state: State<Arc<Mutex<AppState>>>,
+    id: i64,
+    tags: Vec<String>,
+    add: bool,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    drop(guard);
+
+    let internal = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    if db::get_media_by_id(pool.clone(), id)
+        .await
+        .map_err(internal)?
+        .is_none()
+    {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+
+    if add {
+        db::add_media_tags(&pool, id, &tags)
+            .await
+            .map_err(internal)?;
+    } else {
+        db::remove_media_tags(&pool, id, &tags)
+            .await
+            .map_err(internal)?;
+    }
+    let current = db::get_media_tags(&pool, id).await.map_err(internal)?;
+    Ok(Json(json!({ "id": id, "tags": current })))
+}
+
+/// POST /media/{id}/tags: attach the given tags to an entry. Admins only.
+pub async fn add_tags_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+    Json(body): Json<TagsBody>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    update_tags(state, id, body.tags, true).await
+}
+
+/// DELETE /media/{id}/tags: detach the given tags from an entry. Admins only.
+pub async fn remove_tags_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+    Json(body): Json<TagsBody>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    update_tags(state, id, body.tags, false).await
+}
+
+/// GET /tags: every tag in use with its entry count, counting only
+/// entries the caller can see.
+pub async fn list_tags_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let guard = state.0.lock().await;
+    let pool = guard.pool.clone();
+    drop(guard);
+
+    let tags = db::list_tags_with_counts(&pool, acl_viewer(&user))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(json!({ "tags": tags })))
+}
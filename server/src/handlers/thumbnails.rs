@@ -1,19 +1,31 @@
 use crate::db;
-use crate::models;
-use crate::state::AppState;
+use crate::handlers::auth::ensure_access;
+use crate::handlers::hls::probe_duration;
+use crate::metadata;
+use crate::models::{self, User};
+use crate::state::{AppState, InFlightMap};
 use axum::body::StreamBody;
-use axum::http::HeaderValue;
+use axum::http::header::ACCEPT;
+use axum::http::{HeaderMap, HeaderValue};
 use axum::response::{IntoResponse, Redirect, Response};
-use axum::{extract::State, http::StatusCode};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+};
 use httpdate::fmt_http_date;
 use image::{imageops::FilterType, ImageOutputFormat};
 use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::process::Command;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex, Semaphore};
 use tokio_util::io::ReaderStream;
 
 #[derive(serde::Deserialize)]
@@ -34,17 +46,27 @@ pub struct GenThumbQuery {
 
 pub async fn thumbnail_handler(
     state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
     axum::extract::Query(q): axum::extract::Query<ThumbQuery>,
 ) -> Result<Response, (StatusCode, String)> {
     let guard = state.0.lock().await;
     let pool = guard.pool.clone();
-    let _media_root = guard.directory_to_scan.clone();
+    let media_root = guard.directory_to_scan.clone();
+    let ffmpeg_path = guard.ffmpeg_enabled.then(|| {
+        guard
+            .ffmpeg_path
+            .clone()
+            .unwrap_or_else(|| "ffmpeg".to_string())
+    });
     // resolve thumbnails dir from state (must be provided by main)
     let thumbs_dir = guard
         .thumbnails_dir
         .clone()
         .map(PathBuf::from)
         .expect("thumbnails_dir must be configured in AppState");
+    let regen_sem = guard.regen_semaphore.clone();
+    let in_flight = guard.in_flight.clone();
     drop(guard);
 
     // Locate entry by id or path
@@ -67,58 +89,98 @@ pub async fn thumbnail_handler(
         Some(e) => e,
         None => return Err((StatusCode::NOT_FOUND, "Not found".to_string())),
     };
+    ensure_access(&pool, &user, entry.id).await?;
 
     // If thumbnail exists on disk, serve it. Otherwise, try to generate for images.
     let _ = tokio::fs::create_dir_all(&thumbs_dir).await;
 
-    if let Some(tp) = entry.thumb_path.clone() {
+    // Thumbnails of an older version of the source are left for regeneration
+    let fingerprint = tokio::fs::metadata(Path::new(&media_root).join(&entry.path))
+        .await
+        .ok()
+        .map(|m| source_fingerprint(&entry.path, &m));
+    if let (Some(tp), Some(fp)) = (entry.thumb_path.clone(), fingerprint) {
         // We store thumbnail paths as URL paths under `/thumbnails/<name>`.
         // Resolve the final segment and serve the corresponding file from
         // the configured thumbnails directory.
-        if let Some(fname) = Path::new(&tp).file_name().and_then(|s| s.to_str()) {
-            let fs_path = thumbs_dir.join(fname);
-            if fs_path.exists() {
-                let meta = tokio::fs::metadata(&fs_path)
-                    .await
-                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-                let total_size = meta.len();
-                let modified = meta.modified().ok();
-
-                // compute etag
-                let mut hasher = Sha256::new();
-                hasher.update(entry.path.as_bytes());
-                hasher.update(&total_size.to_le_bytes());
-                if let Some(m) = modified {
-                    if let Ok(dur) = m.duration_since(UNIX_EPOCH) {
-                        hasher.update(&dur.as_secs().to_le_bytes());
-                        hasher.update(&dur.subsec_nanos().to_le_bytes());
+        let fname = Path::new(&tp)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .filter(|f| parse_thumbnail_name(f).is_some_and(|(id, f)| id == entry.id && f == fp));
+        if let Some(fname) = fname.filter(|f| thumbs_dir.join(f).exists()) {
+            let accept = headers.get(ACCEPT).and_then(|v| v.to_str().ok());
+            let (fname, format) = match negotiate_format(accept, ffmpeg_path.is_some()) {
+                ThumbFormat::Jpeg => (fname.to_string(), ThumbFormat::Jpeg),
+                format => match ensure_thumbnail_variant(
+                    ffmpeg_path.as_deref().unwrap_or("ffmpeg"),
+                    &thumbs_dir,
+                    fname,
+                    format,
+                    &regen_sem,
+                    &in_flight,
+                )
+                .await
+                {
+                    Ok(variant) => (variant, format),
+                    Err(e) => {
+                        tracing::warn!(
+                            "{} thumbnail for {} failed: {}",
+                            format.extension(),
+                            entry.path,
+                            e
+                        );
+                        (fname.to_string(), ThumbFormat::Jpeg)
                     }
+                },
+            };
+            let fs_path = thumbs_dir.join(&fname);
+            let meta = tokio::fs::metadata(&fs_path)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let total_size = meta.len();
+            let modified = meta.modified().ok();
+
+            // compute etag
+            let mut hasher = Sha256::new();
+            hasher.update(entry.path.as_bytes());
+            hasher.update(format.extension().as_bytes());
+            hasher.update(total_size.to_le_bytes());
+            if let Some(m) = modified {
+                if let Ok(dur) = m.duration_since(UNIX_EPOCH) {
+                    hasher.update(dur.as_secs().to_le_bytes());
+                    hasher.update(dur.subsec_nanos().to_le_bytes());
                 }
-                let res = hasher.finalize();
-                let etag = format!("\"{:x}\"", res);
+            }
+            let res = hasher.finalize();
+            let etag = format!("\"{:x}\"", res);
 
-                // We set ETag and Last-Modified headers; conditional GETs are mostly handled by the static /thumbnails mount.
+            // We set ETag and Last-Modified headers; conditional GETs are mostly handled by the static /thumbnails mount.
 
-                let file = File::open(&fs_path)
-                    .await
-                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-                let stream = ReaderStream::new(file);
-                let body = StreamBody::new(stream);
-                let boxed = axum::body::boxed(body);
-                let mut res = Response::new(boxed);
-                res.headers_mut()
-                    .insert("content-type", HeaderValue::from_static("image/jpeg"));
-                res.headers_mut()
-                    .insert("ETag", HeaderValue::from_str(&etag).unwrap());
-                if let Some(m) = modified {
-                    let s = fmt_http_date(m);
-                    res.headers_mut().insert(
-                        "Last-Modified",
-                        HeaderValue::from_str(&s).unwrap_or(HeaderValue::from_static("")),
-                    );
-                }
-                return Ok(res);
+            touch_thumbnail(&fs_path);
+            let file = File::open(&fs_path)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let stream = ReaderStream::new(file);
+            let body = StreamBody::new(stream);
+            let boxed = axum::body::boxed(body);
+            let mut res = Response::new(boxed);
+            res.headers_mut().insert(
+                "content-type",
+                HeaderValue::from_static(format.content_type()),
+            );
+            // The body depends on Accept, so caches must key on it
+            res.headers_mut()
+                .insert("Vary", HeaderValue::from_static("Accept"));
+            res.headers_mut()
+                .insert("ETag", HeaderValue::from_str(&etag).unwrap());
+            if let Some(m) = modified {
+                let s = fmt_http_date(m);
+                res.headers_mut().insert(
+                    "Last-Modified",
+                    HeaderValue::from_str(&s).unwrap_or(HeaderValue::from_static("")),
+                );
             }
+            return Ok(res);
         }
     }
 
@@ -147,12 +209,23 @@ pub async fn thumbnail_handler(
 
 pub async fn generate_thumbnail_handler(
     state: State<Arc<Mutex<AppState>>>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
     axum::extract::Query(q): axum::extract::Query<GenThumbQuery>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     // Delegate to the shared generator helper
     // locate entry by id or path
     let guard = state.0.lock().await;
     let pool = guard.pool.clone();
+    let ffmpeg_path = guard.ffmpeg_enabled.then(|| {
+        guard
+            .ffmpeg_path
+            .clone()
+            .unwrap_or_else(|| "ffmpeg".to_string())
+    });
+    let thumbs_dir = guard.thumbnails_dir.clone().map(PathBuf::from);
+    let regen_sem = guard.regen_semaphore.clone();
+    let in_flight = guard.in_flight.clone();
     drop(guard);
 
     let opt = if let Some(id) = q.id {
@@ -174,22 +247,40 @@ pub async fn generate_thumbnail_handler(
         Some(e) => e,
         None => return Err((StatusCode::NOT_FOUND, "Not found".to_string())),
     };
+    ensure_access(&pool, &user, entry.id).await?;
 
     let w = q.w.unwrap_or(500);
     let h = q.h.unwrap_or(500);
 
-    match generate_thumbnail_for_entry(state.0.clone(), &entry, w, h).await {
-        Ok(out_name) => {
-            let url = format!("/thumbnails/{}", out_name);
-            Ok(Redirect::temporary(url.as_str()))
+    let url = match generate_thumbnail_for_entry(state.0.clone(), &entry, w, h).await {
+        Ok(mut out_name) => {
+            let accept = headers.get(ACCEPT).and_then(|v| v.to_str().ok());
+            let format = negotiate_format(accept, ffmpeg_path.is_some());
+            if let (Some(ffmpeg), Some(dir)) = (ffmpeg_path.as_deref(), thumbs_dir.as_deref()) {
+                if format != ThumbFormat::Jpeg {
+                    match ensure_thumbnail_variant(
+                        ffmpeg, dir, &out_name, format, &regen_sem, &in_flight,
+                    )
+                    .await
+                    {
+                        Ok(variant) => out_name = variant,
+                        Err(e) => tracing::warn!("{} thumbnail failed: {}", format.extension(), e),
+                    }
+                }
+            }
+            format!("/thumbnails/{}", out_name)
         }
         Err(e) => {
             // generation failed; return placeholder redirect
             tracing::error!("thumbnail generation failed: {}", e);
-            let url = "/thumbnails/placeholder.jpg";
-            Ok(Redirect::temporary(url))
+            "/thumbnails/placeholder.jpg".to_string()
         }
-    }
+    };
+    let mut res = Redirect::temporary(url.as_str()).into_response();
+    // The redirect target depends on Accept, so caches must key on it
+    res.headers_mut()
+        .insert("Vary", HeaderValue::from_static("Accept"));
+    Ok(res)
 }
 
 /// Generate thumbnail for a specific media entry. Returns the output filename on success.
@@ -220,11 +311,13 @@ pub async fn generate_thumbnail_for_entry(
     );
     drop(guard);
 
-    let _ = tokio::fs::create_dir_all(&thumbs_dir)
+    tokio::fs::create_dir_all(&thumbs_dir)
         .await
         .map_err(|e| e.to_string())?;
 
-    let out_name = format!("{}_{}x{}.jpg", entry.id, w, h);
+    let src = Path::new(&media_root).join(&entry.path);
+    let src_meta = tokio::fs::metadata(&src).await.map_err(|e| e.to_string())?;
+    let out_name = thumbnail_name(entry.id, &source_fingerprint(&entry.path, &src_meta), w, h);
     let out_path = thumbs_dir.join(&out_name);
     if out_path.exists() {
         return Ok(out_name);
@@ -241,15 +334,18 @@ pub async fn generate_thumbnail_for_entry(
     );
     let tmp_path = thumbs_dir.join(&tmp_name);
 
-    let src = Path::new(&media_root).join(&entry.path);
-
     if entry
         .mime_type
         .as_deref()
         .unwrap_or("")
         .starts_with("image/")
     {
-        let img = image::open(&src).map_err(|e| e.to_string())?;
+        let mut img = image::open(&src).map_err(|e| e.to_string())?;
+        if metadata::has_exif(entry.mime_type.as_deref().unwrap_or("")) {
+            if let Some(orientation) = metadata::read_orientation(&src) {
+                img = metadata::apply_orientation(img, orientation);
+            }
+        }
         let thumb = img.resize(w, h, FilterType::Lanczos3);
         let mut out_file = std::fs::File::create(&tmp_path).map_err(|e| e.to_string())?;
         thumb
@@ -267,9 +363,11 @@ pub async fn generate_thumbnail_for_entry(
             size: entry.size,
             tags: entry.tags.clone(),
             thumb_path: Some(format!("/thumbnails/{}", out_name)),
-            width: Some(thumb.width() as i64),
-            height: Some(thumb.height() as i64),
+            width: Some(img.width() as i64),
+            height: Some(img.height() as i64),
             duration_secs: entry.duration_secs,
+            mtime_ns: None,
+            inode: None,
         };
         let _ = db::upsert_media(pool.clone(), &ne).await;
         return Ok(out_name);
@@ -282,44 +380,24 @@ pub async fn generate_thumbnail_for_entry(
         if !ffmpeg_enabled {
             return Err("ffmpeg disabled".to_string());
         }
-        // probe duration
-        let mut duration_secs_opt: Option<i64> = None;
-        if let Ok(output) = Command::new(ffprobe_path.as_str())
-            .args([
-                "-v",
-                "error",
-                "-show_entries",
-                "format=duration",
-                "-of",
-                "default=noprint_wrappers=1:nokey=1",
-                src.to_string_lossy().as_ref(),
-            ])
-            .output()
-            .await
-        {
-            if output.status.success() {
-                if let Ok(s) = String::from_utf8(output.stdout) {
-                    if let Ok(f) = s.trim().parse::<f64>() {
-                        duration_secs_opt = Some(f.round() as i64);
-                    }
+        let duration = probe_duration(&ffprobe_path, &src).await;
+        let duration_secs_opt = duration.map(|d| d.round() as i64);
+        // Reuse the frame picked last time so every size shows the same moment
+        let seek_time = match db::get_thumb_time(&pool, entry.id).await.ok().flatten() {
+            Some(t) => t,
+            None => match choose_thumbnail_time(&ffmpeg_path, &src, duration).await {
+                Some(t) => {
+                    let _ = db::set_thumb_time(&pool, entry.id, t).await;
+                    t
                 }
-            }
-        }
-        let seek_time = if let Some(d) = duration_secs_opt {
-            let ten = (d as f64 * 0.1).round() as i64;
-            if d > 3 {
-                1
-            } else {
-                ten.max(0)
-            }
-        } else {
-            1
+                None => fallback_seek_time(duration_secs_opt),
+            },
         };
         let out_path_str = tmp_path.to_string_lossy().to_string();
         let ffmpeg_result = Command::new(ffmpeg_path.as_str())
             .args([
                 "-ss",
-                &format!("{}", seek_time),
+                &format!("{:.3}", seek_time),
                 "-i",
                 src.to_string_lossy().as_ref(),
                 "-frames:v",
@@ -356,6 +434,8 @@ pub async fn generate_thumbnail_for_entry(
                         width: Some(tw as i64),
                         height: Some(th as i64),
                         duration_secs: duration_secs_opt.or(entry.duration_secs),
+                        mtime_ns: None,
+                        inode: None,
                     };
                     let _ = db::upsert_media(pool.clone(), &ne).await;
                     return Ok(out_name);
@@ -369,3 +449,513 @@ pub async fn generate_thumbnail_for_entry(
 
     Err("unsupported media type or generation failed".to_string())
 }
+
+/// Fractions of a video's duration sampled when picking its thumbnail frame.
+const CANDIDATE_FRACTIONS: [f64; 5] = [0.1, 0.25, 0.4, 0.55, 0.7];
+/// Seconds sampled when the duration is unknown.
+const CANDIDATE_SECS: [f64; 4] = [1.0, 3.0, 5.0, 10.0];
+/// Frames darker than this mean luma are treated as black.
+const MIN_FRAME_MEAN: f64 = 24.0;
+/// Frames with less luma spread than this are treated as blank.
+const MIN_FRAME_STDDEV: f64 = 12.0;
+// Candidates are only scored, so they are decoded small
+const CANDIDATE_WIDTH: u32 = 160;
+
+/// Luminance statistics of a frame, on the 0-255 scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+pub fn frame_stats(img: &image::DynamicImage) -> FrameStats {
+    let luma = img.to_luma8();
+    let n = luma.pixels().len().max(1) as f64;
+    let mean = luma.pixels().map(|p| p.0[0] as f64).sum::<f64>() / n;
+    let var = luma
+        .pixels()
+        .map(|p| (p.0[0] as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    FrameStats {
+        mean,
+        stddev: var.sqrt(),
+    }
+}
+
+/// Timestamps sampled for a video of `duration` seconds.
+pub fn candidate_times(duration: Option<f64>) -> Vec<f64> {
+    match duration {
+        Some(d) if d > 0.0 => CANDIDATE_FRACTIONS
+            .iter()
+            .map(|f| (d * f * 1000.0).round() / 1000.0)
+            .collect(),
+        _ => CANDIDATE_SECS.to_vec(),
+    }
+}
+
+/// Pick the most detailed frame that is neither near-black nor near-uniform.
+/// When every candidate is rejected the most detailed one is still better
+/// than nothing. Ties go to the earlier frame.
+pub fn pick_frame(candidates: &[(f64, FrameStats)]) -> Option<f64> {
+    let best = |it: &mut dyn Iterator<Item = &(f64, FrameStats)>| {
+        it.fold(None::<&(f64, FrameStats)>, |best, c| match best {
+            Some(b) if b.1.stddev >= c.1.stddev => Some(b),
+            _ => Some(c),
+        })
+        .map(|c| c.0)
+    };
+    best(
+        &mut candidates
+            .iter()
+            .filter(|(_, s)| s.mean >= MIN_FRAME_MEAN && s.stddev >= MIN_FRAME_STDDEV),
+    )
+    .or_else(|| best(&mut candidates.iter()))
+}
+
+// Sample candidate frames from `src` and return the timestamp of the best
+// one, or None when ffmpeg could not decode any of them.
+async fn choose_thumbnail_time(
+    ffmpeg_path: &str,
+    src: &Path,
+    duration: Option<f64>,
+) -> Option<f64> {
+    let mut scored = Vec::new();
+    for t in candidate_times(duration) {
+        let output = Command::new(ffmpeg_path)
+            .args([
+                "-v",
+                "error",
+                "-ss",
+                &format!("{:.3}", t),
+                "-i",
+                src.to_string_lossy().as_ref(),
+                "-frames:v",
+                "1",
+                "-vf",
+                &format!("scale={}:-1", CANDIDATE_WIDTH),
+                "-f",
+                "image2pipe",
+                "-vcodec",
+                "mjpeg",
+                "-",
+            ])
+            .output()
+            .await
+            .ok()?;
+        if !output.status.success() {
+            continue;
+        }
+        if let Ok(img) = image::load_from_memory(&output.stdout) {
+            scored.push((t, frame_stats(&img)));
+        }
+    }
+    pick_frame(&scored)
+}
+
+// Seek used before frames were scored: 1s in, or 10% into very short videos.
+fn fallback_seek_time(duration_secs: Option<i64>) -> f64 {
+    match duration_secs {
+        Some(d) if d <= 3 => (d as f64 * 0.1).round().max(0.0),
+        _ => 1.0,
+    }
+}
+
+/// Encodings a thumbnail is served in. JPEG is what the generator writes;
+/// WebP and AVIF variants are converted from it with ffmpeg on request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbFormat {
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl ThumbFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "jpg",
+            ThumbFormat::WebP => "webp",
+            ThumbFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "image/jpeg",
+            ThumbFormat::WebP => "image/webp",
+            ThumbFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Format of a cached file, judged by its extension.
+    pub fn from_file_name(name: &str) -> Option<ThumbFormat> {
+        match name.rsplit_once('.')?.1 {
+            "jpg" => Some(ThumbFormat::Jpeg),
+            "webp" => Some(ThumbFormat::WebP),
+            "avif" => Some(ThumbFormat::Avif),
+            _ => None,
+        }
+    }
+
+    // Set once ffmpeg turns out to lack the encoder, so we stop trying
+    fn unavailable(self) -> Option<&'static AtomicBool> {
+        static WEBP: AtomicBool = AtomicBool::new(false);
+        static AVIF: AtomicBool = AtomicBool::new(false);
+        match self {
+            ThumbFormat::Jpeg => None,
+            ThumbFormat::WebP => Some(&WEBP),
+            ThumbFormat::Avif => Some(&AVIF),
+        }
+    }
+}
+
+/// Pick the thumbnail format for an `Accept` header: AVIF, then WebP, when
+/// listed with a non-zero q-value, otherwise JPEG. Wildcards never select
+/// the newer formats, and without ffmpeg only JPEG can be produced.
+pub fn negotiate_format(accept: Option<&str>, can_convert: bool) -> ThumbFormat {
+    let accept = match accept {
+        Some(a) if can_convert => a,
+        _ => return ThumbFormat::Jpeg,
+    };
+    let accepts = |wanted: &str| {
+        accept.split(',').any(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or("");
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|v| v.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            media.eq_ignore_ascii_case(wanted) && q > 0.0
+        })
+    };
+    [ThumbFormat::Avif, ThumbFormat::WebP]
+        .into_iter()
+        .find(|f| {
+            accepts(f.content_type()) && !f.unavailable().is_some_and(|u| u.load(Ordering::Relaxed))
+        })
+        .unwrap_or(ThumbFormat::Jpeg)
+}
+
+/// Make sure the `format` variant of the cached JPEG `jpeg_name` exists,
+/// converting it with ffmpeg if needed. Returns the variant's file name.
+/// Conversions share `regen_sem` with the other ffmpeg jobs, and concurrent
+/// requests for the same variant wait on a single conversion.
+pub async fn ensure_thumbnail_variant(
+    ffmpeg_path: &str,
+    thumbs_dir: &Path,
+    jpeg_name: &str,
+    format: ThumbFormat,
+    regen_sem: &Arc<Semaphore>,
+    in_flight: &Arc<Mutex<InFlightMap>>,
+) -> Result<String, String> {
+    let stem = jpeg_name
+        .strip_suffix(".jpg")
+        .ok_or_else(|| format!("not a JPEG thumbnail: {}", jpeg_name))?;
+    if format == ThumbFormat::Jpeg {
+        return Ok(jpeg_name.to_string());
+    }
+    let name = format!("{}.{}", stem, format.extension());
+    let out_path = thumbs_dir.join(&name);
+    if out_path.exists() {
+        return Ok(name);
+    }
+    let src = thumbs_dir.join(jpeg_name);
+    let (ffmpeg_path, regen_sem) = (ffmpeg_path.to_string(), regen_sem.clone());
+    let work = async move {
+        let _permit = regen_sem.acquire().await.map_err(|e| e.to_string())?;
+        // Another request may have converted it while this one queued
+        if out_path.exists() {
+            return Ok(());
+        }
+        convert_thumbnail(&ffmpeg_path, &src, &out_path, format).await
+    };
+    run_deduped(in_flight, name.clone(), work).await?;
+    Ok(name)
+}
+
+async fn convert_thumbnail(
+    ffmpeg_path: &str,
+    src: &Path,
+    out_path: &Path,
+    format: ThumbFormat,
+) -> Result<(), String> {
+    // ffmpeg picks the muxer from the extension, so keep it last
+    let tmp_path = out_path.with_extension(format!(
+        "{}.{}.{}",
+        format.extension(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos(),
+        format.extension()
+    ));
+    let codec: &[&str] = match format {
+        ThumbFormat::Jpeg => return Ok(()),
+        ThumbFormat::WebP => &["-c:v", "libwebp", "-quality", "75"],
+        ThumbFormat::Avif => &[
+            "-c:v",
+            "libaom-av1",
+            "-still-picture",
+            "1",
+            "-crf",
+            "32",
+            "-cpu-used",
+            "6",
+        ],
+    };
+    let output = Command::new(ffmpeg_path)
+        .args(["-v", "error", "-i", src.to_string_lossy().as_ref()])
+        .args(codec)
+        .args(["-frames:v", "1", "-y", tmp_path.to_string_lossy().as_ref()])
+        .output()
+        .await
+        .map_err(|e| format!("failed to spawn ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if stderr.contains("Unknown encoder") || stderr.contains("Encoder not found") {
+            if let Some(flag) = format.unavailable() {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+        return Err(stderr);
+    }
+    tokio::fs::rename(&tmp_path, out_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Fingerprint of a thumbnail source from its path, size and mtime. Editing
+/// the file, or a different file taking over a reused id, changes it.
+pub fn source_fingerprint(rel_path: &str, meta: &std::fs::Metadata) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(rel_path.as_bytes());
+    hasher.update(meta.len().to_le_bytes());
+    if let Some(dur) = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    {
+        hasher.update(dur.as_nanos().to_le_bytes());
+    }
+    let fp = format!("{:x}", hasher.finalize());
+    fp[..16].to_string()
+}
+
+/// Cache file name of a thumbnail: `<id>_<fingerprint>_<w>x<h>.jpg`. WebP
+/// and AVIF variants swap the extension.
+pub fn thumbnail_name(id: i64, fingerprint: &str, w: u32, h: u32) -> String {
+    format!("{}_{}_{}x{}.jpg", id, fingerprint, w, h)
+}
+
+/// Whether a file in the thumbnails directory is something we generated:
+/// a thumbnail in any format, a sprite sheet's WebVTT track or a preview clip.
+pub fn is_cache_file(name: &str) -> bool {
+    ThumbFormat::from_file_name(name).is_some() || name.ends_with(".vtt") || name.ends_with(".mp4")
+}
+
+/// Media id and source fingerprint of a cached thumbnail's file name. Temp
+/// files and names from before fingerprinting return None.
+pub fn parse_thumbnail_name(name: &str) -> Option<(i64, &str)> {
+    if !is_cache_file(name) {
+        return None;
+    }
+    let (stem, _ext) = name.rsplit_once('.')?;
+    if stem.contains('.') {
+        return None;
+    }
+    let (id, rest) = stem.split_once('_')?;
+    let (fingerprint, _size) = rest.split_once('_')?;
+    Some((id.parse().ok()?, fingerprint))
+}
+
+/// Delete cached thumbnails whose media row is gone or whose source has
+/// changed since they were made. Returns how many files were removed.
+pub async fn remove_orphaned_thumbnails(
+    pool: &SqlitePool,
+    thumbs_dir: &Path,
+    media_root: &Path,
+) -> usize {
+    let mut read_dir = match tokio::fs::read_dir(thumbs_dir).await {
+        Ok(rd) => rd,
+        Err(_) => return 0,
+    };
+    // Current fingerprint per media id, None when the entry or file is gone
+    let mut current: HashMap<i64, Option<String>> = HashMap::new();
+    let mut removed = 0;
+    while let Ok(Some(e)) = read_dir.next_entry().await {
+        let fname = e.file_name().to_string_lossy().to_string();
+        // Only `<id>_...jpg` files are ours; temp files are aged out separately
+        let id = match fname
+            .split_once('_')
+            .and_then(|(id, _)| id.parse::<i64>().ok())
+        {
+            Some(id) if is_cache_file(&fname) && fname.matches('.').count() == 1 => id,
+            _ => continue,
+        };
+        let want = match current.entry(id) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                let fp = match db::get_media_by_id(pool.clone(), id).await {
+                    Ok(Some(entry)) => tokio::fs::metadata(media_root.join(&entry.path))
+                        .await
+                        .ok()
+                        .map(|m| source_fingerprint(&entry.path, &m)),
+                    Ok(None) => None,
+                    // Keep the cache as is rather than guess on a DB error
+                    Err(_) => continue,
+                };
+                v.insert(fp)
+            }
+        };
+        let live = match (parse_thumbnail_name(&fname), want) {
+            (Some((_, fp)), Some(want)) => fp == want.as_str(),
+            _ => false,
+        };
+        if !live && tokio::fs::remove_file(e.path()).await.is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// How often the cache is checked against `thumbnails_max_mb`.
+pub const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Record that a cached thumbnail was just served. The access time orders
+/// eviction, and setting it explicitly works on `noatime` mounts too.
+pub fn touch_thumbnail(path: &Path) {
+    if let Ok(f) = std::fs::File::open(path) {
+        let _ = f.set_times(std::fs::FileTimes::new().set_accessed(SystemTime::now()));
+    }
+}
+
+/// Delete the least recently served thumbnails until the cache fits in
+/// `max_bytes`, clearing `thumb_path` of the entries that pointed at them.
+/// Returns how many files were removed.
+pub async fn evict_thumbnails(pool: &SqlitePool, thumbs_dir: &Path, max_bytes: u64) -> usize {
+    let mut read_dir = match tokio::fs::read_dir(thumbs_dir).await {
+        Ok(rd) => rd,
+        Err(_) => return 0,
+    };
+    let mut files = Vec::new();
+    let mut total = 0;
+    while let Ok(Some(e)) = read_dir.next_entry().await {
+        let fname = e.file_name().to_string_lossy().to_string();
+        // The placeholder and in-progress temp files are never evicted
+        if parse_thumbnail_name(&fname).is_none() {
+            continue;
+        }
+        if let Ok(meta) = e.metadata().await {
+            let used = meta.accessed().or_else(|_| meta.modified()).ok();
+            total += meta.len();
+            files.push((used, meta.len(), fname));
+        }
+    }
+    if total <= max_bytes {
+        return 0;
+    }
+
+    files.sort();
+    let mut evicted = Vec::new();
+    for (_, len, fname) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if tokio::fs::remove_file(thumbs_dir.join(&fname))
+            .await
+            .is_ok()
+        {
+            total -= len;
+            evicted.push(format!("/thumbnails/{}", fname));
+        }
+    }
+    if let Err(e) = db::clear_thumb_paths(pool, &evicted).await {
+        tracing::error!("failed to clear evicted thumbnail paths: {}", e);
+    }
+    evicted.len()
+}
+
+/// Keep the thumbnails cache under `max_bytes` by running `evict_thumbnails`
+/// every `EVICTION_INTERVAL` in the background.
+pub fn spawn_thumbnail_eviction(pool: SqlitePool, thumbs_dir: PathBuf, max_bytes: u64) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let n = evict_thumbnails(&pool, &thumbs_dir, max_bytes).await;
+            if n > 0 {
+                tracing::info!("Evicted {} thumbnail(s) to stay under the cache limit", n);
+            }
+        }
+    });
+}
+
+/// Run `work` unless another request is already producing the same `key`,
+/// in which case wait for its result through the shared in-flight map. The
+/// work runs on its own task, so a client disconnecting mid-way neither
+/// cancels it nor leaves the key behind for later requests to wait on.
+pub async fn run_deduped<F>(
+    in_flight: &Arc<Mutex<InFlightMap>>,
+    key: String,
+    work: F,
+) -> Result<(), String>
+where
+    F: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let leader = {
+        let mut infl = in_flight.lock().await;
+        match infl.get_mut(&key) {
+            Some(waiters) => {
+                waiters.push(tx);
+                false
+            }
+            None => {
+                infl.insert(key.clone(), vec![tx]);
+                true
+            }
+        }
+    };
+    if leader {
+        let in_flight = in_flight.clone();
+        tokio::spawn(async move {
+            // A panicking job still releases the key
+            let res = tokio::spawn(work)
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            let waiters = in_flight.lock().await.remove(&key);
+            for w in waiters.into_iter().flatten() {
+                let _ = w.send(res.clone());
+            }
+        });
+    }
+    rx.await
+        .unwrap_or_else(|_| Err("generation abandoned".to_string()))
+}
+
+/// Remove every cached thumbnail (any size) generated for the given media ids.
+/// Thumbnails are named `<id>_<fingerprint>_<w>x<h>.jpg`, so one directory
+/// pass is enough.
+pub async fn remove_cached_thumbnails(thumbs_dir: &Path, ids: &HashSet<i64>) -> usize {
+    if ids.is_empty() {
+        return 0;
+    }
+    let mut removed = 0;
+    let mut read_dir = match tokio::fs::read_dir(thumbs_dir).await {
+        Ok(rd) => rd,
+        Err(_) => return 0,
+    };
+    while let Ok(Some(e)) = read_dir.next_entry().await {
+        let fname = e.file_name().to_string_lossy().to_string();
+        let owner = fname
+            .split_once('_')
+            .and_then(|(id, _)| id.parse::<i64>().ok());
+        if let Some(id) = owner {
+            if ids.contains(&id) && tokio::fs::remove_file(e.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
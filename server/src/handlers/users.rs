@@ -0,0 +1,167 @@
+use crate::auth::{hash_token, new_token, now_secs};
+use crate::db;
+use crate::handlers::auth::AdminUser;
+use crate::models::{Role, User};
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// How long an invite or reset link can be redeemed for.
+pub const INVITE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(serde::Deserialize)]
+pub struct InviteBody {
+    pub username: String,
+    // defaults to viewer
+    pub role: Option<Role>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UpdateUserBody {
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+}
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+async fn pool_of(state: &State<Arc<Mutex<AppState>>>) -> SqlitePool {
+    state.0.lock().await.pool.clone()
+}
+
+async fn find_user(pool: &SqlitePool, id: i64) -> Result<User, (StatusCode, String)> {
+    db::get_user(pool, id)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "No such user".to_string()))
+}
+
+// Issue a fresh invite for `user_id` and describe it for the response
+async fn issue_invite(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<serde_json::Value, (StatusCode, String)> {
+    let token = new_token();
+    let expires_at = now_secs() + INVITE_TTL_SECS;
+    db::create_invite(pool, &hash_token(&token), user_id, expires_at)
+        .await
+        .map_err(internal)?;
+    Ok(json!({ "invite_token": token, "invite_expires_at": expires_at }))
+}
+
+// Refuse changes that would leave nobody able to administer the server
+async fn keep_an_admin(pool: &SqlitePool, user: &User) -> Result<(), (StatusCode, String)> {
+    if user.is_admin()
+        && !user.disabled
+        && db::count_active_admins(pool, user.id)
+            .await
+            .map_err(internal)?
+            == 0
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "cannot remove the last active admin".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// GET /admin/users: every account.
+pub async fn list_users_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let users = db::list_users(&pool).await.map_err(internal)?;
+    Ok(Json(json!({ "users": users })))
+}
+
+/// POST /admin/users: create an account without a password and return a
+/// one-time invite token for POST /auth/accept_invite.
+pub async fn invite_user_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Json(body): Json<InviteBody>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let username = body.username.trim();
+    if username.is_empty() || username.chars().any(char::is_whitespace) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "username must be a single word".to_string(),
+        ));
+    }
+    if db::get_user_id_by_name(&pool, username)
+        .await
+        .map_err(internal)?
+        .is_some()
+    {
+        return Err((StatusCode::CONFLICT, "username already taken".to_string()));
+    }
+    let id = db::create_user(&pool, username, "", body.role.unwrap_or(Role::Viewer))
+        .await
+        .map_err(internal)?;
+    let mut res = issue_invite(&pool, id).await?;
+    res["user"] = json!(find_user(&pool, id).await?);
+    Ok((StatusCode::CREATED, Json(res)))
+}
+
+/// GET /admin/users/{id}
+pub async fn get_user_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+) -> Result<Json<User>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    Ok(Json(find_user(&pool, id).await?))
+}
+
+/// PATCH /admin/users/{id}: change the role or disable/enable an account.
+pub async fn update_user_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+    Json(body): Json<UpdateUserBody>,
+) -> Result<Json<User>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let user = find_user(&pool, id).await?;
+    if body.role == Some(Role::Viewer) || body.disabled == Some(true) {
+        keep_an_admin(&pool, &user).await?;
+    }
+    db::update_user(&pool, id, body.role, body.disabled)
+        .await
+        .map_err(internal)?;
+    Ok(Json(find_user(&pool, id).await?))
+}
+
+/// DELETE /admin/users/{id}
+pub async fn delete_user_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    let user = find_user(&pool, id).await?;
+    keep_an_admin(&pool, &user).await?;
+    db::delete_user(&pool, id).await.map_err(internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /admin/users/{id}/reset: clear the password, end the account's
+/// sessions and return a new invite token to set another one.
+pub async fn reset_user_handler(
+    state: State<Arc<Mutex<AppState>>>,
+    _admin: AdminUser,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let pool = pool_of(&state).await;
+    find_user(&pool, id).await?;
+    db::set_password(&pool, id, "").await.map_err(internal)?;
+    Ok(Json(issue_invite(&pool, id).await?))
+}
@@ -0,0 +1,132 @@
+use crate::scanner::{ScanProgress, ScanSummary};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+// How many finished jobs are kept around for GET /scan/{id}
+const MAX_FINISHED_JOBS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+pub struct ScanJob {
+    pub id: u64,
+    pub state: JobState,
+    pub started_at: Instant,
+    pub finished_at: Option<Instant>,
+    pub progress: Arc<ScanProgress>,
+    pub summary: Option<ScanSummary>,
+    pub error: Option<String>,
+}
+
+/// Snapshot of a scan job as returned by the HTTP API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanJobStatus {
+    pub id: u64,
+    pub state: JobState,
+    pub files_seen: u64,
+    pub directories_visited: u64,
+    pub errors: u64,
+    pub elapsed_ms: u128,
+    pub summary: Option<ScanSummary>,
+    pub error: Option<String>,
+}
+
+impl ScanJob {
+    pub fn status(&self) -> ScanJobStatus {
+        let end = self.finished_at.unwrap_or_else(Instant::now);
+        ScanJobStatus {
+            id: self.id,
+            state: self.state,
+            files_seen: self.progress.files_seen.load(Ordering::Relaxed),
+            directories_visited: self.progress.directories_visited.load(Ordering::Relaxed),
+            errors: self.progress.errors.load(Ordering::Relaxed),
+            elapsed_ms: end.duration_since(self.started_at).as_millis(),
+            summary: self.summary.clone(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Registry of background scans. At most one scan runs at a time.
+#[derive(Default)]
+pub struct ScanJobs {
+    next_id: u64,
+    running: Option<u64>,
+    jobs: BTreeMap<u64, ScanJob>,
+}
+
+impl ScanJobs {
+    /// The job currently running, if any.
+    pub fn running(&self) -> Option<&ScanJob> {
+        self.running.and_then(|id| self.jobs.get(&id))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ScanJob> {
+        self.jobs.get(&id)
+    }
+
+    /// Register a new running job and return it. Callers must check
+    /// `running()` first; this does not coalesce on its own.
+    pub fn start(&mut self) -> &ScanJob {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.jobs.insert(
+            id,
+            ScanJob {
+                id,
+                state: JobState::Running,
+                started_at: Instant::now(),
+                finished_at: None,
+                progress: Arc::new(ScanProgress::default()),
+                summary: None,
+                error: None,
+            },
+        );
+        self.running = Some(id);
+        self.prune();
+        &self.jobs[&id]
+    }
+
+    /// Record the outcome of job `id` and release the running slot.
+    pub fn finish(&mut self, id: u64, result: Result<ScanSummary, String>) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.finished_at = Some(Instant::now());
+            match result {
+                Ok(summary) => {
+                    job.state = JobState::Completed;
+                    job.summary = Some(summary);
+                }
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e);
+                }
+            }
+        }
+        if self.running == Some(id) {
+            self.running = None;
+        }
+    }
+
+    // Drop the oldest finished jobs beyond the retention limit
+    fn prune(&mut self) {
+        let finished: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|j| j.state != JobState::Running)
+            .map(|j| j.id)
+            .collect();
+        if finished.len() > MAX_FINISHED_JOBS {
+            for id in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+                self.jobs.remove(id);
+            }
+        }
+    }
+}
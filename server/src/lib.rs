@@ -1,7 +1,12 @@
+pub mod auth;
 pub mod config;
 pub mod db;
 pub mod handlers;
+pub mod jobs;
+pub mod metadata;
 pub mod models;
+pub mod probe;
 pub mod scanner;
 pub mod startup;
 pub mod state;
+pub mod watcher;
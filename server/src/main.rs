@@ -1,22 +1,15 @@
-use axum::{
-    routing::{get, post},
-    Router,
-};
-use server::handlers::admin;
-use server::handlers::{
-    generate_thumbnail_handler, get_file_details_handler, list_directory_handler, stream_handler,
-    thumbnail_handler, trigger_scan_handler,
-};
+use server::jobs::ScanJobs;
 use server::state::AppState;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::{Mutex, Semaphore};
-use tracing_subscriber;
 
 use clap::{Arg, Command as ClapApp};
 
 use server::startup::{
-    build_client_service, build_cors, build_thumbnails_service, init_db, load_config,
-    prepare_thumbnails_cache, resolve_client_dist_dir, resolve_thumbnails_dir,
+    build_client_service, build_cors, build_router, init_db, load_config, prepare_thumbnails_cache,
+    prepare_transcode_cache, resolve_client_dist_dir, resolve_thumbnails_dir,
+    resolve_transcode_dir,
 };
 
 fn main() {
@@ -33,6 +26,25 @@ fn main() {
                 .num_args(1),
         )
         .subcommand(ClapApp::new("scan").about("Trigger a directory scan"))
+        .subcommand(
+            ClapApp::new("create-user")
+                .about("Create a login account; the password is read from stdin")
+                .arg(Arg::new("username").required(true))
+                .arg(
+                    Arg::new("role")
+                        .long("role")
+                        .value_parser(["admin", "viewer"])
+                        .default_value("viewer"),
+                ),
+        )
+        .subcommand(
+            ClapApp::new("acl")
+                .about(
+                    "Restrict a directory to the named users; with no users, lift the restriction",
+                )
+                .arg(Arg::new("path").required(true))
+                .arg(Arg::new("users").num_args(0..)),
+        )
         .get_matches();
 
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -53,18 +65,111 @@ fn main() {
 
         let pool = init_db(&config).await;
 
-        if let Some(_) = matches.subcommand_matches("scan") {
+        if matches.subcommand_matches("scan").is_some() {
             println!("Starting directory scan...");
-            if let Err(e) = server::scanner::scan_directory_and_index(
+            match server::scanner::scan_directory_and_index(
                 pool.clone(),
                 config.directory_to_scan.clone(),
                 None,
+                Some(resolve_thumbnails_dir(&config)),
+                None,
             )
             .await
             {
-                tracing::error!("Error scanning directory: {}", e);
+                Ok(summary) => println!(
+                    "Directory scan completed: {} added, {} updated, {} unchanged, {} removed.",
+                    summary.added, summary.updated, summary.unchanged, summary.removed
+                ),
+                Err(e) => {
+                    tracing::error!("Error scanning directory: {}", e);
+                    return;
+                }
+            }
+            if config.ffmpeg_enabled.unwrap_or(false) {
+                let ffprobe = config.ffprobe_path.as_deref().unwrap_or("ffprobe");
+                match server::probe::probe_pending(
+                    &pool,
+                    std::path::Path::new(&config.directory_to_scan),
+                    ffprobe,
+                )
+                .await
+                {
+                    Ok(n) => println!("Probed {} video(s) with ffprobe.", n),
+                    Err(e) => tracing::error!("Error probing videos: {}", e),
+                }
+            }
+            return;
+        }
+
+        if let Some(sub) = matches.subcommand_matches("create-user") {
+            let username = sub.get_one::<String>("username").expect("required");
+            let role = server::models::Role::parse(sub.get_one::<String>("role").expect("default"))
+                .expect("validated by clap");
+            eprint!("Password for {}: ", username);
+            let mut password = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut password) {
+                eprintln!("Error reading password: {}", e);
+                std::process::exit(1);
+            }
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                eprintln!("Password must not be empty");
+                std::process::exit(1);
+            }
+            let hash = match server::auth::hash_password(password) {
+                Ok(h) => h,
+                Err(e) => {
+                    eprintln!("Error hashing password: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            match server::db::create_user(&pool, username, &hash, role).await {
+                Ok(id) => println!("Created {} {} (id {}).", role.as_str(), username, id),
+                Err(e) => {
+                    eprintln!("Error creating user: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+
+        if let Some(sub) = matches.subcommand_matches("acl") {
+            let path = sub.get_one::<String>("path").expect("required");
+            let dir = match server::db::get_media_by_path(pool.clone(), path.clone()).await {
+                Ok(Some(e)) if e.mime_type.is_none() => e,
+                Ok(_) => {
+                    eprintln!("No indexed directory at {}", path);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error looking up {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+            let mut user_ids = Vec::new();
+            for name in sub.get_many::<String>("users").into_iter().flatten() {
+                match server::db::get_user_id_by_name(&pool, name).await {
+                    Ok(Some(id)) => user_ids.push(id),
+                    Ok(None) => {
+                        eprintln!("No such user: {}", name);
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("Error looking up user {}: {}", name, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Err(e) = server::db::set_acl(&pool, dir.id, &user_ids).await {
+                eprintln!("Error updating access list: {}", e);
+                std::process::exit(1);
+            }
+            if user_ids.is_empty() {
+                println!("{} is visible to everyone.", path);
+            } else {
+                let names = server::db::get_acl(&pool, dir.id).await.unwrap_or_default();
+                println!("{} is visible to: {}", path, names.join(", "));
             }
-            println!("Directory scan completed.");
             return;
         }
 
@@ -82,13 +187,45 @@ fn main() {
             // regeneration controls
             regen_semaphore: Arc::new(Semaphore::new(4)),
             in_flight: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            transcode_dir: Some(resolve_transcode_dir(&config).to_string_lossy().to_string()),
+            transcode_semaphore: Arc::new(Semaphore::new(
+                config.transcode_concurrency.unwrap_or(2).max(1),
+            )),
+            scan_jobs: Arc::new(Mutex::new(ScanJobs::default())),
+            cookie_secure: config.cookie_secure.unwrap_or(true),
         }));
 
         // ensure cache and build static service for thumbnails
-        prepare_thumbnails_cache(&thumbnails_dir_path);
-        let serve_thumbs = build_thumbnails_service(thumbnails_dir_path.clone());
-        // (previously created serve_thumbs above)
+        prepare_thumbnails_cache(
+            &thumbnails_dir_path,
+            &pool,
+            std::path::Path::new(&config.directory_to_scan),
+        )
+        .await;
+        prepare_transcode_cache(
+            &resolve_transcode_dir(&config),
+            &pool,
+            std::path::Path::new(&config.directory_to_scan),
+        )
+        .await;
 
+        if config.watch_enabled.unwrap_or(false) {
+            if let Err(e) = server::watcher::spawn_watcher(
+                pool.clone(),
+                PathBuf::from(&config.directory_to_scan),
+                Some(thumbnails_dir_path.clone()),
+                config.watch_debounce_ms,
+            ) {
+                tracing::error!("Failed to start filesystem watcher: {}", e);
+            }
+        }
+        if let Some(mb) = config.thumbnails_max_mb {
+            server::handlers::thumbnails::spawn_thumbnail_eviction(
+                pool.clone(),
+                thumbnails_dir_path.clone(),
+                mb * 1024 * 1024,
+            );
+        }
         let cors_opt = match build_cors(&config) {
             Ok(c) => c,
             Err(e) => {
@@ -97,20 +234,7 @@ fn main() {
             }
         };
 
-        let mut app = Router::new()
-            .route("/scan", post(trigger_scan_handler))
-            .route("/media", get(list_directory_handler))
-            .route("/media/details", get(get_file_details_handler))
-            .route("/media/thumbnail", get(thumbnail_handler))
-            .route("/media/generate_thumbnail", get(generate_thumbnail_handler))
-            .route(
-                "/admin/regenerate_thumbnails",
-                post(admin::regenerate_thumbnails_handler),
-            )
-            .route("/media/stream", get(stream_handler))
-            .route("/media/image", get(stream_handler))
-            .nest_service("/thumbnails", serve_thumbs)
-            .with_state(state.clone());
+        let mut app = build_router(state).await;
         // If client dist is configured, mount it as a fallback SPA service
         if let Some(cd) = resolve_client_dist_dir(&config) {
             let client_router = build_client_service(cd);
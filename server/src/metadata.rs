@@ -0,0 +1,257 @@
+use crate::models::MediaMetadata;
+use exif::{DateTime, Exif, In, Tag, Value};
+use image::DynamicImage;
+use std::path::Path;
+
+/// EXIF data of one file: the stored metadata plus the pixel dimensions,
+/// which go to the `media` row rather than `media_metadata`.
+#[derive(Debug, Clone, Default)]
+pub struct ExifInfo {
+    pub metadata: MediaMetadata,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+}
+
+/// Whether files of this MIME type may carry EXIF we know how to read.
+pub fn has_exif(mime_type: &str) -> bool {
+    matches!(
+        mime_type,
+        "image/jpeg" | "image/tiff" | "image/heic" | "image/heif"
+    )
+}
+
+/// Read EXIF from a JPEG, TIFF or HEIF/HEIC file. Returns None when the file
+/// has no EXIF block or cannot be parsed.
+pub fn read_exif(path: &Path) -> Option<ExifInfo> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut reader = std::io::BufReader::new(file);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+
+    let orientation = uint(&exif, Tag::Orientation).filter(|o| (1..=8).contains(o));
+    let metadata = MediaMetadata {
+        taken_at: taken_at(&exif),
+        camera_make: ascii(&exif, Tag::Make),
+        camera_model: ascii(&exif, Tag::Model),
+        lens: ascii(&exif, Tag::LensModel).or_else(|| ascii(&exif, Tag::LensMake)),
+        exposure_time: rational(&exif, Tag::ExposureTime).and_then(format_exposure),
+        f_number: rational(&exif, Tag::FNumber),
+        iso: uint(&exif, Tag::PhotographicSensitivity).map(i64::from),
+        orientation: orientation.map(i64::from),
+        gps_latitude: gps_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+        gps_longitude: gps_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+    };
+    let mut width = uint(&exif, Tag::PixelXDimension).map(i64::from);
+    let mut height = uint(&exif, Tag::PixelYDimension).map(i64::from);
+    // Dimensions describe the stored pixels; report them as displayed
+    if orientation.is_some_and(swaps_axes) {
+        std::mem::swap(&mut width, &mut height);
+    }
+    Some(ExifInfo {
+        metadata,
+        width,
+        height,
+    })
+}
+
+/// EXIF orientation (1-8) of a file, if it records one.
+pub fn read_orientation(path: &Path) -> Option<u32> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut reader = std::io::BufReader::new(file);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    uint(&exif, Tag::Orientation).filter(|o| (1..=8).contains(o))
+}
+
+/// Rotate and flip a decoded image so it displays upright for the given EXIF
+/// orientation. Orientation 1 and unknown values leave it untouched.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Orientations 5-8 turn the image a quarter, swapping width and height
+fn swaps_axes(orientation: u32) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(parts) => {
+            let s = String::from_utf8_lossy(parts.first()?);
+            let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!s.is_empty()).then(|| s.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rational(exif: &Exif, tag: Tag) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) => v.first().filter(|r| r.denom != 0).map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+fn format_exposure(secs: f64) -> Option<String> {
+    if secs <= 0.0 || !secs.is_finite() {
+        None
+    } else if secs < 1.0 {
+        Some(format!("1/{}", (1.0 / secs).round()))
+    } else {
+        Some(format!("{}", (secs * 10.0).round() / 10.0))
+    }
+}
+
+fn taken_at(exif: &Exif) -> Option<String> {
+    let raw = [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .into_iter()
+        .find_map(|t| match &exif.get_field(t, In::PRIMARY)?.value {
+            Value::Ascii(parts) => parts.first().cloned(),
+            _ => None,
+        })?;
+    let mut dt = DateTime::from_ascii(&raw).ok()?;
+    if let Some(Value::Ascii(parts)) = exif
+        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+        .map(|f| &f.value)
+    {
+        if let Some(p) = parts.first() {
+            let _ = dt.parse_offset(p);
+        }
+    }
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    );
+    if let Some(off) = dt.offset {
+        let sign = if off < 0 { '-' } else { '+' };
+        let off = off.unsigned_abs();
+        s.push_str(&format!("{}{:02}:{:02}", sign, off / 60, off % 60));
+    }
+    Some(s)
+}
+
+// Degrees/minutes/seconds rationals to signed decimal degrees
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let dms = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() >= 3 && v.iter().all(|r| r.denom != 0) => {
+            v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    let negative = match exif.get_field(ref_tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(parts)) => parts.first().and_then(|p| p.first()) == Some(&negative_ref),
+        _ => false,
+    };
+    Some(if negative { -dms } else { dms })
+}
+
+/// Music tags of one audio file, from ID3, Vorbis comments or MP4 atoms.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub duration_secs: Option<f64>,
+}
+
+/// Read tags and duration from an audio file. Returns None when the format is
+/// not recognised.
+pub fn read_audio_tags(path: &Path) -> Option<AudioTags> {
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let file = std::fs::File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let mut out = AudioTags::default();
+    // Tags found ahead of the container (e.g. ID3v2 in MP3) come first, so
+    // container metadata read afterwards wins when both are present.
+    if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        apply_audio_tags(&mut out, rev.tags());
+    }
+    if let Some(rev) = probed.format.metadata().current() {
+        apply_audio_tags(&mut out, rev.tags());
+    }
+
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+        if let (Some(tb), Some(frames)) = (params.time_base, params.n_frames) {
+            let t = tb.calc_time(frames);
+            out.duration_secs = Some(t.seconds as f64 + t.frac);
+        }
+    }
+    Some(out)
+}
+
+fn apply_audio_tags(out: &mut AudioTags, tags: &[symphonia::core::meta::Tag]) {
+    use symphonia::core::meta::StandardTagKey as K;
+
+    for tag in tags {
+        let key = match tag.std_key {
+            Some(k) => k,
+            None => continue,
+        };
+        let value = tag.value.to_string();
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if value.is_empty() {
+            continue;
+        }
+        match key {
+            K::TrackTitle => out.title = Some(value.to_string()),
+            K::Artist => out.artist = Some(value.to_string()),
+            K::Album => out.album = Some(value.to_string()),
+            K::AlbumArtist => out.album_artist = Some(value.to_string()),
+            K::TrackNumber => out.track_number = leading_number(value).or(out.track_number),
+            K::DiscNumber => out.disc_number = leading_number(value).or(out.disc_number),
+            K::Genre => out.genre = Some(value.to_string()),
+            // Prefer the first date seen; "2019-05-01" and "2019" both give 2019
+            K::Date | K::ReleaseDate | K::OriginalDate if out.year.is_none() => {
+                out.year = value
+                    .get(..4)
+                    .and_then(|y| y.parse().ok())
+                    .filter(|y| *y > 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+// "3/12" -> 3
+fn leading_number(s: &str) -> Option<i64> {
+    let digits: String = s
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok().filter(|n| *n > 0)
+}
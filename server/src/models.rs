@@ -29,11 +29,225 @@ pub struct NewMediaEntry {
     pub parent_id: Option<i64>,
     pub mime_type: Option<String>,
     pub size: Option<i64>,
-    // optional tags when creating/updating
+    // when Some, replaces the entry's tags; None leaves existing tags untouched
     pub tags: Option<Vec<String>>,
     // optional thumbnail path and metadata when known
     pub thumb_path: Option<String>,
     pub width: Option<i64>,
     pub height: Option<i64>,
     pub duration_secs: Option<i64>,
+    // filesystem fingerprint recorded by the scanner (mtime in ns since epoch, inode on unix)
+    pub mtime_ns: Option<i64>,
+    pub inode: Option<i64>,
+}
+
+// Minimal view of an indexed child row used by the scanner to decide whether
+// a directory entry needs to be re-upserted.
+#[derive(Debug, Clone)]
+pub struct IndexedChild {
+    pub id: i64,
+    pub is_dir: bool,
+    pub size: Option<i64>,
+    pub mtime_ns: Option<i64>,
+    pub inode: Option<i64>,
+}
+
+// A tag name with the number of media entries carrying it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TagCount {
+    pub name: String,
+    pub count: i64,
+}
+
+// Camera metadata read from EXIF at scan time. Every field is optional since
+// cameras and editing tools write wildly different subsets.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct MediaMetadata {
+    // local time the photo was taken, "YYYY-MM-DDTHH:MM:SS" plus "+HH:MM" when known
+    pub taken_at: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    // exposure time in seconds as photographers write it, e.g. "1/250" or "2.5"
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i64>,
+    // EXIF orientation, 1-8
+    pub orientation: Option<i64>,
+    // decimal degrees, negative for south / west
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+}
+
+// Music library views built from audio tags
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Artist {
+    pub id: i64,
+    pub name: String,
+    pub album_count: i64,
+    pub track_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Album {
+    pub id: i64,
+    pub title: String,
+    // album artist, falling back to the track artist
+    pub artist_id: Option<i64>,
+    pub artist: Option<String>,
+    pub year: Option<i64>,
+    pub track_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Track {
+    // id of the media row holding the audio file
+    pub id: i64,
+    pub path: String,
+    pub title: String,
+    pub artist_id: Option<i64>,
+    pub artist: Option<String>,
+    pub album_id: Option<i64>,
+    pub album: Option<String>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub duration_secs: Option<f64>,
+}
+
+// Stream layout of a video as reported by ffprobe
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ProbeInfo {
+    // ffprobe format name, e.g. "matroska,webm" or "mov,mp4,m4a,3gp,3g2,mj2"
+    pub container: Option<String>,
+    pub video_codec: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<f64>,
+    // overall bitrate in bits per second
+    pub bitrate: Option<i64>,
+    pub duration_secs: Option<f64>,
+    pub audio_tracks: Vec<AudioStream>,
+    pub subtitles: Vec<SubtitleStream>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AudioStream {
+    pub codec: Option<String>,
+    pub channels: Option<i64>,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SubtitleStream {
+    pub codec: Option<String>,
+    pub language: Option<String>,
+}
+
+// A login account; the password hash never leaves db.rs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    // disabled accounts cannot log in and lose their sessions
+    pub disabled: bool,
+    pub created_at: String,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+}
+
+// Admins manage users, scans and thumbnails and see every directory;
+// viewers browse and stream what their grants allow.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "admin" => Some(Role::Admin),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+}
+
+// What an API key may be used for; see `auth::required_scope`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Stream,
+    Scan,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Stream => "stream",
+            Scope::Scan => "scan",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Scope> {
+        match s {
+            "read" => Some(Scope::Read),
+            "stream" => Some(Scope::Stream),
+            "scan" => Some(Scope::Scan),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+// A long-lived key acting as `user_id` within `scopes`; the key itself is
+// only shown once, when it is created
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub user_id: i64,
+    pub username: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    // unix seconds; None never expires
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+// A public link to one entry and everything below it. Like API keys, the
+// token is only shown when the share is created.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Share {
+    pub id: i64,
+    pub media_id: i64,
+    pub path: String,
+    // the user who created it; the link sees what they can see
+    pub user_id: i64,
+    pub username: String,
+    pub created_at: String,
+    // unix seconds; None never expires
+    pub expires_at: Option<i64>,
+    pub has_password: bool,
+    pub max_downloads: Option<i64>,
+    pub downloads: i64,
 }
@@ -0,0 +1,134 @@
+use crate::db;
+use crate::models::{AudioStream, ProbeInfo, SubtitleStream};
+use futures::stream::{self, StreamExt};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::path::Path;
+use tokio::process::Command;
+
+// How many ffprobe processes the probing stage runs at once
+const PROBE_CONCURRENCY: usize = 4;
+
+/// Parse the output of `ffprobe -print_format json -show_format -show_streams`.
+pub fn parse_ffprobe_json(output: &str) -> Option<ProbeInfo> {
+    let v: Value = serde_json::from_str(output).ok()?;
+    let format = &v["format"];
+    let mut info = ProbeInfo {
+        container: str_field(&format["format_name"]),
+        bitrate: num_field(&format["bit_rate"]).map(|b| b as i64),
+        duration_secs: num_field(&format["duration"]),
+        ..Default::default()
+    };
+
+    for s in v["streams"].as_array().map(Vec::as_slice).unwrap_or(&[]) {
+        let language = str_field(&s["tags"]["language"]).filter(|l| l != "und");
+        match s["codec_type"].as_str() {
+            // The first real video stream wins. Cover art shows up as a video
+            // stream too, flagged as an attached picture.
+            Some("video")
+                if info.video_codec.is_none()
+                    && s["disposition"]["attached_pic"].as_i64() != Some(1) =>
+            {
+                info.video_codec = str_field(&s["codec_name"]);
+                info.width = s["width"].as_i64();
+                info.height = s["height"].as_i64();
+                info.frame_rate =
+                    frame_rate(&s["avg_frame_rate"]).or_else(|| frame_rate(&s["r_frame_rate"]));
+            }
+            Some("audio") => info.audio_tracks.push(AudioStream {
+                codec: str_field(&s["codec_name"]),
+                channels: s["channels"].as_i64(),
+                language,
+            }),
+            Some("subtitle") => info.subtitles.push(SubtitleStream {
+                codec: str_field(&s["codec_name"]),
+                language,
+            }),
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+fn str_field(v: &Value) -> Option<String> {
+    v.as_str().filter(|s| !s.is_empty()).map(str::to_string)
+}
+
+// ffprobe prints most numbers as strings
+fn num_field(v: &Value) -> Option<f64> {
+    match v {
+        Value::String(s) => s.parse().ok(),
+        Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+// "30000/1001" -> 29.97; "0/0" means unknown
+fn frame_rate(v: &Value) -> Option<f64> {
+    let (num, den) = v.as_str()?.split_once('/')?;
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    if num <= 0.0 || den <= 0.0 {
+        return None;
+    }
+    Some((num / den * 1000.0).round() / 1000.0)
+}
+
+/// Run ffprobe on one file.
+pub async fn probe_file(ffprobe_path: &str, path: &Path) -> Result<ProbeInfo, String> {
+    let output = Command::new(ffprobe_path)
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("failed to spawn ffprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    parse_ffprobe_json(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| "unreadable ffprobe output".to_string())
+}
+
+/// Post-scan stage: probe every video that has no probe results yet. The
+/// scanner clears results of changed files, so only new and modified videos
+/// are probed. Files ffprobe cannot read get an empty result and are not
+/// retried until they change. Returns how many files were probed.
+pub async fn probe_pending(
+    pool: &SqlitePool,
+    media_root: &Path,
+    ffprobe_path: &str,
+) -> Result<usize, String> {
+    let pending = db::list_unprobed_videos(pool)
+        .await
+        .map_err(|e| format!("Failed to list unprobed videos: {}", e))?;
+
+    let results: Vec<Result<(), String>> = stream::iter(pending)
+        .map(|(id, rel_path)| async move {
+            let info = match probe_file(ffprobe_path, &media_root.join(&rel_path)).await {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::warn!("ffprobe failed for {}: {}", rel_path, e);
+                    ProbeInfo::default()
+                }
+            };
+            db::set_media_probe(pool, id, &info)
+                .await
+                .map_err(|e| format!("Failed to store probe for {}: {}", rel_path, e))
+        })
+        .buffer_unordered(PROBE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut probed = 0;
+    for r in results {
+        r?;
+        probed += 1;
+    }
+    Ok(probed)
+}
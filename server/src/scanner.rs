@@ -1,14 +1,195 @@
 use crate::db;
-use crate::models::NewMediaEntry;
-use sqlx::SqlitePool;
-use std::path::PathBuf;
+use crate::handlers::thumbnails::remove_cached_thumbnails;
+use crate::metadata::{self, AudioTags};
+use crate::models::{IndexedChild, MediaMetadata, NewMediaEntry};
+use serde::Serialize;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
 
 const BATCH_SIZE: usize = 500;
 
+/// Counts of entries (files and directories) touched by a scan.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    // rows dropped because their file or directory no longer exists on disk
+    pub removed: usize,
+    // videos run through ffprobe after the walk (only when ffmpeg is enabled)
+    pub probed: usize,
+}
+
+/// Live counters updated while a scan runs, readable from other tasks.
+#[derive(Debug, Default)]
+pub struct ScanProgress {
+    pub files_seen: AtomicU64,
+    pub directories_visited: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl ScanProgress {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// On-disk fingerprint used to detect changes between scans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: i64,
+    pub mtime_ns: Option<i64>,
+    pub inode: Option<i64>,
+}
+
+impl Fingerprint {
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        let mtime_ns = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as i64);
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(meta.ino() as i64)
+        };
+        #[cfg(not(unix))]
+        let inode = None;
+        Fingerprint {
+            size: meta.len() as i64,
+            mtime_ns,
+            inode,
+        }
+    }
+
+    /// True when the indexed row was recorded from the same file contents.
+    /// Inodes are only compared when both sides know one.
+    pub fn matches(&self, indexed: &IndexedChild) -> bool {
+        if indexed.is_dir || indexed.size != Some(self.size) {
+            return false;
+        }
+        if indexed.mtime_ns.is_none() || indexed.mtime_ns != self.mtime_ns {
+            return false;
+        }
+        match (indexed.inode, self.inode) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+}
+
+/// Row for a directory at `rel_path`; directories carry no size or fingerprint.
+pub fn new_directory_entry(
+    name: String,
+    rel_path: String,
+    parent_id: Option<i64>,
+) -> NewMediaEntry {
+    NewMediaEntry {
+        name,
+        path: rel_path,
+        parent_id,
+        mime_type: None,
+        size: None,
+        tags: None,
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: None,
+        inode: None,
+    }
+}
+
+/// Row for a regular file, with its MIME type guessed from `path`.
+pub fn new_file_entry(
+    name: String,
+    rel_path: String,
+    parent_id: Option<i64>,
+    path: &Path,
+    fp: &Fingerprint,
+) -> NewMediaEntry {
+    let mime_type = mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string();
+    NewMediaEntry {
+        name,
+        path: rel_path,
+        parent_id,
+        mime_type: Some(mime_type),
+        size: Some(fp.size),
+        tags: None,
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: fp.mtime_ns,
+        inode: fp.inode,
+    }
+}
+
+/// Metadata read from a file's contents, stored next to its `media` row.
+#[derive(Debug, Clone, Default)]
+pub struct FileMetadata {
+    pub exif: Option<MediaMetadata>,
+    pub audio: Option<AudioTags>,
+}
+
+/// Read EXIF from photos and tags from audio files, filling in the entry's
+/// pixel dimensions or duration when the file records them.
+pub fn read_file_metadata(entry: &mut NewMediaEntry, path: &Path) -> FileMetadata {
+    let mime = entry.mime_type.as_deref().unwrap_or("");
+    let mut out = FileMetadata::default();
+    if metadata::has_exif(mime) {
+        if let Some(info) = metadata::read_exif(path) {
+            entry.width = info.width;
+            entry.height = info.height;
+            out.exif = Some(info.metadata);
+        }
+    } else if mime.starts_with("audio/") {
+        out.audio = metadata::read_audio_tags(path);
+        if let Some(d) = out.audio.as_ref().and_then(|a| a.duration_secs) {
+            entry.duration_secs = Some(d.round() as i64);
+        }
+    }
+    out
+}
+
+/// [`read_file_metadata`] on the blocking pool, since EXIF and audio tag
+/// parsing read and decode the file.
+pub async fn read_file_metadata_blocking(
+    mut entry: NewMediaEntry,
+    path: PathBuf,
+) -> Result<(NewMediaEntry, FileMetadata), String> {
+    tokio::task::spawn_blocking(move || {
+        let meta = read_file_metadata(&mut entry, &path);
+        (entry, meta)
+    })
+    .await
+    .map_err(|e| format!("Failed to read file metadata: {}", e))
+}
+
+/// Replace the stored metadata of media `id` with `meta`. Probe results are
+/// dropped so the probing stage looks at the new contents, and so is the
+/// chosen thumbnail frame.
+pub async fn store_file_metadata_in_tx(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: i64,
+    meta: &FileMetadata,
+) -> Result<(), sqlx::Error> {
+    db::set_media_metadata_in_tx(tx, id, meta.exif.as_ref()).await?;
+    db::clear_media_probe_in_tx(tx, id).await?;
+    db::clear_thumb_time_in_tx(tx, id).await?;
+    db::set_audio_track_in_tx(tx, id, meta.audio.as_ref()).await
+}
+
 // Helper function to process a batch of files in a single transaction
 async fn flush_file_buffer(
     pool: &SqlitePool,
-    buffer: &mut Vec<NewMediaEntry>,
+    buffer: &mut Vec<(NewMediaEntry, FileMetadata)>,
 ) -> Result<(), sqlx::Error> {
     if buffer.is_empty() {
         return Ok(());
@@ -18,8 +199,9 @@ async fn flush_file_buffer(
     let mut tx = pool.begin().await?;
 
     // Process all files in the buffer
-    while let Some(entry) = buffer.pop() {
-        db::upsert_media_in_tx(&mut tx, &entry).await?;
+    while let Some((entry, meta)) = buffer.pop() {
+        let id = db::upsert_media_in_tx(&mut tx, &entry).await?;
+        store_file_metadata_in_tx(&mut tx, id, &meta).await?;
     }
 
     // Commit the transaction
@@ -28,99 +210,190 @@ async fn flush_file_buffer(
     Ok(())
 }
 
+/// Delete stale rows (and everything nested under them) in one transaction.
+pub async fn prune_stale_paths(
+    pool: &SqlitePool,
+    paths: &[String],
+    removed_ids: &mut HashSet<i64>,
+) -> Result<(), sqlx::Error> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut tx = pool.begin().await?;
+    for p in paths {
+        removed_ids.extend(db::delete_media_subtree_in_tx(&mut tx, p).await?);
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Walk `directory`, upserting new or changed entries and removing rows whose
+/// files disappeared. Cached thumbnails of removed rows are deleted from
+/// `thumbnails_dir` when one is given. Counters in `progress`, if any, are
+/// updated as the walk proceeds.
 pub async fn scan_directory_and_index(
     pool: SqlitePool,
     directory: String,
     parent_id: Option<i64>,
-) -> Result<(), String> {
+    thumbnails_dir: Option<PathBuf>,
+    progress: Option<&ScanProgress>,
+) -> Result<ScanSummary, String> {
     let root = PathBuf::from(&directory);
+    scan_subtree(
+        pool,
+        &root,
+        root.clone(),
+        parent_id,
+        thumbnails_dir,
+        progress,
+    )
+    .await
+}
 
-    let mut stack: Vec<(PathBuf, Option<i64>)> = vec![(PathBuf::from(directory), parent_id)];
+/// Same as `scan_directory_and_index`, but starts at `start` (a directory
+/// somewhere below `root`, already indexed with id `parent_id`) while keeping
+/// stored paths relative to `root`.
+pub async fn scan_subtree(
+    pool: SqlitePool,
+    root: &Path,
+    start: PathBuf,
+    parent_id: Option<i64>,
+    thumbnails_dir: Option<PathBuf>,
+    progress: Option<&ScanProgress>,
+) -> Result<ScanSummary, String> {
+    let mut stack: Vec<(PathBuf, Option<i64>)> = vec![(start, parent_id)];
+    let mut summary = ScanSummary::default();
+    let mut removed_ids: HashSet<i64> = HashSet::new();
 
     // Buffer for file entries to be upserted in batches
-    let mut file_buffer: Vec<NewMediaEntry> = Vec::with_capacity(BATCH_SIZE);
+    let mut file_buffer: Vec<(NewMediaEntry, FileMetadata)> = Vec::with_capacity(BATCH_SIZE);
 
     while let Some((dir_path, parent)) = stack.pop() {
         let mut read_dir = match tokio::fs::read_dir(&dir_path).await {
             Ok(rd) => rd,
-            Err(_) => continue,
+            Err(_) => {
+                if let Some(p) = progress {
+                    ScanProgress::bump(&p.errors);
+                }
+                continue;
+            }
         };
+        if let Some(p) = progress {
+            ScanProgress::bump(&p.directories_visited);
+        }
+
+        // What the index currently holds for this directory, keyed by relative path
+        let indexed = db::list_indexed_children(&pool, parent)
+            .await
+            .map_err(|e| format!("db query error: {}", e))?;
+
+        // Relative paths observed on disk; whatever is indexed but unseen gets pruned.
+        let mut seen: HashSet<String> = HashSet::with_capacity(indexed.len());
+        let mut listing_complete = true;
 
         loop {
-            match read_dir.next_entry().await {
-                Ok(opt) => {
-                    if let Some(entry) = opt {
-                        let path = entry.path();
-                        let name = entry.file_name().to_string_lossy().to_string();
-
-                        // compute relative path from root
-                        let rel_path = match path.strip_prefix(&root) {
-                            Ok(p) => p.to_string_lossy().to_string(),
-                            Err(_) => path.to_string_lossy().to_string(),
-                        };
-
-                        // use async metadata
-                        let meta = match tokio::fs::metadata(&path).await {
-                            Ok(m) => m,
-                            Err(_) => continue,
-                        };
-
-                        if meta.is_dir() {
-                            // Directories must be upserted immediately because we need their ID for traversal
-                            let n = NewMediaEntry {
-                                name: name.clone(),
-                                path: rel_path.clone(),
-                                parent_id: parent,
-                                mime_type: None,
-                                size: None,
-                                tags: None,
-                                thumb_path: None,
-                                width: None,
-                                height: None,
-                                duration_secs: None,
-                            };
-
-                            let new_parent_id = db::upsert_media(pool.clone(), &n)
-                                .await
-                                .map_err(|e| format!("db upsert error: {}", e))?;
-
-                            stack.push((path, Some(new_parent_id)));
-                        } else if meta.is_file() {
-                            let size = meta.len() as i64;
-                            let mime_type = mime_guess::from_path(&path)
-                                .first_or_octet_stream()
-                                .to_string();
-
-                            let n = NewMediaEntry {
-                                name: name.clone(),
-                                path: rel_path.clone(),
-                                parent_id: parent,
-                                mime_type: Some(mime_type),
-                                size: Some(size),
-                                tags: None,
-                                thumb_path: None,
-                                width: None,
-                                height: None,
-                                duration_secs: None,
-                            };
-
-                            // Buffer file entries for batch processing
-                            file_buffer.push(n);
-
-                            // When buffer reaches BATCH_SIZE, process the batch in a transaction
-                            if file_buffer.len() >= BATCH_SIZE {
-                                flush_file_buffer(&pool, &mut file_buffer)
-                                    .await
-                                    .map_err(|e| format!("Failed to flush file buffer: {}", e))?;
-                            }
-                        }
-                    } else {
-                        break;
+            let entry = match read_dir.next_entry().await {
+                Ok(Some(e)) => e,
+                Ok(None) => break,
+                Err(_) => {
+                    // A partial listing must not be mistaken for deletions
+                    if let Some(p) = progress {
+                        ScanProgress::bump(&p.errors);
+                    }
+                    listing_complete = false;
+                    break;
+                }
+            };
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            // compute relative path from root
+            let rel_path = match path.strip_prefix(root) {
+                Ok(p) => p.to_string_lossy().to_string(),
+                Err(_) => path.to_string_lossy().to_string(),
+            };
+            seen.insert(rel_path.clone());
+
+            // use async metadata
+            let meta = match tokio::fs::metadata(&path).await {
+                Ok(m) => m,
+                Err(_) => {
+                    if let Some(p) = progress {
+                        ScanProgress::bump(&p.errors);
                     }
+                    continue;
+                }
+            };
+
+            let existing = indexed.get(&rel_path);
+
+            if meta.is_dir() {
+                // Known directories keep their id; nothing about them needs rewriting.
+                if let Some(child) = existing.filter(|c| c.is_dir) {
+                    summary.unchanged += 1;
+                    stack.push((path, Some(child.id)));
+                    continue;
+                }
+
+                // Directories must be upserted immediately because we need their ID for traversal
+                let n = new_directory_entry(name, rel_path, parent);
+
+                let new_parent_id = db::upsert_media(pool.clone(), &n)
+                    .await
+                    .map_err(|e| format!("db upsert error: {}", e))?;
+
+                if existing.is_some() {
+                    summary.updated += 1;
+                } else {
+                    summary.added += 1;
+                }
+                stack.push((path, Some(new_parent_id)));
+            } else if meta.is_file() {
+                if let Some(p) = progress {
+                    ScanProgress::bump(&p.files_seen);
+                }
+                let fp = Fingerprint::from_metadata(&meta);
+                match existing {
+                    Some(child) if fp.matches(child) => {
+                        summary.unchanged += 1;
+                        continue;
+                    }
+                    Some(child) if child.is_dir => {
+                        // A directory was replaced by a file; drop the old subtree first
+                        prune_stale_paths(&pool, std::slice::from_ref(&rel_path), &mut removed_ids)
+                            .await
+                            .map_err(|e| format!("Failed to prune stale rows: {}", e))?;
+                        summary.added += 1;
+                    }
+                    Some(_) => summary.updated += 1,
+                    None => summary.added += 1,
+                }
+
+                let n = new_file_entry(name, rel_path, parent, &path, &fp);
+                let (n, meta) = read_file_metadata_blocking(n, path).await?;
+
+                // Buffer file entries for batch processing
+                file_buffer.push((n, meta));
+
+                // When buffer reaches BATCH_SIZE, process the batch in a transaction
+                if file_buffer.len() >= BATCH_SIZE {
+                    flush_file_buffer(&pool, &mut file_buffer)
+                        .await
+                        .map_err(|e| format!("Failed to flush file buffer: {}", e))?;
                 }
-                Err(_) => break,
             }
         }
+
+        if listing_complete {
+            let stale: Vec<String> = indexed
+                .keys()
+                .filter(|p| !seen.contains(*p))
+                .cloned()
+                .collect();
+            prune_stale_paths(&pool, &stale, &mut removed_ids)
+                .await
+                .map_err(|e| format!("Failed to prune stale rows: {}", e))?;
+        }
     }
 
     // Flush any remaining files in the buffer
@@ -130,5 +403,10 @@ pub async fn scan_directory_and_index(
             .map_err(|e| format!("Failed to flush file buffer: {}", e))?;
     }
 
-    Ok(())
+    summary.removed = removed_ids.len();
+    if let Some(thumbs_dir) = thumbnails_dir {
+        remove_cached_thumbnails(&thumbs_dir, &removed_ids).await;
+    }
+
+    Ok(summary)
 }
@@ -1,8 +1,16 @@
+use crate::handlers::hls::remove_stale_transcodes;
+use crate::handlers::thumbnails::{
+    is_cache_file, parse_thumbnail_name, remove_orphaned_thumbnails, touch_thumbnail,
+};
+use axum::body::Body;
+use axum::http::Request;
+use axum::middleware::{from_fn, Next};
 use crate::{config::AppConfig, db::initialize_database};
 use axum::http::{HeaderValue, Method};
-use axum::routing::{get_service, MethodRouter};
+use axum::routing::{delete, get, get_service, post, MethodRouter};
 use image::{ImageOutputFormat, RgbImage};
 use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
 use std::path::{Path, PathBuf};
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
@@ -10,6 +18,25 @@ use axum::response::IntoResponse;
 use axum::http::StatusCode;
 use axum::extract::Path as AxumPath;
 use mime_guess::from_path;
+use crate::handlers::admin;
+use crate::handlers::{
+    accept_invite_handler, add_tags_handler, create_api_key_handler, create_share_handler,
+    delete_user_handler, generate_thumbnail_handler, get_album_handler, get_file_details_handler,
+    get_scan_job_handler, get_user_handler, hls_handler, invite_user_handler, list_albums_handler,
+    list_api_keys_handler, list_artists_handler, list_directory_handler, list_shares_handler,
+    list_tags_handler, list_tracks_handler, list_users_handler, login_handler, logout_handler,
+    me_handler, preview_handler, remove_tags_handler, require_auth, reset_user_handler,
+    revoke_api_key_handler, revoke_share_handler, search_handler, shared_handler, sprite_handler,
+    stream_handler, thumbnail_handler, trigger_scan_handler, unlock_share_handler,
+    update_user_handler,
+};
+use crate::handlers::auth::ensure_access;
+use crate::models::User;
+use crate::state::AppState;
+use axum::middleware::from_fn_with_state;
+use axum::Router;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 pub fn load_config(cli_path: Option<PathBuf>) -> Result<AppConfig, Box<dyn std::error::Error>> {
     use ::config::{builder::DefaultState, ConfigBuilder, File};
@@ -81,7 +108,7 @@ pub fn load_config(cli_path: Option<PathBuf>) -> Result<AppConfig, Box<dyn std::
         tracing::info!("Using configuration file: {}", cfg_path.display());
         builder = builder.add_source(File::from(cfg_path));
     } else {
-        return Err(format!("No config.json found. Provide --config <file.json> or place config.json in ./, server/, XDG (~/.config/media-server/), or /etc/media-server/").into());
+        return Err("No config.json found. Provide --config <file.json> or place config.json in ./, server/, XDG (~/.config/media-server/), or /etc/media-server/".into());
     }
 
     let settings = builder
@@ -114,9 +141,10 @@ pub async fn init_db(config: &AppConfig) -> sqlx::SqlitePool {
         .connect(&db_url)
         .await
         .expect("Failed to create sqlx pool");
-    initialize_database(pool.clone())
-        .await
-        .expect("db init failed");
+    if let Err(e) = initialize_database(pool.clone()).await {
+        eprintln!("Database initialization failed: {}", e);
+        std::process::exit(1);
+    }
     pool
 }
 
@@ -136,14 +164,20 @@ pub fn resolve_thumbnails_dir(config: &AppConfig) -> PathBuf {
     }
 }
 
-pub fn resolve_client_dist_dir(config: &AppConfig) -> Option<PathBuf> {
-    if let Some(d) = config.client_dist_dir.clone() {
-        Some(PathBuf::from(d))
-    } else {
-        None
+pub fn resolve_transcode_dir(config: &AppConfig) -> PathBuf {
+    if let Some(t) = config.transcode_dir.clone() {
+        return PathBuf::from(t);
+    }
+    match resolve_thumbnails_dir(config).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.join("transcode"),
+        _ => PathBuf::from(".transcode"),
     }
 }
 
+pub fn resolve_client_dist_dir(config: &AppConfig) -> Option<PathBuf> {
+    config.client_dist_dir.clone().map(PathBuf::from)
+}
+
 /// Build a service that serves static client files, with a fallback to index.html
 /// for SPA client-side routing. The caller should mount this under `/` or `/app`.
 pub fn build_client_service(client_dist: PathBuf) -> axum::Router {
@@ -220,7 +254,13 @@ pub fn build_client_service(client_dist: PathBuf) -> axum::Router {
         .route("/*path", axum::routing::get(spa_handler))
 }
 
-pub fn prepare_thumbnails_cache(thumbnails_dir_path: &Path) {
+/// Create the thumbnails directory and its placeholder, then clear out temp
+/// files left by interrupted runs and thumbnails no media entry can use.
+pub async fn prepare_thumbnails_cache(
+    thumbnails_dir_path: &Path,
+    pool: &SqlitePool,
+    media_root: &Path,
+) {
     let _ = std::fs::create_dir_all(thumbnails_dir_path);
     tracing::info!("Thumbnails directory: {}", thumbnails_dir_path.display());
     // Ensure placeholder
@@ -239,7 +279,7 @@ pub fn prepare_thumbnails_cache(thumbnails_dir_path: &Path) {
         let now = std::time::SystemTime::now();
         for e in entries.flatten() {
             if let Ok(fname) = e.file_name().into_string() {
-                if fname.ends_with(".jpg") && fname.matches('.').count() >= 2 {
+                if is_cache_file(&fname) && fname.matches('.').count() >= 2 {
                     if let Ok(meta) = e.metadata() {
                         if let Ok(modified) = meta.modified() {
                             if let Ok(age) = now.duration_since(modified) {
@@ -253,21 +293,142 @@ pub fn prepare_thumbnails_cache(thumbnails_dir_path: &Path) {
             }
         }
     }
+    // GC thumbnails of deleted or changed sources
+    let removed = remove_orphaned_thumbnails(pool, thumbnails_dir_path, media_root).await;
+    if removed > 0 {
+        tracing::info!("Removed {} orphaned thumbnail(s)", removed);
+    }
+}
+
+/// Create the HLS transcode cache and drop segments of deleted or changed
+/// sources left from earlier runs.
+pub async fn prepare_transcode_cache(transcode_dir: &Path, pool: &SqlitePool, media_root: &Path) {
+    let _ = std::fs::create_dir_all(transcode_dir);
+    tracing::info!("Transcode directory: {}", transcode_dir.display());
+    let removed = remove_stale_transcodes(pool, transcode_dir, media_root).await;
+    if removed > 0 {
+        tracing::info!("Removed {} stale transcode cache(s)", removed);
+    }
 }
 
-pub fn build_thumbnails_service(thumbnails_dir_path: PathBuf) -> MethodRouter {
-    get_service(ServeDir::new(thumbnails_dir_path)).handle_error(|e: std::io::Error| async move {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Unhandled internal error: {}", e),
+pub fn build_thumbnails_service(thumbnails_dir_path: PathBuf, pool: SqlitePool) -> MethodRouter {
+    let dir = std::sync::Arc::new(thumbnails_dir_path.clone());
+    get_service(ServeDir::new(thumbnails_dir_path))
+        .handle_error(|e: std::io::Error| async move {
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Unhandled internal error: {}", e),
+            )
+        })
+        // Cached files are named after their media id, so they get the same
+        // ACL check as the media routes. Served thumbnails count as recently
+        // used for cache eviction, and so do 304s: browsers revalidate the
+        // thumbnails they already hold
+        .layer(from_fn(move |req: Request<Body>, next: Next<Body>| {
+            let dir = dir.clone();
+            let pool = pool.clone();
+            async move {
+                let name = req.uri().path().trim_start_matches('/').to_string();
+                if name != "placeholder.jpg" {
+                    let id = name
+                        .split_once('_')
+                        .and_then(|(id, _)| id.parse::<i64>().ok());
+                    let (Some(id), Some(user)) = (id, req.extensions().get::<User>()) else {
+                        return (StatusCode::NOT_FOUND, "Not found".to_string()).into_response();
+                    };
+                    if let Err(e) = ensure_access(&pool, user, id).await {
+                        return e.into_response();
+                    }
+                }
+                let res = next.run(req).await;
+                let hit = res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED;
+                if hit && parse_thumbnail_name(&name).is_some() {
+                    touch_thumbnail(&dir.join(&name));
+                }
+                res
+            }
+        }))
+}
+
+/// All API routes with their auth layer, as served by main. The SPA fallback
+/// and CORS depend on the config and are added by the caller.
+pub async fn build_router(state: Arc<Mutex<AppState>>) -> Router {
+    let (thumbnails_dir, pool) = {
+        let guard = state.lock().await;
+        (guard.thumbnails_dir.clone(), guard.pool.clone())
+    };
+    let mut protected = Router::new()
+        .route("/scan", post(trigger_scan_handler))
+        .route("/scan/:id", get(get_scan_job_handler))
+        .route("/media", get(list_directory_handler))
+        .route("/media/details", get(get_file_details_handler))
+        .route("/media/search", get(search_handler))
+        .route(
+            "/media/:id/tags",
+            post(add_tags_handler).delete(remove_tags_handler),
+        )
+        .route("/tags", get(list_tags_handler))
+        .route("/music/artists", get(list_artists_handler))
+        .route("/music/albums", get(list_albums_handler))
+        .route("/music/albums/:id", get(get_album_handler))
+        .route("/music/tracks", get(list_tracks_handler))
+        .route("/media/thumbnail", get(thumbnail_handler))
+        .route("/media/generate_thumbnail", get(generate_thumbnail_handler))
+        .route(
+            "/admin/regenerate_thumbnails",
+            post(admin::regenerate_thumbnails_handler),
+        )
+        .route("/media/stream", get(stream_handler))
+        .route("/media/image", get(stream_handler))
+        .route("/media/hls/:id/:file", get(hls_handler))
+        .route("/media/sprites/:id/:file", get(sprite_handler))
+        .route("/media/preview", get(preview_handler))
+        .route(
+            "/admin/users",
+            get(list_users_handler).post(invite_user_handler),
         )
-    })
+        .route(
+            "/admin/users/:id",
+            get(get_user_handler)
+                .patch(update_user_handler)
+                .delete(delete_user_handler),
+        )
+        .route("/admin/users/:id/reset", post(reset_user_handler))
+        .route(
+            "/admin/api_keys",
+            get(list_api_keys_handler).post(create_api_key_handler),
+        )
+        .route("/admin/api_keys/:id", delete(revoke_api_key_handler))
+        .route("/shares", post(create_share_handler))
+        .route("/admin/shares", get(list_shares_handler))
+        .route("/admin/shares/:id", delete(revoke_share_handler))
+        .route("/auth/logout", post(logout_handler))
+        .route("/auth/me", get(me_handler));
+    if let Some(dir) = thumbnails_dir {
+        protected =
+            protected.nest_service("/thumbnails", build_thumbnails_service(dir.into(), pool));
+    }
+    protected
+        // Everything above needs a session; unmatched paths fall through
+        // to the SPA, which stays reachable so users can log in
+        .route_layer(from_fn_with_state(state.clone(), require_auth))
+        .route("/auth/login", post(login_handler))
+        .route("/auth/accept_invite", post(accept_invite_handler))
+        .route("/s/:token", get(shared_handler))
+        .route("/s/:token/unlock", post(unlock_share_handler))
+        .with_state(state)
 }
 
 pub fn build_cors(config: &AppConfig) -> Result<Option<CorsLayer>, String> {
     if let Some(false) = config.cors_enabled { return Ok(None); }
     let mut cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers(Any);
 
     if config.cors_allow_credentials.unwrap_or(false) {
@@ -1,9 +1,14 @@
+use crate::jobs::ScanJobs;
 use sqlx::SqlitePool;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::oneshot;
 use tokio::sync::{Mutex, Semaphore};
 
+// Waiters registered for an in-flight thumbnail job, keyed by "<id>:<w>x<h>"
+// (or "<id>:sprite" for scrubbing sprite sheets).
+pub type InFlightMap = HashMap<String, Vec<oneshot::Sender<Result<(), String>>>>;
+
 #[derive(Clone)]
 pub struct AppState {
     pub pool: SqlitePool,
@@ -17,5 +22,12 @@ pub struct AppState {
     pub regen_semaphore: Arc<Semaphore>,
     // Track in-flight keys mapping to waiters so concurrent callers can wait for
     // completion instead of spawning duplicate work. Key -> Vec<oneshot::Sender<Result<(),String>>>
-    pub in_flight: Arc<Mutex<HashMap<String, Vec<oneshot::Sender<Result<(), String>>>>>>,
+    pub in_flight: Arc<Mutex<InFlightMap>>,
+    // HLS segment cache and the limit on concurrent ffmpeg transcodes
+    pub transcode_dir: Option<String>,
+    pub transcode_semaphore: Arc<Semaphore>,
+    // Background scans started through POST /scan
+    pub scan_jobs: Arc<Mutex<ScanJobs>>,
+    // Whether the session cookie is marked Secure (HTTPS only)
+    pub cookie_secure: bool,
 }
@@ -0,0 +1,259 @@
+use crate::db;
+use crate::handlers::thumbnails::remove_cached_thumbnails;
+use crate::scanner::{self, Fingerprint};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const DEFAULT_DEBOUNCE_MS: u64 = 1000;
+
+// Events that can change what the index should contain
+fn watch_mask() -> AddWatchFlags {
+    AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_ONLYDIR
+}
+
+enum WatchEvent {
+    // Something at this absolute path was created, written, moved or deleted
+    Changed(PathBuf),
+    // The kernel dropped events; only a full rescan can recover
+    Overflow,
+}
+
+/// Start watching `root` recursively with inotify and keep the index in sync.
+/// Events are debounced for `debounce_ms` (default 1s) and then applied as
+/// targeted upserts and deletes.
+pub fn spawn_watcher(
+    pool: SqlitePool,
+    root: PathBuf,
+    thumbnails_dir: Option<PathBuf>,
+    debounce_ms: Option<u64>,
+) -> Result<(), String> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(|e| e.to_string())?;
+    let mut watches: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    add_watches_recursive(inotify, &root, &mut watches);
+    tracing::info!(
+        "Watching {} directories under {}",
+        watches.len(),
+        root.display()
+    );
+
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    // inotify reads block, so they live on a dedicated OS thread
+    std::thread::Builder::new()
+        .name("media-watcher".to_string())
+        .spawn(move || read_events_loop(inotify, watches, tx))
+        .map_err(|e| e.to_string())?;
+
+    let debounce = Duration::from_millis(debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS));
+    tokio::spawn(apply_events_loop(pool, root, thumbnails_dir, debounce, rx));
+    Ok(())
+}
+
+fn add_watches_recursive(
+    inotify: Inotify,
+    dir: &Path,
+    watches: &mut HashMap<WatchDescriptor, PathBuf>,
+) {
+    for entry in walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+    {
+        match inotify.add_watch(entry.path(), watch_mask()) {
+            Ok(wd) => {
+                watches.insert(wd, entry.path().to_path_buf());
+            }
+            Err(e) => {
+                // Typically ENOSPC: fs.inotify.max_user_watches is too low
+                tracing::warn!("Failed to watch {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+fn read_events_loop(
+    inotify: Inotify,
+    mut watches: HashMap<WatchDescriptor, PathBuf>,
+    tx: mpsc::UnboundedSender<WatchEvent>,
+) {
+    loop {
+        let events = match inotify.read_events() {
+            Ok(evs) => evs,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => {
+                tracing::error!("inotify read failed, watcher stopped: {}", e);
+                return;
+            }
+        };
+
+        for ev in events {
+            if ev.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                if tx.send(WatchEvent::Overflow).is_err() {
+                    return;
+                }
+                continue;
+            }
+            if ev.mask.contains(AddWatchFlags::IN_IGNORED) {
+                watches.remove(&ev.wd);
+                continue;
+            }
+            let (dir, name) = match (watches.get(&ev.wd), ev.name) {
+                (Some(d), Some(n)) => (d.clone(), n),
+                _ => continue,
+            };
+            let path = dir.join(name);
+
+            // New directories need watches of their own before anything lands in them
+            if ev.mask.contains(AddWatchFlags::IN_ISDIR)
+                && ev
+                    .mask
+                    .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+            {
+                add_watches_recursive(inotify, &path, &mut watches);
+            }
+
+            if tx.send(WatchEvent::Changed(path)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn apply_events_loop(
+    pool: SqlitePool,
+    root: PathBuf,
+    thumbnails_dir: Option<PathBuf>,
+    debounce: Duration,
+    mut rx: mpsc::UnboundedReceiver<WatchEvent>,
+) {
+    while let Some(first) = rx.recv().await {
+        // Collect until the stream has been quiet for one debounce window
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+        let mut overflow = false;
+        let mut next = Some(first);
+        while let Some(ev) = next {
+            match ev {
+                WatchEvent::Changed(p) => {
+                    changed.insert(p);
+                }
+                WatchEvent::Overflow => overflow = true,
+            }
+            next = tokio::time::timeout(debounce, rx.recv())
+                .await
+                .ok()
+                .flatten();
+        }
+
+        if overflow {
+            tracing::warn!("inotify queue overflowed; rescanning {}", root.display());
+            if let Err(e) = scanner::scan_directory_and_index(
+                pool.clone(),
+                root.to_string_lossy().to_string(),
+                None,
+                thumbnails_dir.clone(),
+                None,
+            )
+            .await
+            {
+                tracing::error!("Watcher rescan failed: {}", e);
+            }
+            continue;
+        }
+
+        // Parents first, so a new directory exists before its children are attached
+        let mut paths: Vec<PathBuf> = changed.into_iter().collect();
+        paths.sort_by_key(|p| p.components().count());
+        for p in paths {
+            if let Err(e) = apply_change(&pool, &root, thumbnails_dir.as_deref(), &p).await {
+                tracing::error!("Watcher failed to index {}: {}", p.display(), e);
+            }
+        }
+    }
+}
+
+// Bring the row for `path` (and anything below it) in line with the filesystem.
+async fn apply_change(
+    pool: &SqlitePool,
+    root: &Path,
+    thumbnails_dir: Option<&Path>,
+    path: &Path,
+) -> Result<(), String> {
+    let rel_path = match path.strip_prefix(root) {
+        Ok(p) if !p.as_os_str().is_empty() => p.to_string_lossy().to_string(),
+        _ => return Ok(()),
+    };
+
+    let meta = match tokio::fs::metadata(path).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut removed = HashSet::new();
+            scanner::prune_stale_paths(pool, std::slice::from_ref(&rel_path), &mut removed)
+                .await
+                .map_err(|e| e.to_string())?;
+            if let Some(td) = thumbnails_dir {
+                remove_cached_thumbnails(td, &removed).await;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // Resolve the parent row; the media root itself has no row
+    let parent_id = match Path::new(&rel_path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => {
+            match db::get_media_by_path(pool.clone(), p.to_string_lossy().to_string())
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                Some(parent) => Some(parent.id),
+                // Parent not indexed yet; the next scan will pick this up
+                None => return Ok(()),
+            }
+        }
+        _ => None,
+    };
+
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if meta.is_dir() {
+        let n = scanner::new_directory_entry(name, rel_path, parent_id);
+        let id = db::upsert_media(pool.clone(), &n)
+            .await
+            .map_err(|e| e.to_string())?;
+        // Directories moved in from elsewhere arrive with their contents
+        scanner::scan_subtree(
+            pool.clone(),
+            root,
+            path.to_path_buf(),
+            Some(id),
+            thumbnails_dir.map(Path::to_path_buf),
+            None,
+        )
+        .await?;
+    } else if meta.is_file() {
+        let fp = Fingerprint::from_metadata(&meta);
+        let n = scanner::new_file_entry(name, rel_path, parent_id, path, &fp);
+        let (n, meta) = scanner::read_file_metadata_blocking(n, path.to_path_buf()).await?;
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let id = db::upsert_media_in_tx(&mut tx, &n)
+            .await
+            .map_err(|e| e.to_string())?;
+        scanner::store_file_metadata_in_tx(&mut tx, id, &meta)
+            .await
+            .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
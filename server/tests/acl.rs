@@ -0,0 +1,254 @@
+mod common;
+
+use axum::body::Body;
+use axum::extract::{Extension, Query, State};
+use axum::http::{header, HeaderMap, Request, StatusCode, Uri};
+use server::auth::{hash_token, now_secs};
+use server::db;
+use server::handlers::{
+    get_file_details_handler, list_directory_handler, search_handler, stream_handler,
+    thumbnail_handler,
+};
+use server::models::{Role, User};
+use server::startup::build_router;
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+fn query<T: serde::de::DeserializeOwned>(uri: &str) -> Query<T> {
+    Query::try_from_uri(&uri.parse::<Uri>().unwrap()).unwrap()
+}
+
+fn names(v: &serde_json::Value) -> Vec<String> {
+    let mut names: Vec<String> = v["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["path"].as_str().unwrap().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn grants_are_inherited_and_enforced() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(media_dir.join("family/2024"));
+    let _ = std::fs::create_dir_all(media_dir.join("work"));
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    image::RgbImage::from_pixel(8, 8, image::Rgb([200, 100, 50]))
+        .save(media_dir.join("family/2024/beach.jpg"))
+        .unwrap();
+    std::fs::write(media_dir.join("work/clip.mp4"), b"video").unwrap();
+    std::fs::write(media_dir.join("beach notes.txt"), b"notes").unwrap();
+
+    let pool = common::memory_pool().await;
+    let mut users = Vec::new();
+    for name in ["alice", "bob"] {
+        let id = db::create_user(&pool, name, "unused", Role::Viewer)
+            .await
+            .expect("create user");
+        users.push(User {
+            id,
+            username: name.to_string(),
+            created_at: String::new(),
+            role: Role::Viewer,
+            disabled: false,
+        });
+    }
+    let (alice, bob) = (users[0].clone(), users[1].clone());
+
+    let family = common::add(&pool, "family", None, None).await;
+    let year = common::add(&pool, "family/2024", Some(family), None).await;
+    let photo = common::add(
+        &pool,
+        "family/2024/beach.jpg",
+        Some(year),
+        Some("image/jpeg"),
+    )
+    .await;
+    let work = common::add(&pool, "work", None, None).await;
+    let clip = common::add(&pool, "work/clip.mp4", Some(work), Some("video/mp4")).await;
+    let notes = common::add(&pool, "beach notes.txt", None, Some("text/plain")).await;
+    db::set_acl(&pool, family, &[alice.id]).await.unwrap();
+    db::set_acl(&pool, work, &[alice.id, bob.id]).await.unwrap();
+    assert_eq!(
+        db::get_acl(&pool, work).await.unwrap(),
+        vec!["alice", "bob"]
+    );
+
+    // Grants reach everything below the directory they are set on
+    for id in [family, year, photo, work, clip, notes] {
+        assert!(db::can_access(&pool, alice.id, id).await.unwrap());
+    }
+    for (id, visible) in [
+        (family, false),
+        (year, false),
+        (photo, false),
+        (work, true),
+        (clip, true),
+        (notes, true),
+    ] {
+        assert_eq!(db::can_access(&pool, bob.id, id).await.unwrap(), visible);
+    }
+
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let list = |user: &User, uri: String| {
+        list_directory_handler(State(state.clone()), Extension(user.clone()), query(&uri))
+    };
+
+    // Listings leave out what the user cannot see
+    let root = list(&bob, "/media".to_string()).await.expect("list");
+    assert_eq!(names(&root.0), vec!["beach notes.txt", "work"]);
+    let root = list(&alice, "/media".to_string()).await.expect("list");
+    assert_eq!(names(&root.0), vec!["beach notes.txt", "family", "work"]);
+    let err = list(&bob, format!("/media?parent_id={}", year))
+        .await
+        .expect_err("hidden");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    let err = list(&bob, "/media?path=family/2024".to_string())
+        .await
+        .expect_err("hidden");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    // Admins are not bound by grants
+    let admin = User {
+        role: Role::Admin,
+        ..bob.clone()
+    };
+    let root = list(&admin, "/media".to_string()).await.expect("list");
+    assert_eq!(names(&root.0), vec!["beach notes.txt", "family", "work"]);
+    let listed = list(&alice, format!("/media?parent_id={}", year))
+        .await
+        .expect("list");
+    assert_eq!(names(&listed.0), vec!["family/2024/beach.jpg"]);
+
+    let hits = search_handler(
+        State(state.clone()),
+        Extension(bob.clone()),
+        query("/media/search?q=beach"),
+    )
+    .await
+    .expect("search");
+    assert_eq!(names(&hits.0), vec!["beach notes.txt"]);
+
+    // Hidden entries cannot be looked up, streamed or thumbnailed
+    let details = |user: &User, uri: String| {
+        get_file_details_handler(State(state.clone()), Extension(user.clone()), query(&uri))
+    };
+    let err = details(&bob, format!("/media/details?path={}", photo))
+        .await
+        .expect_err("hidden");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    let found = details(&alice, format!("/media/details?path={}", photo))
+        .await
+        .expect("details");
+    assert_eq!(found.0["id"], photo);
+    let stream = |user: &User| {
+        stream_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            query(&format!("/media/stream?id={}", photo)),
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+    };
+    let err = stream(&bob).await.expect_err("hidden");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    assert_eq!(
+        stream(&alice).await.expect("stream").status(),
+        StatusCode::OK
+    );
+    let thumb = |user: &User| {
+        thumbnail_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            HeaderMap::new(),
+            query(&format!("/media/thumbnail?id={}&w=4&h=4", photo)),
+        )
+    };
+    let err = thumb(&bob).await.expect_err("hidden");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    assert!(thumb(&alice).await.is_ok());
+
+    // A nested grant narrows access instead of widening it
+    db::set_acl(&pool, year, &[bob.id]).await.unwrap();
+    assert!(!db::can_access(&pool, bob.id, photo).await.unwrap());
+    assert!(!db::can_access(&pool, alice.id, photo).await.unwrap());
+    assert!(db::can_access(&pool, alice.id, family).await.unwrap());
+
+    // Clearing the grants opens the directory up again
+    db::set_acl(&pool, year, &[]).await.unwrap();
+    db::set_acl(&pool, family, &[]).await.unwrap();
+    assert!(db::get_acl(&pool, family).await.unwrap().is_empty());
+    assert!(db::can_access(&pool, bob.id, photo).await.unwrap());
+    let root = list(&bob, "/media".to_string()).await.expect("list");
+    assert_eq!(names(&root.0), vec!["beach notes.txt", "family", "work"]);
+}
+
+#[tokio::test]
+async fn cached_thumbnails_of_hidden_entries_are_not_served() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+
+    let pool = common::memory_pool().await;
+    let alice = db::create_user(&pool, "alice", "unused", Role::Viewer)
+        .await
+        .expect("create user");
+    let bob = db::create_user(&pool, "bob", "unused", Role::Viewer)
+        .await
+        .expect("create user");
+    for (token, id) in [("alice", alice), ("bob", bob)] {
+        db::create_session(&pool, &hash_token(token), id, now_secs() + 3600, now_secs())
+            .await
+            .unwrap();
+    }
+    let family = common::add(&pool, "family", None, None).await;
+    let photo = common::add(&pool, "family/beach.jpg", Some(family), Some("image/jpeg")).await;
+    let clip = common::add(&pool, "clip.mp4", None, Some("video/mp4")).await;
+    db::set_acl(&pool, family, &[alice]).await.unwrap();
+
+    let photo_thumb = format!("{}_0123456789abcdef_32x32.jpg", photo);
+    let photo_sprite = format!("{}_0123456789abcdef_sprite.jpg", photo);
+    let clip_thumb = format!("{}_0123456789abcdef_32x32.jpg", clip);
+    for name in [
+        &photo_thumb,
+        &photo_sprite,
+        &clip_thumb,
+        &"stray.jpg".to_string(),
+    ] {
+        std::fs::write(thumbs_dir.join(name), b"thumb").unwrap();
+    }
+    std::fs::write(thumbs_dir.join("placeholder.jpg"), b"placeholder").unwrap();
+
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let app = build_router(state).await;
+    let get = |token: &str, name: &str| {
+        Request::get(format!("/thumbnails/{}", name))
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for (token, name, expected) in [
+        ("bob", &photo_thumb, StatusCode::NOT_FOUND),
+        ("bob", &photo_sprite, StatusCode::NOT_FOUND),
+        ("bob", &clip_thumb, StatusCode::OK),
+        ("bob", &"placeholder.jpg".to_string(), StatusCode::OK),
+        ("bob", &"stray.jpg".to_string(), StatusCode::NOT_FOUND),
+        ("alice", &photo_thumb, StatusCode::OK),
+        ("alice", &photo_sprite, StatusCode::OK),
+    ] {
+        let (status, _, _) = common::send(&app, get(token, name)).await;
+        assert_eq!(status, expected, "{} fetching {}", token, name);
+    }
+}
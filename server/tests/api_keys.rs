@@ -0,0 +1,171 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use server::auth::{hash_token, new_api_key, now_secs, required_scope, API_KEY_PREFIX};
+use server::db;
+use server::models::{Role, Scope};
+use server::startup::build_router;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn routes_map_to_scopes() {
+    assert!(new_api_key().starts_with(API_KEY_PREFIX));
+    for (method, path, scope) in [
+        (Method::GET, "/media", Scope::Read),
+        (Method::GET, "/media/search", Scope::Read),
+        (Method::GET, "/thumbnails/1_abc_200x200.jpg", Scope::Read),
+        (Method::GET, "/media/stream", Scope::Stream),
+        (Method::HEAD, "/media/stream", Scope::Stream),
+        (Method::GET, "/media/hls/4/seg0.ts", Scope::Stream),
+        (Method::POST, "/scan", Scope::Scan),
+        (Method::GET, "/scan/3", Scope::Scan),
+        (Method::GET, "/scanner", Scope::Read),
+        (Method::GET, "/admin/users", Scope::Admin),
+        (Method::POST, "/media/4/tags", Scope::Admin),
+    ] {
+        assert_eq!(required_scope(&method, path), scope, "{} {}", method, path);
+    }
+}
+
+#[tokio::test]
+async fn keys_authenticate_within_their_scopes() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"video").unwrap();
+
+    let pool = common::memory_pool().await;
+    let admin_id = db::create_user(&pool, "root", "unused", Role::Admin)
+        .await
+        .expect("create admin");
+    let viewer_id = db::create_user(&pool, "vera", "unused", Role::Viewer)
+        .await
+        .expect("create viewer");
+    // Bootstrap key for calling the admin endpoints
+    let root_key = new_api_key();
+    db::create_api_key(
+        &pool,
+        "bootstrap",
+        &hash_token(&root_key),
+        admin_id,
+        &[Scope::Admin],
+        None,
+    )
+    .await
+    .unwrap();
+    let state = Arc::new(TokioMutex::new(common::app_state(pool.clone(), &media_dir)));
+
+    let app = build_router(state).await;
+
+    let create = |body: serde_json::Value| {
+        common::send_json(&app, "POST", "/admin/api_keys", Some(&root_key), Some(body))
+    };
+    let (status, created) = create(serde_json::json!({
+        "name": "ingest",
+        "scopes": ["scan", "scan"],
+        "expires_at": now_secs() + 3600,
+    }))
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", created);
+    let ingest = created["key"].as_str().unwrap().to_string();
+    let ingest_id = created["api_key"]["id"].as_i64().unwrap();
+    assert_eq!(created["api_key"]["scopes"], serde_json::json!(["scan"]));
+    assert_eq!(created["api_key"]["username"], "root");
+    assert!(created["api_key"]["last_used_at"].is_null());
+
+    // A scan-only key can start scans and nothing else
+    let (status, job) = common::send_json(&app, "POST", "/scan", Some(&ingest), None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    // Wait for the scan so clip.mp4 can be streamed below
+    let job_uri = format!("/scan/{}", job["id"]);
+    let mut finished = false;
+    for _ in 0..50 {
+        let (status, job) = common::send_json(&app, "GET", &job_uri, Some(&ingest), None).await;
+        assert_eq!(status, StatusCode::OK, "{}", job);
+        if job["state"] != "running" {
+            finished = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(finished, "scan job did not finish");
+    for (method, uri) in [("GET", "/media"), ("GET", "/admin/api_keys")] {
+        let (status, _) = common::send_json(&app, method, uri, Some(&ingest), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+    }
+    let (_, listed) =
+        common::send_json(&app, "GET", "/admin/api_keys", Some(&root_key), None).await;
+    let entry = listed["api_keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|k| k["id"] == ingest_id)
+        .unwrap()
+        .clone();
+    assert!(entry["last_used_at"].as_i64().unwrap() >= now_secs() - 5);
+    assert!(!listed.to_string().contains(&ingest));
+
+    // Keys for viewers act as the viewer and cannot carry scan or admin
+    let (status, _) = create(serde_json::json!({
+        "name": "player", "scopes": ["scan"], "user_id": viewer_id,
+    }))
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, created) = create(serde_json::json!({
+        "name": "player", "scopes": ["stream"], "user_id": viewer_id,
+    }))
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let player = created["key"].as_str().unwrap().to_string();
+    let (status, _) = common::send_json(
+        &app,
+        "GET",
+        "/media/stream?path=clip.mp4",
+        Some(&player),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::send_json(&app, "GET", "/media", Some(&player), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    db::update_user(&pool, viewer_id, None, Some(true))
+        .await
+        .unwrap();
+    let (status, _) = common::send_json(
+        &app,
+        "GET",
+        "/media/stream?path=clip.mp4",
+        Some(&player),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Expired and revoked keys are rejected
+    let (status, _) = create(serde_json::json!({
+        "name": "old", "scopes": ["read"], "expires_at": now_secs() - 1,
+    }))
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let expired = new_api_key();
+    db::create_api_key(
+        &pool,
+        "expired",
+        &hash_token(&expired),
+        admin_id,
+        &[Scope::Read],
+        Some(now_secs() - 1),
+    )
+    .await
+    .unwrap();
+    let (status, _) = common::send_json(&app, "GET", "/media", Some(&expired), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let revoke_uri = format!("/admin/api_keys/{}", ingest_id);
+    let (status, _) = common::send_json(&app, "DELETE", &revoke_uri, Some(&root_key), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = common::send_json(&app, "POST", "/scan", Some(&ingest), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = common::send_json(&app, "DELETE", &revoke_uri, Some(&root_key), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use server::auth::{dummy_password_hash, hash_password, request_token, verify_password};
+use server::db;
+use server::models::Role;
+use server::startup::{build_client_service, build_router};
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn passwords_and_tokens() {
+    let hash = hash_password("hunter2").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(verify_password("hunter2", &hash));
+    assert!(!verify_password("hunter3", &hash));
+    assert!(!verify_password("hunter2", "not a hash"));
+    assert!(!verify_password("", dummy_password_hash()));
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(request_token(&headers), None);
+    headers.insert(
+        header::COOKIE,
+        HeaderValue::from_static("theme=dark; session=abc123"),
+    );
+    assert_eq!(request_token(&headers).as_deref(), Some("abc123"));
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_static("Bearer xyz789"),
+    );
+    assert_eq!(request_token(&headers).as_deref(), Some("xyz789"));
+}
+
+fn login(username: &str, password: &str) -> Request<Body> {
+    Request::post("/auth/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "username": username, "password": password }).to_string(),
+        ))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn api_routes_require_a_session() {
+    let base = common::TestDir::new();
+    let thumbs_dir = base.join("thumbnails");
+    let client_dir = base.join("client");
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    let _ = std::fs::create_dir_all(&client_dir);
+    std::fs::write(thumbs_dir.join("placeholder.jpg"), b"jpeg").unwrap();
+    std::fs::write(client_dir.join("index.html"), b"<html>app</html>").unwrap();
+
+    let pool = common::memory_pool().await;
+    db::create_user(
+        &pool,
+        "alice",
+        &hash_password("hunter2").unwrap(),
+        Role::Viewer,
+    )
+    .await
+    .expect("create user");
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &base)
+    }));
+
+    let app = build_router(state.clone())
+        .await
+        .merge(build_client_service(client_dir.clone()));
+
+    // Anonymous requests are turned away from the API but get the SPA
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    let (status, headers, _) = common::send(&app, get("/media")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(headers[header::WWW_AUTHENTICATE], "Bearer");
+    let (status, _, _) = common::send(&app, get("/thumbnails/placeholder.jpg")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    for uri in ["/", "/albums/3"] {
+        let (status, _, body) = common::send(&app, get(uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "<html>app</html>");
+    }
+    let (status, _, _) = common::send(
+        &app,
+        Request::get("/media")
+            .header(header::AUTHORIZATION, "Bearer made-up")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Bad credentials
+    let (status, _, _) = common::send(&app, login("alice", "wrong")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = common::send(&app, login("bob", "hunter2")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Usernames are case-insensitive; the token comes back in the body and a cookie
+    let (status, headers, body) = common::send(&app, login("Alice", "hunter2")).await;
+    assert_eq!(status, StatusCode::OK);
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let token = v["token"].as_str().unwrap().to_string();
+    assert_eq!(token.len(), 64);
+    assert_eq!(v["user"]["username"], "alice");
+    let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+    assert!(cookie.starts_with(&format!("session={};", token)));
+    assert!(cookie.contains("HttpOnly"));
+    assert!(cookie.contains("; Secure"));
+
+    let bearer = format!("Bearer {}", token);
+    let (status, _, body) = common::send(
+        &app,
+        Request::get("/media")
+            .header(header::AUTHORIZATION, &bearer)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, _, _) = common::send(
+        &app,
+        Request::get("/thumbnails/placeholder.jpg")
+            .header(header::COOKIE, format!("session={}", token))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = common::send(
+        &app,
+        Request::get("/auth/me")
+            .header(header::COOKIE, format!("session={}", token))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("\"username\":\"alice\""));
+
+    // Logging out ends the session
+    let (status, headers, _) = common::send(
+        &app,
+        Request::post("/auth/logout")
+            .header(header::AUTHORIZATION, &bearer)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(headers[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .contains("Max-Age=0"));
+    let (status, _, _) = common::send(
+        &app,
+        Request::get("/auth/me")
+            .header(header::AUTHORIZATION, &bearer)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // Plain-HTTP setups can drop the Secure flag
+    state.lock().await.cookie_secure = false;
+    let (status, headers, _) = common::send(&app, login("alice", "hunter2")).await;
+    assert_eq!(status, StatusCode::OK);
+    let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+    assert!(!cookie.contains("Secure"));
+}
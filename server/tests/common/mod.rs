@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+use axum::body::{Body, HttpBody};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::Router;
+use server::db;
+use server::handlers::AdminUser;
+use server::jobs::ScanJobs;
+use server::models::{NewMediaEntry, Role, User};
+use server::state::AppState;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Semaphore};
+use tower::ServiceExt;
+
+/// A scratch directory under <crate>/tests/tmp, removed on drop so a failing
+/// test does not leave its files behind.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        let crate_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let dir = crate_root.join("tests").join("tmp").join(format!(
+            "media_server_test_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).expect("create test dir");
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The caller handlers get from the auth layer: a viewer with no grants, so
+/// everything without an access list is visible.
+pub fn user() -> User {
+    User {
+        id: 1,
+        username: "tester".to_string(),
+        created_at: String::new(),
+        role: Role::Viewer,
+        disabled: false,
+    }
+}
+
+/// The caller for admin-only handlers.
+pub fn admin() -> AdminUser {
+    AdminUser(User {
+        role: Role::Admin,
+        ..user()
+    })
+}
+
+/// In-memory database with the current schema.
+pub async fn memory_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect("sqlite::memory:")
+        .await
+        .expect("create in-memory db");
+    db::initialize_database(pool.clone())
+        .await
+        .expect("init db");
+    pool
+}
+
+/// State for serving `media_dir` with ffmpeg off and no caches; tests
+/// override fields with struct update syntax.
+pub fn app_state(pool: SqlitePool, media_dir: &Path) -> AppState {
+    AppState {
+        pool,
+        directory_to_scan: media_dir.to_string_lossy().to_string(),
+        ffmpeg_enabled: false,
+        ffmpeg_path: None,
+        ffprobe_path: None,
+        thumbnails_dir: None,
+        client_dist_dir: None,
+        regen_semaphore: Arc::new(Semaphore::new(4)),
+        in_flight: Arc::new(Mutex::new(HashMap::new())),
+        transcode_dir: None,
+        transcode_semaphore: Arc::new(Semaphore::new(2)),
+        scan_jobs: Arc::new(Mutex::new(ScanJobs::default())),
+        cookie_secure: true,
+    }
+}
+
+/// Index an entry named after the last component of `path`.
+pub async fn add(pool: &SqlitePool, path: &str, parent_id: Option<i64>, mime: Option<&str>) -> i64 {
+    db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            parent_id,
+            mime_type: mime.map(|m| m.to_string()),
+            size: None,
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: None,
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert media")
+}
+
+/// Run a request through the router and collect the response.
+pub async fn send(app: &Router, req: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let res = app.clone().oneshot(req).await.unwrap();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let mut body = res.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.expect("body chunk"));
+    }
+    (status, headers, String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Send a JSON request with an optional bearer token. Bodies that are not
+/// JSON come back as a string value.
+pub async fn send_json(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Option<serde_json::Value>,
+) -> (StatusCode, serde_json::Value) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = match body {
+        Some(v) => req
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(v.to_string())),
+        None => req.body(Body::empty()),
+    };
+    let (status, _, body) = send(app, req.unwrap()).await;
+    let v = serde_json::from_str(&body).unwrap_or(serde_json::Value::String(body));
+    (status, v)
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use server::config::AppConfig;
+use server::startup::{build_cors, build_router};
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[tokio::test]
+async fn preflight_allows_every_method_the_api_uses() {
+    let config: AppConfig = serde_json::from_value(serde_json::json!({
+        "db_path": "unused",
+        "directory_to_scan": "unused",
+        "cors_allowed_origins": ["https://app.example"],
+    }))
+    .unwrap();
+    let cors = build_cors(&config).unwrap().expect("cors enabled");
+
+    let pool = common::memory_pool().await;
+    let state = Arc::new(TokioMutex::new(common::app_state(
+        pool,
+        std::path::Path::new("."),
+    )));
+    let app = build_router(state).await.layer(cors);
+
+    for (method, uri) in [
+        ("GET", "/media"),
+        ("POST", "/shares"),
+        ("PATCH", "/admin/users/2"),
+        ("DELETE", "/media/4/tags"),
+    ] {
+        let (status, headers, _) = common::send(
+            &app,
+            Request::options(uri)
+                .header(header::ORIGIN, "https://app.example")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{} {}", method, uri);
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(allowed.contains(method), "{} not in {}", method, allowed);
+    }
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use axum::body::HttpBody;
+use axum::extract::{Extension, Path, State};
+use axum::http::StatusCode;
+use server::db;
+use server::handlers::hls::{
+    master_playlist, media_playlist, output_size, parse_segment_name, remove_stale_transcodes,
+};
+use server::handlers::hls_handler;
+use server::models::NewMediaEntry;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn playlists_cover_the_whole_duration() {
+    let master = master_playlist(Some(1920), Some(1080));
+    assert!(master.starts_with("#EXTM3U\n"));
+    assert!(master.contains("RESOLUTION=1920x1080"));
+    assert!(master.ends_with("index.m3u8\n"));
+    assert!(!master_playlist(None, None).contains("RESOLUTION"));
+    // Sources above 1080p are scaled down to stay within the advertised level
+    assert!(master.contains("CODECS=\"avc1.64002a,mp4a.40.2\""));
+    assert_eq!(output_size(1280, 720), (1280, 720));
+    assert_eq!(output_size(3840, 2160), (1920, 1080));
+    assert_eq!(output_size(2160, 3840), (1080, 1920));
+    assert_eq!(output_size(4096, 3072), (1440, 1080));
+    assert!(master_playlist(Some(3840), Some(2160)).contains("RESOLUTION=1920x1080"));
+
+    let index = media_playlist(13.5, 6);
+    let segs: Vec<&str> = index.lines().filter(|l| l.ends_with(".ts")).collect();
+    assert_eq!(segs, vec!["seg0.ts", "seg1.ts", "seg2.ts"]);
+    assert!(index.contains("#EXTINF:6.000,\nseg1.ts"));
+    assert!(index.contains("#EXTINF:1.500,\nseg2.ts"));
+    assert!(index.contains("#EXT-X-TARGETDURATION:6\n"));
+    assert!(index.ends_with("#EXT-X-ENDLIST\n"));
+
+    assert_eq!(media_playlist(12.0, 6).matches(".ts").count(), 2);
+
+    assert_eq!(parse_segment_name("seg0.ts"), Some(0));
+    assert_eq!(parse_segment_name("seg42.ts"), Some(42));
+    assert_eq!(parse_segment_name("seg.ts"), None);
+    assert_eq!(parse_segment_name("seg-1.ts"), None);
+    assert_eq!(parse_segment_name("seg1.ts.tmp"), None);
+    assert_eq!(parse_segment_name("../seg1.ts"), None);
+}
+
+#[tokio::test]
+async fn hls_rejects_unplayable_requests() {
+    let pool = common::memory_pool().await;
+    let add = |path: &str, mime: &str| NewMediaEntry {
+        name: path.to_string(),
+        path: path.to_string(),
+        parent_id: None,
+        mime_type: Some(mime.to_string()),
+        size: Some(1),
+        tags: None,
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: None,
+        inode: None,
+    };
+    let video = db::upsert_media(pool.clone(), &add("movie.mkv", "video/x-matroska"))
+        .await
+        .expect("upsert");
+    let image = db::upsert_media(pool.clone(), &add("photo.jpg", "image/jpeg"))
+        .await
+        .expect("upsert");
+
+    let state = Arc::new(TokioMutex::new(common::app_state(
+        pool,
+        std::path::Path::new("."),
+    )));
+    let get = |id: i64, file: &str| {
+        hls_handler(
+            State(state.clone()),
+            Extension(common::user()),
+            Path((id, file.to_string())),
+        )
+    };
+
+    let err = get(video + image + 1, "master.m3u8")
+        .await
+        .expect_err("missing");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    let err = get(image, "master.m3u8").await.expect_err("not a video");
+    assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    let err = get(video, "seg0.ts").await.expect_err("ffmpeg disabled");
+    assert_eq!(err.0, StatusCode::SERVICE_UNAVAILABLE);
+
+    state.lock().await.ffmpeg_enabled = true;
+    state.lock().await.transcode_dir = Some("tests/tmp/transcode".to_string());
+    let res = get(video, "master.m3u8").await.expect("master playlist");
+    assert_eq!(
+        res.headers()["content-type"],
+        "application/vnd.apple.mpegurl"
+    );
+    // The source file does not exist on disk
+    let err = get(video, "seg0.ts").await.expect_err("missing source");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn hls_rejects_segments_past_the_end() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"not really a video").unwrap();
+
+    // The scanner stores whole seconds, ffprobe reports the exact length
+    let ffprobe = base.join("ffprobe");
+    std::fs::write(&ffprobe, "#!/bin/sh\necho 12.4\n").unwrap();
+    std::fs::set_permissions(&ffprobe, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let video = db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: "clip.mp4".to_string(),
+            path: "clip.mp4".to_string(),
+            parent_id: None,
+            mime_type: Some("video/mp4".to_string()),
+            size: Some(18),
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: Some(12),
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert");
+
+    let mut state = common::app_state(pool, &media_dir);
+    state.ffmpeg_enabled = true;
+    // Any attempt to transcode fails loudly instead of returning 404
+    state.ffmpeg_path = Some("/nonexistent/ffmpeg".to_string());
+    state.ffprobe_path = Some(ffprobe.to_string_lossy().to_string());
+    state.transcode_dir = Some(base.join("transcode").to_string_lossy().to_string());
+    let state = Arc::new(TokioMutex::new(state));
+    let get = |file: &str| {
+        hls_handler(
+            State(state.clone()),
+            Extension(common::user()),
+            Path((video, file.to_string())),
+        )
+    };
+
+    // 12.4 seconds in 6 second segments: seg0..seg2, the last one 0.4s long
+    for file in ["seg3.ts", "seg1000.ts", "seg4294967295.ts", "seg01.ts"] {
+        let err = get(file).await.expect_err(file);
+        assert_eq!(err.0, StatusCode::NOT_FOUND, "{}", file);
+    }
+    let err = get("seg2.ts").await.expect_err("no ffmpeg");
+    assert_eq!(err.0, StatusCode::INTERNAL_SERVER_ERROR);
+    let mut res = get("index.m3u8").await.expect("media playlist");
+    let mut body = String::new();
+    while let Some(chunk) = res.body_mut().data().await {
+        body.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+    }
+    assert!(body.contains("#EXTINF:0.400,\nseg2.ts"), "{}", body);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn stale_transcode_caches_are_removed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let transcode_dir = base.join("transcode");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"first cut").unwrap();
+    let ffprobe = base.join("ffprobe");
+    std::fs::write(&ffprobe, "#!/bin/sh\necho 10\n").unwrap();
+    std::fs::set_permissions(&ffprobe, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let video = common::add(&pool, "clip.mp4", None, Some("video/mp4")).await;
+    let mut state = common::app_state(pool.clone(), &media_dir);
+    state.ffmpeg_enabled = true;
+    state.ffprobe_path = Some(ffprobe.to_string_lossy().to_string());
+    state.transcode_dir = Some(transcode_dir.to_string_lossy().to_string());
+    let state = Arc::new(TokioMutex::new(state));
+    let index = || {
+        hls_handler(
+            State(state.clone()),
+            Extension(common::user()),
+            Path((video, "index.m3u8".to_string())),
+        )
+    };
+    let cache_dirs = || {
+        let mut names: Vec<String> = std::fs::read_dir(&transcode_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+
+    // Editing the source starts a new cache directory and drops the old one
+    index().await.expect("index");
+    let first = cache_dirs();
+    assert_eq!(first.len(), 1);
+    std::fs::write(media_dir.join("clip.mp4"), b"second cut, longer").unwrap();
+    index().await.expect("index");
+    let second = cache_dirs();
+    assert_eq!(second.len(), 1);
+    assert_ne!(first, second);
+
+    // On startup, caches of deleted entries and leftovers are swept too
+    let live = transcode_dir.join(&second[0]);
+    std::fs::create_dir_all(transcode_dir.join("999_0123456789abcdef")).unwrap();
+    std::fs::create_dir_all(transcode_dir.join(format!("{}_0123456789abcdef", video))).unwrap();
+    let tmp = live.join("seg0.1700000000.tmp");
+    std::fs::write(&tmp, b"partial").unwrap();
+    let f = std::fs::File::open(&tmp).unwrap();
+    f.set_times(
+        std::fs::FileTimes::new()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200)),
+    )
+    .unwrap();
+    assert_eq!(
+        remove_stale_transcodes(&pool, &transcode_dir, &media_dir).await,
+        2
+    );
+    assert_eq!(cache_dirs(), second);
+    assert!(live.join("index.m3u8").exists());
+    assert!(!tmp.exists());
+}
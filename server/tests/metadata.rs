@@ -0,0 +1,154 @@
+mod common;
+
+use exif::experimental::Writer;
+use exif::{Field, In, Rational, Tag, Value};
+use server::db;
+use server::scanner::scan_directory_and_index;
+use std::io::Cursor;
+use std::path::Path;
+
+fn ascii(tag: Tag, s: &str) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![s.as_bytes().to_vec()]),
+    }
+}
+
+fn rationals(tag: Tag, v: &[(u32, u32)]) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Rational(
+            v.iter()
+                .map(|&(num, denom)| Rational { num, denom })
+                .collect(),
+        ),
+    }
+}
+
+fn short(tag: Tag, v: u16) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Short(vec![v]),
+    }
+}
+
+// Write a small JPEG and splice an APP1 EXIF segment in after SOI
+fn write_jpeg_with_exif(path: &Path, fields: &[Field]) {
+    let img = image::RgbImage::from_pixel(8, 4, image::Rgb([200, 100, 50]));
+    let mut jpeg = Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgb8(img)
+        .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(80))
+        .unwrap();
+    let jpeg = jpeg.into_inner();
+
+    let mut writer = Writer::new();
+    for f in fields {
+        writer.push_field(f);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).unwrap();
+    let tiff = tiff.into_inner();
+
+    let mut out = jpeg[..2].to_vec();
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(&jpeg[2..]);
+    std::fs::write(path, out).unwrap();
+}
+
+#[tokio::test]
+async fn scanner_stores_exif_metadata() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+
+    write_jpeg_with_exif(
+        &media_dir.join("photo.jpg"),
+        &[
+            ascii(Tag::Make, "Canon"),
+            ascii(Tag::Model, "EOS R6"),
+            ascii(Tag::LensModel, "RF24-105mm F4 L IS USM"),
+            ascii(Tag::DateTimeOriginal, "2023:05:01 12:34:56"),
+            ascii(Tag::OffsetTimeOriginal, "+02:00"),
+            rationals(Tag::ExposureTime, &[(1, 250)]),
+            rationals(Tag::FNumber, &[(56, 10)]),
+            short(Tag::PhotographicSensitivity, 400),
+            short(Tag::Orientation, 6),
+            short(Tag::PixelXDimension, 8),
+            short(Tag::PixelYDimension, 4),
+            ascii(Tag::GPSLatitudeRef, "S"),
+            rationals(Tag::GPSLatitude, &[(33, 1), (51, 1), (36, 1)]),
+            ascii(Tag::GPSLongitudeRef, "E"),
+            rationals(Tag::GPSLongitude, &[(151, 1), (12, 1), (0, 1)]),
+        ],
+    );
+    std::fs::write(media_dir.join("notes.txt"), b"no exif here").unwrap();
+
+    let pool = common::memory_pool().await;
+
+    scan_directory_and_index(
+        pool.clone(),
+        media_dir.to_string_lossy().to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("scan");
+
+    let photo = db::get_media_by_path(pool.clone(), "photo.jpg".to_string())
+        .await
+        .expect("db get")
+        .expect("photo indexed");
+    // Orientation 6 is a quarter turn, so the displayed size is 4x8
+    assert_eq!((photo.width, photo.height), (Some(4), Some(8)));
+
+    let meta = db::get_media_metadata(&pool, photo.id)
+        .await
+        .expect("db get")
+        .expect("metadata stored");
+    assert_eq!(meta.taken_at.as_deref(), Some("2023-05-01T12:34:56+02:00"));
+    assert_eq!(meta.camera_make.as_deref(), Some("Canon"));
+    assert_eq!(meta.camera_model.as_deref(), Some("EOS R6"));
+    assert_eq!(meta.lens.as_deref(), Some("RF24-105mm F4 L IS USM"));
+    assert_eq!(meta.exposure_time.as_deref(), Some("1/250"));
+    assert_eq!(meta.f_number, Some(5.6));
+    assert_eq!(meta.iso, Some(400));
+    assert_eq!(meta.orientation, Some(6));
+    let lat = meta.gps_latitude.expect("latitude");
+    let lon = meta.gps_longitude.expect("longitude");
+    assert!((lat + 33.86).abs() < 1e-6, "latitude {}", lat);
+    assert!((lon - 151.2).abs() < 1e-6, "longitude {}", lon);
+
+    let notes = db::get_media_by_path(pool.clone(), "notes.txt".to_string())
+        .await
+        .expect("db get")
+        .expect("notes indexed");
+    assert!(db::get_media_metadata(&pool, notes.id)
+        .await
+        .expect("db get")
+        .is_none());
+
+    // A rewritten photo without EXIF drops the stale metadata
+    image::RgbImage::from_pixel(4, 4, image::Rgb([0, 0, 0]))
+        .save(media_dir.join("photo.jpg"))
+        .unwrap();
+    scan_directory_and_index(
+        pool.clone(),
+        media_dir.to_string_lossy().to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("rescan");
+    assert!(db::get_media_metadata(&pool, photo.id)
+        .await
+        .expect("db get")
+        .is_none());
+}
@@ -0,0 +1,92 @@
+use server::db;
+use server::models::NewMediaEntry;
+use sqlx::sqlite::SqlitePoolOptions;
+
+#[tokio::test]
+async fn legacy_database_is_upgraded() {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect("sqlite::memory:")
+        .await
+        .expect("create in-memory db");
+
+    // Schema as written by builds that predate thumbnails and fingerprints
+    sqlx::query(
+        r#"
+        CREATE TABLE media (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            path TEXT NOT NULL UNIQUE,
+            parent_id INTEGER,
+            mime_type TEXT,
+            size INTEGER,
+            tags TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (parent_id) REFERENCES media (id)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await
+    .expect("create legacy table");
+    sqlx::query("INSERT INTO media (name, path, mime_type, size) VALUES ('old', 'old.jpg', 'image/jpeg', 3)")
+        .execute(&pool)
+        .await
+        .expect("insert legacy row");
+
+    db::initialize_database(pool.clone())
+        .await
+        .expect("migrate legacy db");
+    assert_eq!(
+        db::schema_version(&pool).await.expect("read version"),
+        db::SCHEMA_VERSION
+    );
+
+    // Existing rows survive and the new columns are usable
+    let old = db::get_media_by_path(pool.clone(), "old.jpg".to_string())
+        .await
+        .expect("db get")
+        .expect("legacy row kept");
+    assert_eq!(old.size, Some(3));
+    assert!(old.thumb_path.is_none());
+
+    let ne = NewMediaEntry {
+        name: "new".to_string(),
+        path: "new.jpg".to_string(),
+        parent_id: None,
+        mime_type: Some("image/jpeg".to_string()),
+        size: Some(1),
+        tags: None,
+        thumb_path: Some("/thumbnails/x.jpg".to_string()),
+        width: Some(10),
+        height: Some(20),
+        duration_secs: None,
+        mtime_ns: Some(1),
+        inode: Some(2),
+    };
+    db::upsert_media(pool.clone(), &ne)
+        .await
+        .expect("upsert with new columns");
+
+    // Running again is a no-op
+    db::initialize_database(pool.clone())
+        .await
+        .expect("re-run migrations");
+}
+
+#[tokio::test]
+async fn newer_database_is_refused() {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect("sqlite::memory:")
+        .await
+        .expect("create in-memory db");
+
+    sqlx::query(&format!("PRAGMA user_version = {}", db::SCHEMA_VERSION + 1))
+        .execute(&pool)
+        .await
+        .expect("set version");
+
+    let res = db::initialize_database(pool.clone()).await;
+    assert!(res.is_err(), "expected newer schema to be refused");
+}
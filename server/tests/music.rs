@@ -0,0 +1,375 @@
+mod common;
+
+use axum::extract::{Extension, Path, Query, State};
+use axum::http::{StatusCode, Uri};
+use server::db;
+use server::handlers::{
+    get_album_handler, list_albums_handler, list_artists_handler, list_tags_handler,
+    list_tracks_handler,
+};
+use server::metadata::AudioTags;
+use server::models::{NewMediaEntry, Role, User};
+use server::scanner::scan_directory_and_index;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+// Write a silent 16-bit mono WAV of `secs` seconds with a RIFF INFO list
+fn write_wav(path: &std::path::Path, secs: u32, info: &[(&[u8; 4], &str)]) {
+    let rate: u32 = 8000;
+    let data_len = rate * 2 * secs;
+
+    let mut list = b"INFO".to_vec();
+    for (id, value) in info {
+        let mut v = value.as_bytes().to_vec();
+        v.push(0);
+        list.extend_from_slice(*id);
+        list.extend_from_slice(&(v.len() as u32).to_le_bytes());
+        if v.len() % 2 == 1 {
+            v.push(0);
+        }
+        list.extend_from_slice(&v);
+    }
+
+    let mut fmt = Vec::new();
+    fmt.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    fmt.extend_from_slice(&1u16.to_le_bytes()); // channels
+    fmt.extend_from_slice(&rate.to_le_bytes());
+    fmt.extend_from_slice(&(rate * 2).to_le_bytes());
+    fmt.extend_from_slice(&2u16.to_le_bytes());
+    fmt.extend_from_slice(&16u16.to_le_bytes());
+
+    let mut body = b"WAVE".to_vec();
+    for (id, chunk) in [(b"fmt ", &fmt), (b"LIST", &list)] {
+        body.extend_from_slice(id);
+        body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        body.extend_from_slice(chunk);
+    }
+    body.extend_from_slice(b"data");
+    body.extend_from_slice(&data_len.to_le_bytes());
+    body.resize(body.len() + data_len as usize, 0);
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    std::fs::write(path, out).unwrap();
+}
+
+#[tokio::test]
+async fn scanner_builds_music_library_from_tags() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+
+    write_wav(
+        &media_dir.join("b.wav"),
+        3,
+        &[
+            (b"INAM", "Buddy Holly"),
+            (b"IART", "Weezer"),
+            (b"IPRD", "Blue Album"),
+            (b"IPRT", "4/10"),
+            (b"ICRD", "1994-05-10"),
+            (b"IGNR", "Rock"),
+        ],
+    );
+    write_wav(
+        &media_dir.join("a.wav"),
+        2,
+        &[
+            (b"INAM", "My Name Is Jonas"),
+            (b"IART", "Weezer"),
+            (b"IPRD", "Blue Album"),
+            (b"IPRT", "1"),
+        ],
+    );
+    write_wav(&media_dir.join("loose.wav"), 1, &[(b"IART", "Nobody")]);
+
+    let pool = common::memory_pool().await;
+    let root = media_dir.to_string_lossy().to_string();
+    scan_directory_and_index(pool.clone(), root.clone(), None, None, None)
+        .await
+        .expect("scan");
+
+    let artists = db::list_artists(&pool, None, None, None)
+        .await
+        .expect("artists");
+    let names: Vec<(&str, i64, i64)> = artists
+        .iter()
+        .map(|a| (a.name.as_str(), a.album_count, a.track_count))
+        .collect();
+    assert_eq!(names, vec![("Nobody", 0, 1), ("Weezer", 1, 2)]);
+
+    let albums = db::list_albums(&pool, None, None, None, None)
+        .await
+        .expect("albums");
+    assert_eq!(albums.len(), 1);
+    let album = &albums[0];
+    assert_eq!(album.title, "Blue Album");
+    assert_eq!(album.artist.as_deref(), Some("Weezer"));
+    assert_eq!(album.year, Some(1994));
+    assert_eq!(album.track_count, 2);
+
+    let tracks = db::list_tracks(&pool, None, Some(album.id), None, None, None, None)
+        .await
+        .expect("tracks");
+    let titles: Vec<&str> = tracks.iter().map(|t| t.title.as_str()).collect();
+    assert_eq!(titles, vec!["My Name Is Jonas", "Buddy Holly"]);
+    assert_eq!(tracks[1].track_number, Some(4));
+    assert_eq!(tracks[1].genre.as_deref(), Some("Rock"));
+    assert_eq!(tracks[1].duration_secs, Some(3.0));
+
+    let rock = db::list_tracks(&pool, None, None, Some("rock"), None, None, None)
+        .await
+        .expect("tracks");
+    assert_eq!(rock.len(), 1);
+
+    // Audio duration lands on the media row too
+    let loose = db::get_media_by_path(pool.clone(), "loose.wav".to_string())
+        .await
+        .expect("db get")
+        .expect("indexed");
+    assert_eq!(loose.duration_secs, Some(1));
+
+    // Untitled tracks fall back to the file name
+    let by_nobody = db::list_tracks(&pool, Some(artists[0].id), None, None, None, None, None)
+        .await
+        .expect("tracks");
+    assert_eq!(by_nobody.len(), 1);
+    assert_eq!(by_nobody[0].title, "loose.wav");
+
+    // Removing files prunes albums and artists left without tracks
+    std::fs::remove_file(media_dir.join("a.wav")).unwrap();
+    std::fs::remove_file(media_dir.join("b.wav")).unwrap();
+    scan_directory_and_index(pool.clone(), root.clone(), None, None, None)
+        .await
+        .expect("rescan");
+    assert!(db::list_albums(&pool, None, None, None, None)
+        .await
+        .expect("albums")
+        .is_empty());
+    let artists = db::list_artists(&pool, None, None, None)
+        .await
+        .expect("artists");
+    assert_eq!(artists.len(), 1);
+    assert_eq!(artists[0].name, "Nobody");
+}
+
+#[tokio::test]
+async fn album_artist_groups_compilations() {
+    let pool = common::memory_pool().await;
+
+    let mut ids = Vec::new();
+    for (i, artist) in ["Artist A", "Artist B"].iter().enumerate() {
+        let path = format!("mix/{}.mp3", i);
+        let id = db::upsert_media(
+            pool.clone(),
+            &NewMediaEntry {
+                name: path.clone(),
+                path,
+                parent_id: None,
+                mime_type: Some("audio/mpeg".to_string()),
+                size: Some(1),
+                tags: None,
+                thumb_path: None,
+                width: None,
+                height: None,
+                duration_secs: None,
+                mtime_ns: None,
+                inode: None,
+            },
+        )
+        .await
+        .expect("upsert");
+        let tags = AudioTags {
+            title: Some(format!("Song {}", i + 1)),
+            artist: Some(artist.to_string()),
+            album: Some("Summer Mix".to_string()),
+            album_artist: Some("Various Artists".to_string()),
+            track_number: Some(i as i64 + 1),
+            ..Default::default()
+        };
+        let mut tx = pool.begin().await.expect("begin");
+        db::set_audio_track_in_tx(&mut tx, id, Some(&tags))
+            .await
+            .expect("set track");
+        tx.commit().await.expect("commit");
+        ids.push(id);
+    }
+
+    let albums = db::list_albums(&pool, None, None, None, None)
+        .await
+        .expect("albums");
+    assert_eq!(albums.len(), 1);
+    assert_eq!(albums[0].artist.as_deref(), Some("Various Artists"));
+    assert_eq!(albums[0].track_count, 2);
+
+    // Filtering by artist matches album artists as well as track artists
+    let tracks = db::list_tracks(&pool, albums[0].artist_id, None, None, None, None, None)
+        .await
+        .expect("tracks");
+    assert_eq!(tracks.len(), 2);
+
+    // Retagging moves the tracks and drops the emptied album and artists
+    let mut tx = pool.begin().await.expect("begin");
+    for id in &ids {
+        let tags = AudioTags {
+            artist: Some("Artist A".to_string()),
+            album: Some("Solo".to_string()),
+            ..Default::default()
+        };
+        db::set_audio_track_in_tx(&mut tx, *id, Some(&tags))
+            .await
+            .expect("set track");
+    }
+    tx.commit().await.expect("commit");
+    let albums = db::list_albums(&pool, None, None, None, None)
+        .await
+        .expect("albums");
+    let titles: Vec<&str> = albums.iter().map(|a| a.title.as_str()).collect();
+    assert_eq!(titles, vec!["Solo"]);
+    let artists = db::list_artists(&pool, None, None, None)
+        .await
+        .expect("artists");
+    assert_eq!(artists.len(), 1);
+    assert_eq!(artists[0].name, "Artist A");
+}
+
+#[tokio::test]
+async fn hidden_directories_are_left_out_of_the_library() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(media_dir.join("public"));
+    let _ = std::fs::create_dir_all(media_dir.join("private"));
+    let blue = [(b"IART", "Weezer"), (b"IPRD", "Blue Album")];
+    write_wav(&media_dir.join("public/a.wav"), 1, &blue);
+    write_wav(&media_dir.join("private/b.wav"), 1, &blue);
+    write_wav(
+        &media_dir.join("private/c.wav"),
+        1,
+        &[(b"IART", "Secret Artist"), (b"IPRD", "Secret Album")],
+    );
+
+    let pool = common::memory_pool().await;
+    scan_directory_and_index(
+        pool.clone(),
+        media_dir.to_string_lossy().to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("scan");
+    let id_of = |path: &str| {
+        let pool = pool.clone();
+        let path = path.to_string();
+        async move {
+            db::get_media_by_path(pool, path)
+                .await
+                .expect("db get")
+                .expect("indexed")
+                .id
+        }
+    };
+    let private = id_of("private").await;
+    db::add_media_tags(&pool, id_of("public/a.wav").await, &["shared".to_string()])
+        .await
+        .unwrap();
+    db::add_media_tags(&pool, id_of("private/c.wav").await, &["secret".to_string()])
+        .await
+        .unwrap();
+    let owner = db::create_user(&pool, "owner", "unused", Role::Viewer)
+        .await
+        .unwrap();
+    db::set_acl(&pool, private, &[owner]).await.unwrap();
+    let viewer = User {
+        id: owner + 1,
+        ..common::user()
+    };
+
+    let state = Arc::new(TokioMutex::new(common::app_state(pool.clone(), &media_dir)));
+    let admin = common::admin().0;
+    let query = |uri: &str| Query::try_from_uri(&uri.parse::<Uri>().unwrap()).unwrap();
+
+    // Hidden tracks do not count towards artists, albums or tags
+    let artists = list_artists_handler(
+        State(state.clone()),
+        Extension(viewer.clone()),
+        query("/music/artists"),
+    )
+    .await
+    .expect("artists");
+    let artists = artists.0["artists"].as_array().unwrap().clone();
+    assert_eq!(artists.len(), 1);
+    assert_eq!(artists[0]["name"], "Weezer");
+    assert_eq!(artists[0]["album_count"], 1);
+    assert_eq!(artists[0]["track_count"], 1);
+    let albums = list_albums_handler(
+        State(state.clone()),
+        Extension(viewer.clone()),
+        query("/music/albums"),
+    )
+    .await
+    .expect("albums");
+    let albums = albums.0["albums"].as_array().unwrap().clone();
+    assert_eq!(albums.len(), 1);
+    assert_eq!(albums[0]["title"], "Blue Album");
+    assert_eq!(albums[0]["track_count"], 1);
+    let tracks = list_tracks_handler(
+        State(state.clone()),
+        Extension(viewer.clone()),
+        query("/music/tracks"),
+    )
+    .await
+    .expect("tracks");
+    assert_eq!(tracks.0["tracks"].as_array().unwrap().len(), 1);
+    assert_eq!(tracks.0["tracks"][0]["path"], "public/a.wav");
+    let tags = list_tags_handler(State(state.clone()), Extension(viewer.clone()))
+        .await
+        .expect("tags");
+    assert_eq!(
+        tags.0["tags"],
+        serde_json::json!([{ "name": "shared", "count": 1 }])
+    );
+
+    // Albums made up of hidden tracks are not found at all
+    let all = list_albums_handler(
+        State(state.clone()),
+        Extension(admin.clone()),
+        query("/music/albums"),
+    )
+    .await
+    .expect("albums");
+    let all = all.0["albums"].as_array().unwrap().clone();
+    assert_eq!(all.len(), 2);
+    let secret_id = all.iter().find(|a| a["title"] == "Secret Album").unwrap()["id"]
+        .as_i64()
+        .unwrap();
+    let blue_id = albums[0]["id"].as_i64().unwrap();
+    let err = get_album_handler(
+        State(state.clone()),
+        Extension(viewer.clone()),
+        Path(secret_id),
+    )
+    .await
+    .expect_err("hidden album");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+    let album = get_album_handler(State(state.clone()), Extension(viewer), Path(blue_id))
+        .await
+        .expect("album");
+    assert_eq!(album.0["tracks"].as_array().unwrap().len(), 1);
+
+    // Admins see the whole library
+    let album = get_album_handler(
+        State(state.clone()),
+        Extension(admin.clone()),
+        Path(blue_id),
+    )
+    .await
+    .expect("album");
+    assert_eq!(album.0["track_count"], 2);
+    assert_eq!(album.0["tracks"].as_array().unwrap().len(), 2);
+    let tags = list_tags_handler(State(state.clone()), Extension(admin))
+        .await
+        .expect("tags");
+    assert_eq!(tags.0["tags"].as_array().unwrap().len(), 2);
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use axum::body::Body;
+use axum::extract::{Extension, Query, State};
+use axum::http::{Request, StatusCode};
+use server::db;
+use server::handlers::preview::{preview_clips, preview_ffmpeg_args, PreviewFormat, PreviewQuery};
+use server::handlers::preview_handler;
+use server::models::NewMediaEntry;
+use server::state::AppState;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn clips_are_spread_across_the_video() {
+    assert_eq!(
+        preview_clips(60.0),
+        vec![
+            (9.5, 1.0),
+            (19.5, 1.0),
+            (29.5, 1.0),
+            (39.5, 1.0),
+            (49.5, 1.0)
+        ]
+    );
+    // Too short to split: one clip from the start
+    assert_eq!(preview_clips(8.0), vec![(0.0, 5.0)]);
+    assert_eq!(preview_clips(0.4), vec![(0.0, 1.0)]);
+
+    let args = preview_ffmpeg_args(
+        Path::new("/m/a.mkv"),
+        &[(9.5, 1.0), (19.5, 1.0)],
+        PreviewFormat::WebP,
+        Path::new("/t/out.webp"),
+    );
+    let joined = args.join(" ");
+    assert!(joined
+        .starts_with("-v error -ss 9.500 -t 1.000 -i /m/a.mkv -ss 19.500 -t 1.000 -i /m/a.mkv "));
+    assert!(joined.contains("[v0][v1]concat=n=2:v=1:a=0[out]"));
+    assert!(joined.contains(" -an -c:v libwebp -loop 0 "));
+    assert!(joined.ends_with("-y /t/out.webp"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn previews_are_generated_once_and_streamed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"not really a video").unwrap();
+
+    // Stand-in for ffmpeg: logs each run and writes a fixed body
+    let fake = base.join("ffmpeg");
+    std::fs::write(
+        &fake,
+        format!(
+            "#!/bin/sh\nfor a; do out=\"$a\"; done\necho run >> '{}'\nprintf 'preview-bytes' > \"$out\"\n",
+            base.join("runs").display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let id = db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: "clip.mp4".to_string(),
+            path: "clip.mp4".to_string(),
+            parent_id: None,
+            mime_type: Some("video/mp4".to_string()),
+            size: Some(18),
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: Some(120),
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert media");
+    let state = Arc::new(TokioMutex::new(AppState {
+        ffmpeg_enabled: true,
+        ffmpeg_path: Some(fake.to_string_lossy().to_string()),
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let query = |format: Option<&str>| {
+        Query(PreviewQuery {
+            id: Some(id),
+            path: None,
+            format: format.map(str::to_string),
+        })
+    };
+
+    let res = preview_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(None),
+        Request::new(Body::empty()),
+    )
+    .await
+    .expect("preview");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "video/mp4");
+    assert_eq!(res.headers()["content-length"], "13");
+
+    // Cached: seeking into it does not run ffmpeg again
+    let req = Request::builder()
+        .header("range", "bytes=0-6")
+        .body(Body::empty())
+        .unwrap();
+    let res = preview_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(Some("mp4")),
+        req,
+    )
+    .await
+    .expect("preview");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()["content-range"], "bytes 0-6/13");
+    let runs = std::fs::read_to_string(base.join("runs")).unwrap();
+    assert_eq!(runs.lines().count(), 1);
+
+    let res = preview_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(Some("webp")),
+        Request::new(Body::empty()),
+    )
+    .await
+    .expect("preview");
+    assert_eq!(res.headers()["content-type"], "image/webp");
+
+    let err = preview_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(Some("gif")),
+        Request::new(Body::empty()),
+    )
+    .await
+    .expect_err("unsupported format");
+    assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+    state.lock().await.ffmpeg_enabled = false;
+    let err = preview_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(None),
+        Request::new(Body::empty()),
+    )
+    .await
+    .expect_err("ffmpeg disabled");
+    assert_eq!(err.0, StatusCode::SERVICE_UNAVAILABLE);
+}
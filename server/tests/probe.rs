@@ -0,0 +1,129 @@
+mod common;
+
+use axum::extract::{Extension, Path, Query, State};
+use server::handlers::core::DetailsQuery;
+use server::handlers::{get_file_details_handler, get_scan_job_handler, trigger_scan_handler};
+use server::jobs::JobState;
+use server::models::{AudioStream, SubtitleStream};
+use server::probe::parse_ffprobe_json;
+use server::scanner::ScanSummary;
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+const SAMPLE: &str = r#"{
+  "streams": [
+    {"codec_type": "video", "codec_name": "hevc", "width": 3840, "height": 2160,
+     "avg_frame_rate": "24000/1001", "r_frame_rate": "24000/1001", "disposition": {"attached_pic": 0}},
+    {"codec_type": "audio", "codec_name": "eac3", "channels": 6, "tags": {"language": "eng"}},
+    {"codec_type": "audio", "codec_name": "aac", "channels": 2, "tags": {"language": "und"}},
+    {"codec_type": "subtitle", "codec_name": "subrip", "tags": {"language": "fre"}},
+    {"codec_type": "video", "codec_name": "mjpeg", "width": 600, "height": 600,
+     "disposition": {"attached_pic": 1}}
+  ],
+  "format": {"format_name": "matroska,webm", "duration": "5400.250000", "bit_rate": "18000000"}
+}"#;
+
+#[test]
+fn ffprobe_output_is_parsed() {
+    let info = parse_ffprobe_json(SAMPLE).expect("parse");
+    assert_eq!(info.container.as_deref(), Some("matroska,webm"));
+    assert_eq!(info.video_codec.as_deref(), Some("hevc"));
+    assert_eq!((info.width, info.height), (Some(3840), Some(2160)));
+    assert_eq!(info.frame_rate, Some(23.976));
+    assert_eq!(info.bitrate, Some(18_000_000));
+    assert_eq!(info.duration_secs, Some(5400.25));
+    assert_eq!(
+        info.audio_tracks,
+        vec![
+            AudioStream {
+                codec: Some("eac3".to_string()),
+                channels: Some(6),
+                language: Some("eng".to_string()),
+            },
+            AudioStream {
+                codec: Some("aac".to_string()),
+                channels: Some(2),
+                language: None,
+            },
+        ]
+    );
+    assert_eq!(
+        info.subtitles,
+        vec![SubtitleStream {
+            codec: Some("subrip".to_string()),
+            language: Some("fre".to_string()),
+        }]
+    );
+
+    assert!(parse_ffprobe_json("not json").is_none());
+}
+
+async fn run_scan(state: &Arc<TokioMutex<AppState>>) -> ScanSummary {
+    let (_, started) = trigger_scan_handler(State(state.clone()), common::admin()).await;
+    for _ in 0..100 {
+        let s = get_scan_job_handler(State(state.clone()), common::admin(), Path(started.0.id))
+            .await
+            .expect("job exists")
+            .0;
+        if s.state != JobState::Running {
+            assert_eq!(s.state, JobState::Completed, "scan failed: {:?}", s.error);
+            return s.summary.expect("summary");
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("scan job did not finish");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn scan_probes_new_and_changed_videos() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("movie.mkv"), b"not really a movie").unwrap();
+    std::fs::write(media_dir.join("notes.txt"), b"text").unwrap();
+
+    // Stand-in for ffprobe that prints canned output
+    let fake = base.join("ffprobe");
+    std::fs::write(base.join("out.json"), SAMPLE).unwrap();
+    std::fs::write(
+        &fake,
+        format!("#!/bin/sh\ncat '{}'\n", base.join("out.json").display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let state = Arc::new(TokioMutex::new(AppState {
+        ffmpeg_enabled: true,
+        ffprobe_path: Some(fake.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+
+    assert_eq!(run_scan(&state).await.probed, 1);
+
+    let details = get_file_details_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        Query(DetailsQuery {
+            path: Some("movie.mkv".to_string()),
+        }),
+    )
+    .await
+    .expect("details")
+    .0;
+    assert_eq!(details["probe"]["video_codec"], "hevc");
+    assert_eq!(details["probe"]["audio_tracks"][0]["language"], "eng");
+    assert_eq!(details["probe"]["subtitles"][0]["codec"], "subrip");
+    // Probing fills in what the row did not know yet
+    assert_eq!(details["width"], 3840);
+    assert_eq!(details["duration_secs"], 5400);
+
+    // Unchanged files are not probed again; changed ones are
+    assert_eq!(run_scan(&state).await.probed, 0);
+    std::fs::write(media_dir.join("movie.mkv"), b"a longer fake movie file").unwrap();
+    assert_eq!(run_scan(&state).await.probed, 1);
+}
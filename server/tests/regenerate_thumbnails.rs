@@ -1,27 +1,22 @@
+mod common;
+
 use axum::extract::State;
 use image::{ImageBuffer, Rgb};
 use server::config::AppConfig;
 use server::db;
 use server::handlers::admin::regenerate_thumbnails_handler;
+use server::handlers::thumbnails::{source_fingerprint, thumbnail_name};
 use server::models::NewMediaEntry;
 use server::state::AppState;
-use sqlx::sqlite::SqlitePoolOptions;
 use std::collections::HashMap as StdHashMap;
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::{Mutex as TokioMutex, Semaphore};
+use tokio::sync::Mutex as TokioMutex;
 
 #[tokio::test]
 async fn concurrent_regenerate() {
     // Setup repo-local temp directories under <crate>/tests/tmp
-    let crate_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let base = crate_root.join("tests").join("tmp").join(format!(
-        "media_server_test_{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-    ));
+    let base = common::TestDir::new();
     let media_dir = base.join("media");
     let thumbs_dir = base.join("thumbnails");
     let _ = std::fs::create_dir_all(&media_dir);
@@ -45,17 +40,18 @@ async fn concurrent_regenerate() {
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
         cors_allowed_origins: None,
         cors_allow_credentials: None,
+        cors_enabled: None,
+        client_dist_dir: None,
+        watch_enabled: None,
+        watch_debounce_ms: None,
+        transcode_dir: None,
+        transcode_concurrency: None,
+        thumbnails_max_mb: None,
+        cookie_secure: None,
     };
 
     // init DB (use in-memory SQLite to avoid file permission issues)
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("sqlite::memory:")
-        .await
-        .expect("create in-memory db");
-    db::initialize_database(pool.clone())
-        .await
-        .expect("init db");
+    let pool = common::memory_pool().await;
 
     // Insert media entry pointing to our test image
     let ne = NewMediaEntry {
@@ -69,6 +65,8 @@ async fn concurrent_regenerate() {
         width: None,
         height: None,
         duration_secs: None,
+        mtime_ns: None,
+        inode: None,
     };
     let id = db::upsert_media(pool.clone(), &ne)
         .await
@@ -76,14 +74,8 @@ async fn concurrent_regenerate() {
 
     // Build AppState
     let state = AppState {
-        pool: pool.clone(),
-        directory_to_scan: cfg.directory_to_scan.clone(),
-        ffmpeg_enabled: false,
-        ffmpeg_path: None,
-        ffprobe_path: None,
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
-        regen_semaphore: Arc::new(Semaphore::new(4)),
-        in_flight: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+        ..common::app_state(pool.clone(), Path::new(&cfg.directory_to_scan))
     };
 
     let state_arc = Arc::new(TokioMutex::new(state));
@@ -98,13 +90,13 @@ async fn concurrent_regenerate() {
     let s1 = state_arc.clone();
     let p1 = params.clone();
     let h1 = tokio::spawn(async move {
-        regenerate_thumbnails_handler(State(s1), axum::extract::Query(p1)).await
+        regenerate_thumbnails_handler(State(s1), common::admin(), axum::extract::Query(p1)).await
     });
 
     let s2 = state_arc.clone();
     let p2 = params.clone();
     let h2 = tokio::spawn(async move {
-        regenerate_thumbnails_handler(State(s2), axum::extract::Query(p2)).await
+        regenerate_thumbnails_handler(State(s2), common::admin(), axum::extract::Query(p2)).await
     });
 
     let r1 = h1.await.unwrap();
@@ -114,29 +106,20 @@ async fn concurrent_regenerate() {
     assert!(r2.is_ok(), "second request failed: {:?}", r2.err());
 
     // ensure thumbnail exists
-    let out_name = format!("{}_{}x{}.jpg", id, 100, 100);
+    let fp = source_fingerprint("test.jpg", &std::fs::metadata(&img_path).unwrap());
+    let out_name = thumbnail_name(id, &fp, 100, 100);
     let out_path = thumbs_dir.join(&out_name);
     assert!(
         out_path.exists(),
         "thumbnail not created: {}",
         out_path.display()
     );
-
-    // cleanup
-    let _ = std::fs::remove_dir_all(&base);
 }
 
 #[tokio::test]
 async fn missing_file_regenerate() {
     // Setup repo-local temp directories under <crate>/tests/tmp
-    let crate_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let base = crate_root.join("tests").join("tmp").join(format!(
-        "media_server_test_{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-    ));
+    let base = common::TestDir::new();
     let media_dir = base.join("media");
     let thumbs_dir = base.join("thumbnails");
     let _ = std::fs::create_dir_all(&media_dir);
@@ -157,17 +140,18 @@ async fn missing_file_regenerate() {
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
         cors_allowed_origins: None,
         cors_allow_credentials: None,
+        cors_enabled: None,
+        client_dist_dir: None,
+        watch_enabled: None,
+        watch_debounce_ms: None,
+        transcode_dir: None,
+        transcode_concurrency: None,
+        thumbnails_max_mb: None,
+        cookie_secure: None,
     };
 
     // init DB (use in-memory SQLite to avoid file permission issues)
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("sqlite::memory:")
-        .await
-        .expect("create in-memory db");
-    db::initialize_database(pool.clone())
-        .await
-        .expect("init db");
+    let pool = common::memory_pool().await;
 
     // Insert media entry pointing to a missing image
     let ne = NewMediaEntry {
@@ -181,6 +165,8 @@ async fn missing_file_regenerate() {
         width: None,
         height: None,
         duration_secs: None,
+        mtime_ns: None,
+        inode: None,
     };
     let _id = db::upsert_media(pool.clone(), &ne)
         .await
@@ -188,14 +174,8 @@ async fn missing_file_regenerate() {
 
     // Build AppState
     let state = AppState {
-        pool: pool.clone(),
-        directory_to_scan: cfg.directory_to_scan.clone(),
-        ffmpeg_enabled: false,
-        ffmpeg_path: None,
-        ffprobe_path: None,
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
-        regen_semaphore: Arc::new(Semaphore::new(4)),
-        in_flight: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+        ..common::app_state(pool.clone(), Path::new(&cfg.directory_to_scan))
     };
 
     let state_arc = Arc::new(TokioMutex::new(state));
@@ -206,31 +186,25 @@ async fn missing_file_regenerate() {
     params.insert("h".to_string(), "100".to_string());
     params.insert("concurrency".to_string(), "2".to_string());
 
-    let res = regenerate_thumbnails_handler(State(state_arc), axum::extract::Query(params))
-        .await
-        .expect("handler failed");
+    let res = regenerate_thumbnails_handler(
+        State(state_arc),
+        common::admin(),
+        axum::extract::Query(params),
+    )
+    .await
+    .expect("handler failed");
 
     // Expect a failure count > 0
     assert!(
         res.0.failed > 0 || res.0.done == 0,
         "expected failures for missing file"
     );
-
-    // cleanup
-    let _ = std::fs::remove_dir_all(&base);
 }
 
 #[tokio::test]
 async fn many_waiters_regenerate() {
     // Setup repo-local temp directories under <crate>/tests/tmp
-    let crate_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let base = crate_root.join("tests").join("tmp").join(format!(
-        "media_server_test_{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-    ));
+    let base = common::TestDir::new();
     let media_dir = base.join("media");
     let thumbs_dir = base.join("thumbnails");
     let _ = std::fs::create_dir_all(&media_dir);
@@ -254,17 +228,18 @@ async fn many_waiters_regenerate() {
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
         cors_allowed_origins: None,
         cors_allow_credentials: None,
+        cors_enabled: None,
+        client_dist_dir: None,
+        watch_enabled: None,
+        watch_debounce_ms: None,
+        transcode_dir: None,
+        transcode_concurrency: None,
+        thumbnails_max_mb: None,
+        cookie_secure: None,
     };
 
     // init DB (use in-memory SQLite to avoid file permission issues)
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("sqlite::memory:")
-        .await
-        .expect("create in-memory db");
-    db::initialize_database(pool.clone())
-        .await
-        .expect("init db");
+    let pool = common::memory_pool().await;
 
     // Insert media entry pointing to our test image
     let ne = NewMediaEntry {
@@ -278,6 +253,8 @@ async fn many_waiters_regenerate() {
         width: None,
         height: None,
         duration_secs: None,
+        mtime_ns: None,
+        inode: None,
     };
     let id = db::upsert_media(pool.clone(), &ne)
         .await
@@ -285,14 +262,8 @@ async fn many_waiters_regenerate() {
 
     // Build AppState
     let state = AppState {
-        pool: pool.clone(),
-        directory_to_scan: cfg.directory_to_scan.clone(),
-        ffmpeg_enabled: false,
-        ffmpeg_path: None,
-        ffprobe_path: None,
         thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
-        regen_semaphore: Arc::new(Semaphore::new(4)),
-        in_flight: Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+        ..common::app_state(pool.clone(), Path::new(&cfg.directory_to_scan))
     };
 
     let state_arc = Arc::new(TokioMutex::new(state));
@@ -309,7 +280,7 @@ async fn many_waiters_regenerate() {
         let s = state_arc.clone();
         let p = params.clone();
         handles.push(tokio::spawn(async move {
-            regenerate_thumbnails_handler(State(s), axum::extract::Query(p)).await
+            regenerate_thumbnails_handler(State(s), common::admin(), axum::extract::Query(p)).await
         }));
     }
 
@@ -324,7 +295,8 @@ async fn many_waiters_regenerate() {
     assert_eq!(success, 8, "all callers should succeed");
 
     // ensure thumbnail exists and DB updated
-    let out_name = format!("{}_{}x{}.jpg", id, 80, 80);
+    let fp = source_fingerprint("test2.jpg", &std::fs::metadata(&img_path).unwrap());
+    let out_name = thumbnail_name(id, &fp, 80, 80);
     let out_path = thumbs_dir.join(&out_name);
     assert!(
         out_path.exists(),
@@ -334,7 +306,4 @@ async fn many_waiters_regenerate() {
 
     let entry = db::get_media_by_id(pool.clone(), id).await.expect("db get");
     assert!(entry.unwrap().thumb_path.is_some(), "DB thumb_path not set");
-
-    // cleanup
-    let _ = std::fs::remove_dir_all(&base);
 }
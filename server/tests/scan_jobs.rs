@@ -0,0 +1,74 @@
+mod common;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use server::handlers::{get_scan_job_handler, trigger_scan_handler};
+use server::jobs::JobState;
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+async fn build_state(media_dir: &std::path::Path) -> Arc<TokioMutex<AppState>> {
+    let pool = common::memory_pool().await;
+
+    let state = common::app_state(pool, media_dir);
+    Arc::new(TokioMutex::new(state))
+}
+
+#[tokio::test]
+async fn scan_runs_in_background_and_reports_progress() {
+    // Setup repo-local temp directories under <crate>/tests/tmp
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(media_dir.join("sub"));
+    std::fs::write(media_dir.join("one.txt"), b"1").unwrap();
+    std::fs::write(media_dir.join("sub").join("two.txt"), b"2").unwrap();
+
+    let state = build_state(&media_dir).await;
+
+    let (code, started) = trigger_scan_handler(State(state.clone()), common::admin()).await;
+    assert_eq!(code, StatusCode::ACCEPTED);
+    assert_eq!(started.0.state, JobState::Running);
+    let id = started.0.id;
+
+    // Poll until the job finishes
+    let mut status = None;
+    for _ in 0..50 {
+        let s = get_scan_job_handler(State(state.clone()), common::admin(), Path(id))
+            .await
+            .expect("job exists")
+            .0;
+        if s.state != JobState::Running {
+            status = Some(s);
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let status = status.expect("scan job did not finish");
+    assert_eq!(status.state, JobState::Completed);
+    assert_eq!(status.files_seen, 2);
+    assert_eq!(status.directories_visited, 2);
+    assert_eq!(status.errors, 0);
+    assert_eq!(status.summary.expect("summary").added, 3);
+
+    let missing = get_scan_job_handler(State(state.clone()), common::admin(), Path(id + 100)).await;
+    assert_eq!(missing.err().map(|e| e.0), Some(StatusCode::NOT_FOUND));
+}
+
+#[tokio::test]
+async fn second_scan_is_coalesced_into_running_job() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+
+    let state = build_state(&media_dir).await;
+
+    // Simulate a scan that is still in progress
+    let jobs = state.lock().await.scan_jobs.clone();
+    let running_id = jobs.lock().await.start().id;
+
+    let (code, status) = trigger_scan_handler(State(state.clone()), common::admin()).await;
+    assert_eq!(code, StatusCode::ACCEPTED);
+    assert_eq!(status.0.id, running_id);
+    assert_eq!(status.0.state, JobState::Running);
+}
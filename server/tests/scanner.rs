@@ -0,0 +1,110 @@
+mod common;
+
+use server::db;
+use server::scanner::scan_directory_and_index;
+
+#[tokio::test]
+async fn rescan_skips_unchanged_files() {
+    // Setup repo-local temp directories under <crate>/tests/tmp
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(media_dir.join("album"));
+    std::fs::write(media_dir.join("a.txt"), b"alpha").unwrap();
+    std::fs::write(media_dir.join("album").join("b.txt"), b"bravo").unwrap();
+
+    let pool = common::memory_pool().await;
+
+    let root = media_dir.to_string_lossy().to_string();
+
+    // First scan indexes everything: two files and one directory
+    let first = scan_directory_and_index(pool.clone(), root.clone(), None, None, None)
+        .await
+        .expect("first scan");
+    assert_eq!(first.added, 3);
+    assert_eq!(first.updated, 0);
+    assert_eq!(first.unchanged, 0);
+
+    // Second scan with no changes on disk touches nothing
+    let second = scan_directory_and_index(pool.clone(), root.clone(), None, None, None)
+        .await
+        .expect("second scan");
+    assert_eq!(second.added, 0);
+    assert_eq!(second.updated, 0);
+    assert_eq!(second.unchanged, 3);
+
+    // Modify one file (size changes) and add another
+    std::fs::write(media_dir.join("a.txt"), b"alpha, but longer").unwrap();
+    std::fs::write(media_dir.join("album").join("c.txt"), b"charlie").unwrap();
+
+    let third = scan_directory_and_index(pool.clone(), root.clone(), None, None, None)
+        .await
+        .expect("third scan");
+    assert_eq!(third.added, 1);
+    assert_eq!(third.updated, 1);
+    assert_eq!(third.unchanged, 2);
+
+    let a = db::get_media_by_path(pool.clone(), "a.txt".to_string())
+        .await
+        .expect("db get")
+        .expect("a.txt indexed");
+    assert_eq!(a.size, Some("alpha, but longer".len() as i64));
+}
+
+#[tokio::test]
+async fn rescan_prunes_deleted_entries() {
+    // Setup repo-local temp directories under <crate>/tests/tmp
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(media_dir.join("gone").join("deeper"));
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    std::fs::write(media_dir.join("keep.txt"), b"keep").unwrap();
+    std::fs::write(media_dir.join("drop.txt"), b"drop").unwrap();
+    std::fs::write(media_dir.join("gone").join("x.txt"), b"x").unwrap();
+    std::fs::write(media_dir.join("gone").join("deeper").join("y.txt"), b"y").unwrap();
+
+    let pool = common::memory_pool().await;
+
+    let root = media_dir.to_string_lossy().to_string();
+    let first = scan_directory_and_index(
+        pool.clone(),
+        root.clone(),
+        None,
+        Some(thumbs_dir.clone()),
+        None,
+    )
+    .await
+    .expect("first scan");
+    assert_eq!(first.added, 6);
+
+    // Pretend thumbnails were generated for the file we are about to delete
+    let dropped = db::get_media_by_path(pool.clone(), "drop.txt".to_string())
+        .await
+        .expect("db get")
+        .expect("drop.txt indexed");
+    let stale_thumb = thumbs_dir.join(format!("{}_100x100.jpg", dropped.id));
+    std::fs::write(&stale_thumb, b"jpeg").unwrap();
+
+    std::fs::remove_file(media_dir.join("drop.txt")).unwrap();
+    std::fs::remove_dir_all(media_dir.join("gone")).unwrap();
+
+    let second = scan_directory_and_index(
+        pool.clone(),
+        root.clone(),
+        None,
+        Some(thumbs_dir.clone()),
+        None,
+    )
+    .await
+    .expect("second scan");
+    // drop.txt, gone/, gone/x.txt, gone/deeper/, gone/deeper/y.txt
+    assert_eq!(second.removed, 5);
+    assert_eq!(second.unchanged, 1);
+
+    let remaining = db::list_children(pool.clone(), None, None)
+        .await
+        .expect("list root");
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].path, "keep.txt");
+    assert!(!stale_thumb.exists(), "stale thumbnail not removed");
+}
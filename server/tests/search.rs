@@ -0,0 +1,142 @@
+mod common;
+
+use server::db;
+use server::models::NewMediaEntry;
+use sqlx::SqlitePool;
+
+fn entry(path: &str, mime: Option<&str>, tags: Option<Vec<&str>>) -> NewMediaEntry {
+    NewMediaEntry {
+        name: path.rsplit('/').next().unwrap().to_string(),
+        path: path.to_string(),
+        parent_id: None,
+        mime_type: mime.map(|m| m.to_string()),
+        size: mime.map(|_| 1),
+        tags: tags.map(|t| t.into_iter().map(|s| s.to_string()).collect()),
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: None,
+        inode: None,
+    }
+}
+
+async fn seeded_pool() -> SqlitePool {
+    let pool = common::memory_pool().await;
+
+    for e in [
+        entry("holidays", None, None),
+        entry(
+            "holidays/beach.jpg",
+            Some("image/jpeg"),
+            Some(vec!["summer"]),
+        ),
+        entry("holidays/beach.mp4", Some("video/mp4"), None),
+        entry(
+            "work/report.pdf",
+            Some("application/pdf"),
+            Some(vec!["holiday-planning"]),
+        ),
+        entry("music/summer_song.mp3", Some("audio/mpeg"), None),
+    ] {
+        db::upsert_media(pool.clone(), &e).await.expect("upsert");
+    }
+    pool
+}
+
+#[tokio::test]
+async fn search_matches_prefixes_across_fields() {
+    let pool = seeded_pool().await;
+
+    // "holi" is a prefix of the directory name, the path of its children and a tag
+    let hits = db::search_media(pool.clone(), "holi", None, None, None, None, None)
+        .await
+        .expect("search");
+    let paths: Vec<&str> = hits.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(hits.len(), 4, "unexpected hits: {:?}", paths);
+    // Name matches rank above path-only matches
+    assert_eq!(paths[0], "holidays");
+
+    // Every word must match
+    let hits = db::search_media(pool.clone(), "beach jpg", None, None, None, None, None)
+        .await
+        .expect("search");
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].path, "holidays/beach.jpg");
+
+    // Tags are searchable
+    let hits = db::search_media(
+        pool.clone(),
+        "summer",
+        None,
+        Some("image"),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("search");
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].path, "holidays/beach.jpg");
+
+    // Nothing searchable in the query
+    let hits = db::search_media(pool.clone(), "\" -", None, None, None, None, None)
+        .await
+        .expect("search");
+    assert!(hits.is_empty());
+}
+
+#[tokio::test]
+async fn search_filters_and_pages() {
+    let pool = seeded_pool().await;
+
+    let dirs = db::search_media(
+        pool.clone(),
+        "holidays",
+        Some("directory"),
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("search");
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].path, "holidays");
+
+    let page1 = db::search_media(
+        pool.clone(),
+        "holi",
+        Some("file"),
+        None,
+        Some(2),
+        Some(0),
+        None,
+    )
+    .await
+    .expect("search");
+    let page2 = db::search_media(
+        pool.clone(),
+        "holi",
+        Some("file"),
+        None,
+        Some(2),
+        Some(2),
+        None,
+    )
+    .await
+    .expect("search");
+    assert_eq!(page1.len(), 2);
+    assert_eq!(page2.len(), 1);
+
+    // Deleting rows removes them from the index too
+    let mut tx = pool.begin().await.expect("begin");
+    db::delete_media_subtree_in_tx(&mut tx, "holidays")
+        .await
+        .expect("delete");
+    tx.commit().await.expect("commit");
+    let hits = db::search_media(pool.clone(), "beach", None, None, None, None, None)
+        .await
+        .expect("search");
+    assert!(hits.is_empty());
+}
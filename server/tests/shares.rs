@@ -0,0 +1,345 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use server::auth::{hash_password, hash_token, now_secs};
+use server::db;
+use server::models::Role;
+use server::startup::build_router;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+fn get_uri(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+fn paths(body: &str) -> Vec<String> {
+    let v: serde_json::Value = serde_json::from_str(body).unwrap();
+    let mut paths: Vec<String> = v["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["path"].as_str().unwrap().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[tokio::test]
+async fn share_links_expose_only_their_subtree() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(media_dir.join("client/renders"));
+    let _ = std::fs::create_dir_all(media_dir.join("private"));
+    std::fs::write(media_dir.join("client/brief.txt"), b"the brief").unwrap();
+    std::fs::write(media_dir.join("client/final..v2.txt"), b"v2").unwrap();
+    std::fs::write(media_dir.join("client/renders/final.mp4"), b"0123456789").unwrap();
+    std::fs::write(media_dir.join("private/notes.txt"), b"secret").unwrap();
+
+    let pool = common::memory_pool().await;
+    let admin_id = db::create_user(&pool, "root", "unused", Role::Admin)
+        .await
+        .expect("create admin");
+    let viewer_id = db::create_user(&pool, "vera", "unused", Role::Viewer)
+        .await
+        .expect("create viewer");
+    for (token, id) in [("admin-session", admin_id), ("viewer-session", viewer_id)] {
+        db::create_session(&pool, &hash_token(token), id, now_secs() + 3600, now_secs())
+            .await
+            .unwrap();
+    }
+    let client = common::add(&pool, "client", None, None).await;
+    common::add(&pool, "client/brief.txt", Some(client), Some("text/plain")).await;
+    common::add(
+        &pool,
+        "client/final..v2.txt",
+        Some(client),
+        Some("text/plain"),
+    )
+    .await;
+    let renders = common::add(&pool, "client/renders", Some(client), None).await;
+    let video = common::add(
+        &pool,
+        "client/renders/final.mp4",
+        Some(renders),
+        Some("video/mp4"),
+    )
+    .await;
+    let private = common::add(&pool, "private", None, None).await;
+    common::add(
+        &pool,
+        "private/notes.txt",
+        Some(private),
+        Some("text/plain"),
+    )
+    .await;
+    db::set_acl(&pool, private, &[admin_id]).await.unwrap();
+
+    let state = Arc::new(TokioMutex::new(common::app_state(pool.clone(), &media_dir)));
+
+    let app = build_router(state).await;
+    let create = |session: &str, body: serde_json::Value| {
+        Request::post("/shares")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    // Creating a share needs an admin login
+    let (status, _, _) = common::send(
+        &app,
+        Request::post("/shares")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({ "id": client }).to_string()))
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = common::send(
+        &app,
+        create("viewer-session", serde_json::json!({ "id": client })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, body) = common::send(
+        &app,
+        create("admin-session", serde_json::json!({ "id": client })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let created: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let url = created["url"].as_str().unwrap().to_string();
+    let first_id = created["share"]["id"].as_i64().unwrap();
+    assert_eq!(created["share"]["username"], "root");
+
+    // Anyone with the link can browse the shared folder, and nothing else
+    let (status, _, body) = common::send(&app, get_uri(&url)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(paths(&body), vec!["brief.txt", "final..v2.txt", "renders"]);
+    assert!(!body.contains("client/"));
+    let (_, _, body) = common::send(&app, get_uri(&format!("{}?path=renders", url))).await;
+    assert_eq!(paths(&body), vec!["renders/final.mp4"]);
+    for escape in ["../private", "renders/../../private"] {
+        let (status, _, _) = common::send(&app, get_uri(&format!("{}?path={}", url, escape))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", escape);
+    }
+    // Dots inside a name are not a parent reference
+    let (status, _, body) =
+        common::send(&app, get_uri(&format!("{}?path=final..v2.txt", url))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "v2");
+    let (status, _, _) = common::send(&app, get_uri("/s/not-a-real-token")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Files stream with range support
+    let file_uri = format!("{}?path=renders/final.mp4", url);
+    let (status, _, body) = common::send(
+        &app,
+        Request::get(&file_uri)
+            .header(header::RANGE, "bytes=2-4")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, "234");
+    let (status, _, body) = common::send(&app, get_uri(&file_uri)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "0123456789");
+
+    // Password and download limit: the second full download is refused
+    let (status, _, body) = common::send(
+        &app,
+        create(
+            "admin-session",
+            serde_json::json!({ "id": video, "password": "pw", "max_downloads": 1 }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let created: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let limited = created["url"].as_str().unwrap().to_string();
+    let limited_id = created["share"]["id"].as_i64().unwrap();
+    assert_eq!(created["share"]["has_password"], true);
+    let with_password = |password: &str| {
+        Request::get(&limited)
+            .header("X-Share-Password", password)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (status, _, _) = common::send(&app, get_uri(&limited)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = common::send(&app, with_password("nope")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // The password is not taken from the query string
+    let (status, _, _) = common::send(&app, get_uri(&format!("{}?password=pw", limited))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Browsers exchange the password for a cookie scoped to the share; other
+    // clients can pass the same token as `access`
+    let unlock = |password: &str| {
+        Request::post(format!("{}/unlock", limited))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "password": password }).to_string(),
+            ))
+            .unwrap()
+    };
+    let (status, _, _) = common::send(&app, unlock("nope")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, headers, body) = common::send(&app, unlock("pw")).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let cookie = headers[header::SET_COOKIE].to_str().unwrap().to_string();
+    assert!(cookie.contains("HttpOnly"), "{}", cookie);
+    assert!(cookie.contains(&format!("Path={}", limited)), "{}", cookie);
+    let access = serde_json::from_str::<serde_json::Value>(&body).unwrap()["access"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let seek = |cookie: Option<&str>, query: &str| {
+        let mut req =
+            Request::get(format!("{}{}", limited, query)).header(header::RANGE, "bytes=2-4");
+        if let Some(c) = cookie {
+            req = req.header(header::COOKIE, c);
+        }
+        req.body(Body::empty()).unwrap()
+    };
+    let cookie_pair = cookie.split(';').next().unwrap().to_string();
+    let (status, _, body) = common::send(&app, seek(Some(&cookie_pair), "")).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, "234");
+    let (status, _, _) = common::send(&app, seek(None, &format!("?access={}", access))).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    // Tokens cannot be forged or moved to another share
+    let (expires, _) = access.split_once('.').unwrap();
+    let forged = format!(
+        "{}.{}",
+        expires.parse::<i64>().unwrap() + 3600,
+        "0".repeat(64)
+    );
+    for bad in [forged.as_str(), "garbage"] {
+        let (status, _, _) = common::send(&app, seek(None, &format!("?access={}", bad))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", bad);
+    }
+    db::create_share(
+        &pool,
+        &hash_token("same-password"),
+        video,
+        admin_id,
+        None,
+        Some(&hash_password("pw").unwrap()),
+        None,
+    )
+    .await
+    .unwrap();
+    let (status, _, _) = common::send(
+        &app,
+        get_uri(&format!("/s/same-password?access={}", access)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _, body) = common::send(&app, with_password("pw")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "0123456789");
+    let (status, _, _) = common::send(&app, with_password("pw")).await;
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _, _) = common::send(&app, unlock("pw")).await;
+    assert_eq!(status, StatusCode::GONE);
+
+    // Expired shares are gone
+    db::create_share(
+        &pool,
+        &hash_token("expired-share"),
+        client,
+        admin_id,
+        Some(now_secs() - 1),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let (status, _, _) = common::send(&app, get_uri("/s/expired-share")).await;
+    assert_eq!(status, StatusCode::GONE);
+
+    // Admins list and revoke shares; viewers cannot
+    let admin_get = |session: &str, uri: &str| {
+        Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", session))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (status, _, _) = common::send(&app, admin_get("viewer-session", "/admin/shares")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, body) = common::send(&app, admin_get("admin-session", "/admin/shares")).await;
+    assert_eq!(status, StatusCode::OK);
+    let listed: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let shares = listed["shares"].as_array().unwrap();
+    assert_eq!(shares.len(), 4);
+    let limited_entry = shares.iter().find(|s| s["id"] == limited_id).unwrap();
+    assert_eq!(limited_entry["downloads"], 1);
+    assert_eq!(limited_entry["path"], "client/renders/final.mp4");
+    let (status, _, _) = common::send(
+        &app,
+        Request::delete(format!("/admin/shares/{}", first_id))
+            .header(header::AUTHORIZATION, "Bearer admin-session")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _, _) = common::send(&app, get_uri(&url)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn any_response_from_byte_zero_counts_as_a_download() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("final.mp4"), b"0123456789").unwrap();
+
+    let pool = common::memory_pool().await;
+    let admin_id = db::create_user(&pool, "root", "unused", Role::Admin)
+        .await
+        .expect("create admin");
+    let video = common::add(&pool, "final.mp4", None, Some("video/mp4")).await;
+    let state = Arc::new(TokioMutex::new(common::app_state(pool.clone(), &media_dir)));
+    let app = build_router(state).await;
+    let get_range = |token: &str, range: &str| {
+        Request::get(format!("/s/{}", token))
+            .header(header::RANGE, range)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Suffix ranges reaching back to the start, ranges that merge into the
+    // whole file and unknown units served as a full 200 all use up the link
+    for (i, range) in ["bytes=-999999999999", "bytes=1-,0-0", "items=0-"]
+        .into_iter()
+        .enumerate()
+    {
+        let token = format!("share-{}", i);
+        db::create_share(
+            &pool,
+            &hash_token(&token),
+            video,
+            admin_id,
+            None,
+            None,
+            Some(1),
+        )
+        .await
+        .unwrap();
+        // Seeking within the file is free
+        let (status, _, body) = common::send(&app, get_range(&token, "bytes=2-4")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT, "{}", range);
+        assert_eq!(body, "234");
+        let (status, _, body) = common::send(&app, get_range(&token, range)).await;
+        assert!(status.is_success(), "{}: {}", range, status);
+        assert!(body.contains("0123456789"), "{}: {}", range, body);
+        let (status, _, _) = common::send(&app, get_range(&token, "bytes=2-4")).await;
+        assert_eq!(status, StatusCode::GONE, "{} was not counted", range);
+    }
+}
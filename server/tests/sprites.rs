@@ -0,0 +1,209 @@
+mod common;
+
+use axum::body::HttpBody;
+use axum::extract::{Extension, Path, State};
+use axum::http::StatusCode;
+use image::{ImageBuffer, Rgb};
+use server::db;
+use server::handlers::sprite_handler;
+use server::handlers::sprites::{sprite_layout, sprite_vtt, SpriteLayout};
+use server::handlers::thumbnails::run_deduped;
+use server::models::NewMediaEntry;
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn sprite_layout_and_vtt() {
+    let layout = sprite_layout(95.0, Some(1920), Some(1080));
+    assert_eq!(
+        layout,
+        SpriteLayout {
+            interval_secs: 10,
+            tile_width: 160,
+            tile_height: 90,
+            columns: 10,
+            rows: 1,
+            count: 10,
+        }
+    );
+    let vtt = sprite_vtt(&layout, 95.0, "sprite.jpg");
+    assert!(
+        vtt.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:10.000\nsprite.jpg#xywh=0,0,160,90\n")
+    );
+    assert!(vtt.ends_with("\n00:01:30.000 --> 00:01:35.000\nsprite.jpg#xywh=1440,0,160,90\n"));
+
+    // Portrait video: taller tiles, wrapped onto a second row
+    let layout = sprite_layout(125.0, Some(1080), Some(1920));
+    assert_eq!(
+        (layout.tile_height, layout.count, layout.rows),
+        (284, 13, 2)
+    );
+    let vtt = sprite_vtt(&layout, 125.0, "sprite.jpg");
+    assert!(vtt.contains("00:01:40.000 --> 00:01:50.000\nsprite.jpg#xywh=0,284,160,284\n"));
+
+    // Long videos spread the same tile budget over a wider interval
+    let layout = sprite_layout(3.0 * 3600.0, None, None);
+    assert_eq!((layout.interval_secs, layout.count), (27, 400));
+    assert_eq!(layout.tile_height, 90);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn sprites_are_generated_once_and_cached() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"not really a video").unwrap();
+    std::fs::write(media_dir.join("photo.jpg"), b"not really a photo").unwrap();
+
+    // Stand-in for ffmpeg: logs each run and writes a canned sheet
+    let sheet = base.join("sheet.jpg");
+    ImageBuffer::from_pixel(320, 90, Rgb([10u8, 20u8, 30u8]))
+        .save(&sheet)
+        .unwrap();
+    let fake = base.join("ffmpeg");
+    std::fs::write(
+        &fake,
+        format!(
+            "#!/bin/sh\nfor a; do out=\"$a\"; done\necho run >> '{}'\nsleep 0.2\ncp '{}' \"$out\"\n",
+            base.join("runs").display(),
+            sheet.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let mut ids = Vec::new();
+    for (path, mime) in [("clip.mp4", "video/mp4"), ("photo.jpg", "image/jpeg")] {
+        let id = db::upsert_media(
+            pool.clone(),
+            &NewMediaEntry {
+                name: path.to_string(),
+                path: path.to_string(),
+                parent_id: None,
+                mime_type: Some(mime.to_string()),
+                size: Some(18),
+                tags: None,
+                thumb_path: None,
+                width: Some(1280),
+                height: Some(720),
+                duration_secs: Some(15),
+                mtime_ns: None,
+                inode: None,
+            },
+        )
+        .await
+        .expect("upsert media");
+        ids.push(id);
+    }
+    let state = Arc::new(TokioMutex::new(AppState {
+        ffmpeg_enabled: true,
+        ffmpeg_path: Some(fake.to_string_lossy().to_string()),
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+
+    // Concurrent requests share one ffmpeg run
+    let mut handles = Vec::new();
+    for file in ["sprite.vtt", "sprite.jpg", "sprite.vtt", "sprite.jpg"] {
+        let s = state.clone();
+        let id = ids[0];
+        handles.push(tokio::spawn(async move {
+            sprite_handler(
+                State(s),
+                Extension(common::user()),
+                Path((id, file.to_string())),
+            )
+            .await
+        }));
+    }
+    for h in handles {
+        let res = h.await.unwrap().expect("sprite");
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = sprite_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        Path((ids[0], "sprite.vtt".to_string())),
+    )
+    .await
+    .expect("sprite");
+    assert_eq!(res.headers()["content-type"], "text/vtt; charset=utf-8");
+    let mut body = res.into_body();
+    let mut text = Vec::new();
+    while let Some(chunk) = body.data().await {
+        text.extend_from_slice(&chunk.expect("body chunk"));
+    }
+    assert_eq!(
+        String::from_utf8(text).unwrap(),
+        "WEBVTT\n\n00:00:00.000 --> 00:00:10.000\nsprite.jpg#xywh=0,0,160,90\n\n00:00:10.000 --> 00:00:15.000\nsprite.jpg#xywh=160,0,160,90\n"
+    );
+    let runs = std::fs::read_to_string(base.join("runs")).unwrap();
+    assert_eq!(runs.lines().count(), 1);
+
+    // Both files sit in the thumbnails cache under the video's id
+    let mut cached: Vec<String> = std::fs::read_dir(&thumbs_dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    cached.sort();
+    assert_eq!(cached.len(), 2);
+    assert!(cached[0].starts_with(&format!("{}_", ids[0])) && cached[0].ends_with("_sprite.jpg"));
+    assert!(cached[1].ends_with("_sprite.vtt"));
+
+    let err = sprite_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        Path((ids[1], "sprite.jpg".to_string())),
+    )
+    .await
+    .expect_err("not a video");
+    assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    let err = sprite_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        Path((ids[0], "other.png".to_string())),
+    )
+    .await
+    .expect_err("unknown file");
+    assert_eq!(err.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn abandoned_generation_is_not_wedged() {
+    let in_flight = Arc::new(TokioMutex::new(std::collections::HashMap::new()));
+    let key = || "1:sprite".to_string();
+    let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+    let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
+    let work = async move {
+        let _ = started_tx.send(());
+        let _ = release_rx.await;
+        Ok(())
+    };
+
+    // The first request goes away while its job is still running
+    tokio::select! {
+        _ = run_deduped(&in_flight, key(), work) => panic!("job finished early"),
+        _ = started_rx => {}
+    }
+
+    // A later request for the same key gets that job's result instead of
+    // waiting forever or starting a second one
+    let (res, _) = tokio::join!(
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            run_deduped(&in_flight, key(), async {
+                Err("duplicate job".to_string())
+            }),
+        ),
+        async { release_tx.send(()) },
+    );
+    assert_eq!(res.expect("request hung"), Ok(()));
+    assert!(in_flight.lock().await.is_empty());
+}
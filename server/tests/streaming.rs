@@ -0,0 +1,343 @@
+mod common;
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{Extension, Query, State};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use server::db;
+use server::handlers::stream_handler;
+use server::handlers::streaming::{
+    evaluate_preconditions, if_range_allows, parse_range_header, Precondition, StreamQuery,
+    MAX_RANGES,
+};
+use server::models::NewMediaEntry;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Mutex as TokioMutex;
+
+#[test]
+fn ranges_are_parsed_and_coalesced() {
+    assert_eq!(parse_range_header("bytes=0-9", 100), Ok(Some(vec![(0, 9)])));
+    assert_eq!(
+        parse_range_header("bytes=90-", 100),
+        Ok(Some(vec![(90, 99)]))
+    );
+    assert_eq!(
+        parse_range_header("bytes=-10", 100),
+        Ok(Some(vec![(90, 99)]))
+    );
+    // End past the file is clamped, suffixes longer than the file cover all of it
+    assert_eq!(
+        parse_range_header("bytes=50-500", 100),
+        Ok(Some(vec![(50, 99)]))
+    );
+    assert_eq!(
+        parse_range_header("bytes=-500", 100),
+        Ok(Some(vec![(0, 99)]))
+    );
+
+    // Overlapping and adjacent ranges merge, the rest are sorted
+    assert_eq!(
+        parse_range_header("bytes=50-59, 0-4,5-9 ,55-70", 100),
+        Ok(Some(vec![(0, 9), (50, 70)]))
+    );
+    // Unsatisfiable ranges are dropped as long as one remains
+    assert_eq!(
+        parse_range_header("bytes=0-0,200-300", 100),
+        Ok(Some(vec![(0, 0)]))
+    );
+
+    // Other units are ignored
+    assert_eq!(parse_range_header("items=0-1", 100), Ok(None));
+
+    assert!(parse_range_header("bytes=200-300", 100).is_err());
+    assert!(parse_range_header("bytes=5-1", 100).is_err());
+    assert!(parse_range_header("bytes=a-b", 100).is_err());
+    assert!(parse_range_header("bytes=-", 100).is_err());
+    assert!(parse_range_header("bytes=", 100).is_err());
+
+    let many: Vec<String> = (0..=MAX_RANGES)
+        .map(|i| format!("{}-{}", i * 2, i * 2))
+        .collect();
+    assert!(parse_range_header(&format!("bytes={}", many.join(",")), 1000).is_err());
+}
+
+fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    for (k, v) in pairs {
+        h.insert(*k, HeaderValue::from_str(v).unwrap());
+    }
+    h
+}
+
+#[test]
+fn preconditions_follow_rfc7232_order() {
+    let etag = "\"abc\"";
+    let modified = Some(UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    let before = "Sat, 08 Sep 2001 01:46:39 GMT";
+    let at = "Sun, 09 Sep 2001 01:46:40 GMT";
+    let eval =
+        |pairs: &[(&'static str, &str)]| evaluate_preconditions(&headers(pairs), etag, modified);
+
+    assert_eq!(eval(&[]), Precondition::Proceed);
+
+    // If-Match uses strong comparison
+    assert_eq!(
+        eval(&[("if-match", "\"x\", \"abc\"")]),
+        Precondition::Proceed
+    );
+    assert_eq!(eval(&[("if-match", "*")]), Precondition::Proceed);
+    assert_eq!(eval(&[("if-match", "W/\"abc\"")]), Precondition::Failed);
+    assert_eq!(
+        eval(&[("if-unmodified-since", before)]),
+        Precondition::Failed
+    );
+    assert_eq!(eval(&[("if-unmodified-since", at)]), Precondition::Proceed);
+    // If-Match takes precedence over If-Unmodified-Since
+    assert_eq!(
+        eval(&[("if-match", "\"abc\""), ("if-unmodified-since", before)]),
+        Precondition::Proceed
+    );
+
+    // If-None-Match uses weak comparison and accepts lists
+    assert_eq!(
+        eval(&[("if-none-match", "W/\"abc\"")]),
+        Precondition::NotModified
+    );
+    assert_eq!(
+        eval(&[("if-none-match", "\"x\",\"abc\"")]),
+        Precondition::NotModified
+    );
+    assert_eq!(eval(&[("if-none-match", "*")]), Precondition::NotModified);
+    assert_eq!(eval(&[("if-none-match", "\"x\"")]), Precondition::Proceed);
+    assert_eq!(
+        eval(&[("if-modified-since", at)]),
+        Precondition::NotModified
+    );
+    assert_eq!(
+        eval(&[("if-modified-since", before)]),
+        Precondition::Proceed
+    );
+    // If-None-Match takes precedence over If-Modified-Since
+    assert_eq!(
+        eval(&[("if-none-match", "\"x\""), ("if-modified-since", at)]),
+        Precondition::Proceed
+    );
+    // Unparseable dates are ignored
+    assert_eq!(
+        eval(&[("if-modified-since", "yesterday")]),
+        Precondition::Proceed
+    );
+
+    assert!(if_range_allows(&headers(&[]), etag, modified));
+    assert!(if_range_allows(
+        &headers(&[("if-range", "\"abc\"")]),
+        etag,
+        modified
+    ));
+    assert!(!if_range_allows(
+        &headers(&[("if-range", "W/\"abc\"")]),
+        etag,
+        modified
+    ));
+    assert!(!if_range_allows(
+        &headers(&[("if-range", "\"old\"")]),
+        etag,
+        modified
+    ));
+    assert!(if_range_allows(
+        &headers(&[("if-range", at)]),
+        etag,
+        modified
+    ));
+    assert!(!if_range_allows(
+        &headers(&[("if-range", before)]),
+        etag,
+        modified
+    ));
+}
+
+async fn body_bytes(mut body: axum::body::BoxBody) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        out.extend_from_slice(&chunk.expect("body chunk"));
+    }
+    out
+}
+
+#[tokio::test]
+async fn multiple_ranges_are_served_as_multipart() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+    std::fs::write(media_dir.join("digits.txt"), b"0123456789abcdefghij").unwrap();
+
+    let pool = common::memory_pool().await;
+    let id = db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: "digits.txt".to_string(),
+            path: "digits.txt".to_string(),
+            parent_id: None,
+            mime_type: Some("text/plain".to_string()),
+            size: Some(20),
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: None,
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert");
+
+    let state = Arc::new(TokioMutex::new(common::app_state(pool, &media_dir)));
+    let get = |range: &str| {
+        Request::builder()
+            .header("range", range)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let query = || {
+        Query(StreamQuery {
+            id: Some(id),
+            path: None,
+        })
+    };
+
+    // Two disjoint ranges after coalescing
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        get("bytes=0-1,15-,1-3"),
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let ctype = res.headers()["content-type"].to_str().unwrap().to_string();
+    let boundary = ctype
+        .strip_prefix("multipart/byteranges; boundary=")
+        .expect("multipart content type")
+        .to_string();
+    let length: usize = res.headers()["content-length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = body_bytes(res.into_body()).await;
+    assert_eq!(body.len(), length);
+    let expected = format!(
+        "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-3/20\r\n\r\n0123\
+         \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 15-19/20\r\n\r\nfghij\
+         \r\n--{b}--\r\n",
+        b = boundary
+    );
+    assert_eq!(String::from_utf8(body).unwrap(), expected);
+
+    // Ranges that collapse into one are served as a plain 206
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        get("bytes=2-4,4-6"),
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()["content-range"], "bytes 2-6/20");
+    assert_eq!(body_bytes(res.into_body()).await, b"23456");
+
+    let err = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        get("bytes=30-40,50-"),
+    )
+    .await
+    .expect_err("unsatisfiable");
+    assert_eq!(err.0, StatusCode::RANGE_NOT_SATISFIABLE);
+
+    // A stale If-Range falls back to the full file
+    let req = Request::builder()
+        .header("range", "bytes=0-1")
+        .header("if-range", "\"stale\"")
+        .body(Body::empty())
+        .unwrap();
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        req,
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::OK);
+    let etag = res.headers()["etag"].to_str().unwrap().to_string();
+    assert_eq!(body_bytes(res.into_body()).await, b"0123456789abcdefghij");
+
+    // A current one keeps the range
+    let req = Request::builder()
+        .header("range", "bytes=0-1")
+        .header("if-range", &etag)
+        .body(Body::empty())
+        .unwrap();
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        req,
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+    let req = Request::builder()
+        .header("if-none-match", format!("\"other\", W/{}", etag))
+        .body(Body::empty())
+        .unwrap();
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        req,
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    let req = Request::builder()
+        .header("if-match", "\"other\"")
+        .body(Body::empty())
+        .unwrap();
+    let err = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        req,
+    )
+    .await
+    .expect_err("precondition");
+    assert_eq!(err.0, StatusCode::PRECONDITION_FAILED);
+
+    // HEAD carries the GET headers but no body
+    let req = Request::builder()
+        .method(Method::HEAD)
+        .header("range", "bytes=5-9")
+        .body(Body::empty())
+        .unwrap();
+    let res = stream_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        query(),
+        req,
+    )
+    .await
+    .expect("stream");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()["content-length"], "5");
+    assert_eq!(res.headers()["content-range"], "bytes 5-9/20");
+    assert_eq!(res.headers()["etag"].to_str().unwrap(), etag);
+    assert!(body_bytes(res.into_body()).await.is_empty());
+}
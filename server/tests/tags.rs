@@ -0,0 +1,224 @@
+use server::db;
+use server::models::NewMediaEntry;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+
+fn entry(path: &str, tags: Option<Vec<&str>>) -> NewMediaEntry {
+    NewMediaEntry {
+        name: path.rsplit('/').next().unwrap().to_string(),
+        path: path.to_string(),
+        parent_id: None,
+        mime_type: Some("image/jpeg".to_string()),
+        size: Some(1),
+        tags: tags.map(|t| t.into_iter().map(|s| s.to_string()).collect()),
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: None,
+        inode: None,
+    }
+}
+
+async fn memory_pool() -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect("sqlite::memory:")
+        .await
+        .expect("create in-memory db")
+}
+
+fn strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+#[tokio::test]
+async fn tags_are_added_removed_and_counted() {
+    let pool = memory_pool().await;
+    db::initialize_database(pool.clone())
+        .await
+        .expect("init db");
+
+    let a = db::upsert_media(pool.clone(), &entry("a.jpg", Some(vec!["cat", "summer"])))
+        .await
+        .expect("upsert");
+    let b = db::upsert_media(pool.clone(), &entry("b.jpg", Some(vec!["cat"])))
+        .await
+        .expect("upsert");
+
+    db::add_media_tags(&pool, b, &strings(&["dog", " ", "cat"]))
+        .await
+        .expect("add");
+    assert_eq!(
+        db::get_media_tags(&pool, b).await.expect("get"),
+        strings(&["cat", "dog"])
+    );
+
+    let counts = db::list_tags_with_counts(&pool, None)
+        .await
+        .expect("counts");
+    let counts: Vec<(&str, i64)> = counts.iter().map(|t| (t.name.as_str(), t.count)).collect();
+    assert_eq!(counts, vec![("cat", 2), ("dog", 1), ("summer", 1)]);
+
+    // Unused tags disappear from the listing
+    db::remove_media_tags(&pool, a, &strings(&["summer"]))
+        .await
+        .expect("remove");
+    let names: Vec<String> = db::list_tags_with_counts(&pool, None)
+        .await
+        .expect("counts")
+        .into_iter()
+        .map(|t| t.name)
+        .collect();
+    assert_eq!(names, strings(&["cat", "dog"]));
+
+    // A rescan without tags keeps them; explicit tags replace them
+    db::upsert_media(pool.clone(), &entry("a.jpg", None))
+        .await
+        .expect("upsert");
+    let got = db::get_media_by_id(pool.clone(), a)
+        .await
+        .expect("get")
+        .unwrap();
+    assert_eq!(got.tags, Some(strings(&["cat"])));
+    db::upsert_media(pool.clone(), &entry("a.jpg", Some(vec![])))
+        .await
+        .expect("upsert");
+    let got = db::get_media_by_id(pool.clone(), a)
+        .await
+        .expect("get")
+        .unwrap();
+    assert!(got.tags.is_none());
+
+    // Tag edits are reflected in search
+    let hits = db::search_media(pool.clone(), "dog", None, None, None, None, None)
+        .await
+        .expect("search");
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].id, b);
+}
+
+#[tokio::test]
+async fn listing_filters_by_all_tags_with_paging() {
+    let pool = memory_pool().await;
+    db::initialize_database(pool.clone())
+        .await
+        .expect("init db");
+
+    for (path, tags) in [
+        ("1.jpg", vec!["x", "y"]),
+        ("2.jpg", vec!["x"]),
+        ("3.jpg", vec!["x", "y", "z"]),
+        ("4.jpg", vec!["y"]),
+        ("5.jpg", vec!["x", "y"]),
+    ] {
+        db::upsert_media(pool.clone(), &entry(path, Some(tags)))
+            .await
+            .expect("upsert");
+    }
+
+    let want = Some(strings(&["x", "y", "x"]));
+    let page = db::list_children_advanced(
+        pool.clone(),
+        None,
+        want.clone(),
+        None,
+        None,
+        Some(2),
+        Some(0),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("list");
+    let names: Vec<&str> = page.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, vec!["1.jpg", "3.jpg"]);
+
+    let page = db::list_children_advanced(
+        pool.clone(),
+        None,
+        want.clone(),
+        None,
+        None,
+        Some(2),
+        Some(2),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("list");
+    let names: Vec<&str> = page.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, vec!["5.jpg"]);
+
+    let all = db::list_children(pool.clone(), None, Some(strings(&["z"])))
+        .await
+        .expect("list");
+    assert_eq!(all.len(), 1);
+    assert_eq!(all[0].name, "3.jpg");
+}
+
+#[tokio::test]
+async fn json_tags_are_migrated() {
+    let pool = memory_pool().await;
+
+    // Schema as written by builds that stored tags as a JSON column
+    sqlx::query(
+        r#"
+        CREATE TABLE media (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            path TEXT NOT NULL UNIQUE,
+            parent_id INTEGER,
+            mime_type TEXT,
+            size INTEGER,
+            tags TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (parent_id) REFERENCES media (id)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await
+    .expect("create legacy table");
+    for (path, tags) in [
+        ("a.jpg", Some(r#"["beach","summer"]"#)),
+        ("b.jpg", Some(r#"["summer"]"#)),
+        ("c.jpg", Some("not json")),
+        ("d.jpg", None),
+    ] {
+        sqlx::query("INSERT INTO media (name, path, tags) VALUES (?1, ?1, ?2)")
+            .bind(path)
+            .bind(tags)
+            .execute(&pool)
+            .await
+            .expect("insert legacy row");
+    }
+
+    db::initialize_database(pool.clone())
+        .await
+        .expect("migrate legacy db");
+
+    let a = db::get_media_by_path(pool.clone(), "a.jpg".to_string())
+        .await
+        .expect("get")
+        .unwrap();
+    assert_eq!(a.tags, Some(strings(&["beach", "summer"])));
+    let c = db::get_media_by_path(pool.clone(), "c.jpg".to_string())
+        .await
+        .expect("get")
+        .unwrap();
+    assert!(c.tags.is_none());
+
+    let counts = db::list_tags_with_counts(&pool, None)
+        .await
+        .expect("counts");
+    let counts: Vec<(&str, i64)> = counts.iter().map(|t| (t.name.as_str(), t.count)).collect();
+    assert_eq!(counts, vec![("summer", 2), ("beach", 1)]);
+
+    let hits = db::search_media(pool.clone(), "beach", None, None, None, None, None)
+        .await
+        .expect("search");
+    assert_eq!(hits.len(), 1);
+}
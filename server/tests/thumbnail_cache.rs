@@ -0,0 +1,308 @@
+mod common;
+
+use axum::body::Body;
+use axum::extract::{Extension, Query, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use image::{ImageBuffer, Rgb};
+use server::auth::{hash_token, now_secs};
+use server::db;
+use server::handlers::thumbnails::{
+    evict_thumbnails, generate_thumbnail_for_entry, parse_thumbnail_name, thumbnail_handler,
+    ThumbQuery,
+};
+use server::models::{NewMediaEntry, Role};
+use server::startup::{build_router, prepare_thumbnails_cache};
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+fn new_entry(path: &str) -> NewMediaEntry {
+    NewMediaEntry {
+        name: path.to_string(),
+        path: path.to_string(),
+        parent_id: None,
+        mime_type: Some("image/jpeg".to_string()),
+        size: Some(0),
+        tags: None,
+        thumb_path: None,
+        width: None,
+        height: None,
+        duration_secs: None,
+        mtime_ns: None,
+        inode: None,
+    }
+}
+
+#[test]
+fn thumbnail_names_round_trip() {
+    assert_eq!(
+        parse_thumbnail_name("12_0123456789abcdef_100x100.jpg"),
+        Some((12, "0123456789abcdef"))
+    );
+    // Pre-fingerprint names, temp files and the placeholder are not cache keys
+    assert_eq!(parse_thumbnail_name("12_100x100.jpg"), None);
+    assert_eq!(
+        parse_thumbnail_name("12_0123456789abcdef_100x100.jpg.1700000000.jpg"),
+        None
+    );
+    assert_eq!(parse_thumbnail_name("placeholder.jpg"), None);
+}
+
+#[tokio::test]
+async fn edited_sources_get_fresh_thumbnails() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+
+    let img_path = media_dir.join("photo.jpg");
+    ImageBuffer::from_pixel(64, 64, Rgb([200u8, 20u8, 20u8]))
+        .save(&img_path)
+        .unwrap();
+
+    let pool = common::memory_pool().await;
+    let id = db::upsert_media(pool.clone(), &new_entry("photo.jpg"))
+        .await
+        .expect("upsert media");
+
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let query = || {
+        Query(ThumbQuery {
+            id: Some(id),
+            path: None,
+            w: Some(32),
+            h: Some(32),
+        })
+    };
+
+    let entry = db::get_media_by_id(pool.clone(), id)
+        .await
+        .unwrap()
+        .unwrap();
+    let first = generate_thumbnail_for_entry(state.clone(), &entry, 32, 32)
+        .await
+        .expect("thumbnail");
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        HeaderMap::new(),
+        query(),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(res.status(), 200);
+
+    // Replace the photo: the cached thumbnail no longer matches the source
+    ImageBuffer::from_pixel(48, 48, Rgb([20u8, 20u8, 200u8]))
+        .save(&img_path)
+        .unwrap();
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        HeaderMap::new(),
+        query(),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(res.status(), 307, "stale thumbnail must not be served");
+
+    let entry = db::get_media_by_id(pool.clone(), id)
+        .await
+        .unwrap()
+        .unwrap();
+    let second = generate_thumbnail_for_entry(state.clone(), &entry, 32, 32)
+        .await
+        .expect("thumbnail");
+    assert_ne!(first, second);
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        HeaderMap::new(),
+        query(),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(res.status(), 200);
+
+    // A thumbnail of an entry that no longer exists, and one named the old way
+    let gone = thumbs_dir.join("999_0123456789abcdef_32x32.jpg");
+    let legacy = thumbs_dir.join(format!("{}_32x32.jpg", id));
+    std::fs::copy(thumbs_dir.join(&second), &gone).unwrap();
+    std::fs::copy(thumbs_dir.join(&second), &legacy).unwrap();
+
+    prepare_thumbnails_cache(&thumbs_dir, &pool, &media_dir).await;
+    assert!(!thumbs_dir.join(&first).exists(), "stale thumbnail kept");
+    assert!(!gone.exists(), "orphaned thumbnail kept");
+    assert!(!legacy.exists(), "legacy thumbnail kept");
+    assert!(thumbs_dir.join(&second).exists(), "live thumbnail removed");
+    assert!(thumbs_dir.join("placeholder.jpg").exists());
+}
+
+#[tokio::test]
+async fn eviction_drops_least_recently_served() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+
+    let pool = common::memory_pool().await;
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+
+    // Three thumbnails, all last used an hour ago
+    let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    let mut ids = Vec::new();
+    let mut total = 0;
+    for name in ["a.jpg", "b.jpg", "c.jpg"] {
+        ImageBuffer::from_pixel(64, 64, Rgb([90u8, 120u8, 150u8]))
+            .save(media_dir.join(name))
+            .unwrap();
+        let id = db::upsert_media(pool.clone(), &new_entry(name))
+            .await
+            .expect("upsert media");
+        let entry = db::get_media_by_id(pool.clone(), id)
+            .await
+            .unwrap()
+            .unwrap();
+        let out = generate_thumbnail_for_entry(state.clone(), &entry, 32, 32)
+            .await
+            .expect("thumbnail");
+        let f = std::fs::File::open(thumbs_dir.join(&out)).unwrap();
+        f.set_times(std::fs::FileTimes::new().set_accessed(an_hour_ago))
+            .unwrap();
+        total += f.metadata().unwrap().len();
+        ids.push(id);
+    }
+
+    // Serving `a` makes it the most recently used
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        HeaderMap::new(),
+        Query(ThumbQuery {
+            id: Some(ids[0]),
+            path: None,
+            w: None,
+            h: None,
+        }),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(res.status(), 200);
+
+    // Under budget: nothing happens
+    assert_eq!(evict_thumbnails(&pool, &thumbs_dir, total).await, 0);
+
+    // Room for a single thumbnail: `b` and `c` go, `a` stays
+    assert_eq!(
+        evict_thumbnails(&pool, &thumbs_dir, total / 3 + 16).await,
+        2
+    );
+    let mut thumbs = Vec::new();
+    for id in &ids {
+        let entry = db::get_media_by_id(pool.clone(), *id)
+            .await
+            .unwrap()
+            .unwrap();
+        thumbs.push(entry.thumb_path.is_some());
+    }
+    assert_eq!(thumbs, vec![true, false, false]);
+}
+
+#[tokio::test]
+async fn static_thumbnail_hits_count_as_use() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+
+    let pool = common::memory_pool().await;
+    let user_id = db::create_user(&pool, "vera", "unused", Role::Viewer)
+        .await
+        .expect("create user");
+    db::create_session(
+        &pool,
+        &hash_token("session"),
+        user_id,
+        now_secs() + 3600,
+        now_secs(),
+    )
+    .await
+    .unwrap();
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let app = build_router(state).await;
+
+    let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    let two_hours_ago = an_hour_ago - std::time::Duration::from_secs(3600);
+    let used = |name: &str| {
+        std::fs::metadata(thumbs_dir.join(name))
+            .unwrap()
+            .accessed()
+            .unwrap()
+    };
+    let get = |uri: String, if_modified_since: Option<String>| {
+        let mut req = Request::get(uri).header(header::AUTHORIZATION, "Bearer session");
+        if let Some(since) = if_modified_since {
+            req = req.header(header::IF_MODIFIED_SINCE, since);
+        }
+        req.body(Body::empty()).unwrap()
+    };
+
+    // Served directly, revalidated by a browser cache and fetched as a
+    // variant. The access time is newer than the mtime, so a plain read on a
+    // relatime mount would leave it alone
+    for name in [
+        "1_0123456789abcdef_32x32.jpg",
+        "2_0123456789abcdef_32x32.jpg",
+        "3_0123456789abcdef_32x32.webp",
+    ] {
+        std::fs::write(thumbs_dir.join(name), b"thumb").unwrap();
+        let f = std::fs::File::open(thumbs_dir.join(name)).unwrap();
+        f.set_times(
+            std::fs::FileTimes::new()
+                .set_accessed(an_hour_ago)
+                .set_modified(two_hours_ago),
+        )
+        .unwrap();
+    }
+    let (status, headers, _) = common::send(
+        &app,
+        get("/thumbnails/1_0123456789abcdef_32x32.jpg".into(), None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let last_modified = headers[header::LAST_MODIFIED].to_str().unwrap().to_string();
+    let (status, _, _) = common::send(
+        &app,
+        get(
+            "/thumbnails/2_0123456789abcdef_32x32.jpg".into(),
+            Some(last_modified),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    let (status, _, _) = common::send(
+        &app,
+        get("/thumbnails/3_0123456789abcdef_32x32.webp".into(), None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    for name in [
+        "1_0123456789abcdef_32x32.jpg",
+        "2_0123456789abcdef_32x32.jpg",
+        "3_0123456789abcdef_32x32.webp",
+    ] {
+        assert!(used(name) > an_hour_ago, "{} not marked as used", name);
+    }
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use axum::extract::{Extension, Query, State};
+use axum::http::{HeaderMap, HeaderValue};
+use image::{ImageBuffer, Rgb};
+use server::db;
+use server::handlers::generate_thumbnail_handler;
+use server::handlers::thumbnails::{
+    ensure_thumbnail_variant, negotiate_format, thumbnail_handler, GenThumbQuery, ThumbFormat,
+    ThumbQuery,
+};
+use server::models::NewMediaEntry;
+use server::state::AppState;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+const CHROME: &str = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+
+#[cfg(unix)]
+#[tokio::test]
+async fn thumbnails_are_negotiated_from_accept() {
+    use axum::response::IntoResponse;
+    use std::os::unix::fs::PermissionsExt;
+
+    assert_eq!(negotiate_format(None, true), ThumbFormat::Jpeg);
+    assert_eq!(
+        negotiate_format(Some("image/*,*/*"), true),
+        ThumbFormat::Jpeg
+    );
+    assert_eq!(
+        negotiate_format(Some("image/webp,image/*;q=0.8"), true),
+        ThumbFormat::WebP
+    );
+    assert_eq!(
+        negotiate_format(Some("image/avif;q=0,image/webp"), true),
+        ThumbFormat::WebP
+    );
+    // Without ffmpeg there is nothing to convert with
+    assert_eq!(negotiate_format(Some(CHROME), false), ThumbFormat::Jpeg);
+    assert_eq!(negotiate_format(Some(CHROME), true), ThumbFormat::Avif);
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    ImageBuffer::from_pixel(64, 64, Rgb([90u8, 120u8, 150u8]))
+        .save(media_dir.join("photo.jpg"))
+        .unwrap();
+
+    // Stand-in for ffmpeg: copies its input for WebP and lacks an AV1 encoder
+    let fake = base.join("ffmpeg");
+    std::fs::write(
+        &fake,
+        r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+  case "$1" in
+    -i) src="$2"; shift ;;
+    libaom-av1) echo "Unknown encoder 'libaom-av1'" >&2; exit 1 ;;
+  esac
+  out="$1"
+  shift
+done
+cp "$src" "$out"
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let id = db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: "photo.jpg".to_string(),
+            path: "photo.jpg".to_string(),
+            parent_id: None,
+            mime_type: Some("image/jpeg".to_string()),
+            size: Some(0),
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: None,
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert media");
+    let state = Arc::new(TokioMutex::new(AppState {
+        ffmpeg_enabled: true,
+        ffmpeg_path: Some(fake.to_string_lossy().to_string()),
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let accept = |value: &'static str| {
+        let mut h = HeaderMap::new();
+        h.insert("accept", HeaderValue::from_static(value));
+        h
+    };
+    let query = || ThumbQuery {
+        id: Some(id),
+        path: None,
+        w: Some(32),
+        h: Some(32),
+    };
+
+    // The generator redirects to the negotiated variant
+    let res = generate_thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        accept("image/webp,*/*"),
+        Query(GenThumbQuery {
+            id: Some(id),
+            path: None,
+            w: Some(32),
+            h: Some(32),
+        }),
+    )
+    .await
+    .expect("generate")
+    .into_response();
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+    assert!(location.ends_with("_32x32.webp"), "{}", location);
+    assert_eq!(res.headers()["vary"], "Accept");
+    assert!(thumbs_dir
+        .join(location.trim_start_matches("/thumbnails/"))
+        .exists());
+
+    let content_type = |res: &axum::response::Response| {
+        res.headers()["content-type"].to_str().unwrap().to_string()
+    };
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        accept("image/webp"),
+        Query(query()),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(content_type(&res), "image/webp");
+    assert_eq!(res.headers()["vary"], "Accept");
+
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        HeaderMap::new(),
+        Query(query()),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(content_type(&res), "image/jpeg");
+
+    // AVIF fails for want of an encoder: JPEG is served and AVIF is not tried again
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        accept(CHROME),
+        Query(query()),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(content_type(&res), "image/jpeg");
+    assert_eq!(negotiate_format(Some(CHROME), true), ThumbFormat::WebP);
+    let res = thumbnail_handler(
+        State(state.clone()),
+        Extension(common::user()),
+        accept(CHROME),
+        Query(query()),
+    )
+    .await
+    .expect("thumbnail");
+    assert_eq!(content_type(&res), "image/webp");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn concurrent_variant_requests_convert_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    std::fs::write(thumbs_dir.join("7_abc_32x32.jpg"), b"jpeg").unwrap();
+
+    // Logs each run and is slow enough for the requests to overlap
+    let log = base.join("runs.log");
+    let fake = base.join("ffmpeg");
+    std::fs::write(
+        &fake,
+        format!(
+            "#!/bin/sh\nfor a; do out=\"$a\"; done\necho run >> '{}'\nsleep 0.2\nprintf webp > \"$out\"\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let pool = common::memory_pool().await;
+    let state = common::app_state(pool, &base);
+    let ffmpeg = fake.to_string_lossy().to_string();
+    let results = futures::future::join_all((0..4).map(|_| {
+        ensure_thumbnail_variant(
+            &ffmpeg,
+            &thumbs_dir,
+            "7_abc_32x32.jpg",
+            ThumbFormat::WebP,
+            &state.regen_semaphore,
+            &state.in_flight,
+        )
+    }))
+    .await;
+    for res in results {
+        assert_eq!(res.expect("variant"), "7_abc_32x32.webp");
+    }
+    assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 1);
+    assert_eq!(
+        std::fs::read(thumbs_dir.join("7_abc_32x32.webp")).unwrap(),
+        b"webp"
+    );
+}
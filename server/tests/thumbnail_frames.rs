@@ -0,0 +1,162 @@
+mod common;
+
+use image::{ImageBuffer, Rgb};
+use server::db;
+use server::handlers::thumbnails::{
+    candidate_times, frame_stats, generate_thumbnail_for_entry, pick_frame, FrameStats,
+};
+use server::models::NewMediaEntry;
+use server::state::AppState;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+// Checkerboard of two grey levels; a == b gives a flat frame
+fn checkerboard(a: u8, b: u8) -> image::DynamicImage {
+    image::DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 36, |x, y| {
+        let v = if (x / 4 + y / 4) % 2 == 0 { a } else { b };
+        Rgb([v, v, v])
+    }))
+}
+
+#[test]
+fn frames_are_scored_by_luminance_and_detail() {
+    let black = frame_stats(&checkerboard(4, 4));
+    assert!(black.mean < 5.0 && black.stddev < 0.5);
+    let busy = frame_stats(&checkerboard(60, 200));
+    assert!((busy.mean - 130.0).abs() < 1.0);
+    assert!((busy.stddev - 70.0).abs() < 1.0);
+
+    let stats = |mean, stddev| FrameStats { mean, stddev };
+    // Dark and flat frames lose to any usable one, however noisy they are
+    assert_eq!(
+        pick_frame(&[
+            (1.0, stats(10.0, 40.0)),
+            (2.0, stats(128.0, 2.0)),
+            (3.0, stats(90.0, 20.0)),
+            (4.0, stats(120.0, 35.0)),
+            (5.0, stats(120.0, 35.0)),
+        ]),
+        Some(4.0)
+    );
+    // With nothing usable the most detailed frame is still picked
+    assert_eq!(
+        pick_frame(&[(1.0, stats(3.0, 1.0)), (2.0, stats(5.0, 6.0))]),
+        Some(2.0)
+    );
+    assert_eq!(pick_frame(&[]), None);
+
+    assert_eq!(
+        candidate_times(Some(100.0)),
+        vec![10.0, 25.0, 40.0, 55.0, 70.0]
+    );
+    assert_eq!(candidate_times(None), vec![1.0, 3.0, 5.0, 10.0]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn chosen_frame_is_stored_and_reused() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    std::fs::write(media_dir.join("clip.mp4"), b"not really a video").unwrap();
+
+    // One canned frame per sampled timestamp: black, flat, then three with
+    // increasing detail
+    let frames = [
+        ("10.000", checkerboard(2, 6)),
+        ("25.000", checkerboard(128, 128)),
+        ("40.000", checkerboard(100, 160)),
+        ("55.000", checkerboard(60, 200)),
+        ("70.000", checkerboard(90, 170)),
+    ];
+    let mut cases = String::new();
+    for (t, img) in &frames {
+        let file = base.join(format!("frame_{}.jpg", t));
+        img.save(&file).unwrap();
+        cases.push_str(&format!("  {}) frame='{}' ;;\n", t, file.display()));
+    }
+
+    // Stand-ins: ffprobe reports 100s, ffmpeg logs the seek and writes the
+    // frame for it to stdout or to the output file
+    let ffprobe = base.join("ffprobe");
+    std::fs::write(&ffprobe, "#!/bin/sh\necho 100.0\n").unwrap();
+    let ffmpeg = base.join("ffmpeg");
+    std::fs::write(
+        &ffmpeg,
+        format!(
+            "#!/bin/sh\nprev=\nfor a; do\n  [ \"$prev\" = -ss ] && ss=\"$a\"\n  prev=\"$a\"\n  out=\"$a\"\ndone\necho \"$ss $out\" >> '{}'\ncase \"$ss\" in\n{}  *) exit 1 ;;\nesac\nif [ \"$out\" = - ]; then cat \"$frame\"; else cp \"$frame\" \"$out\"; fi\n",
+            base.join("runs").display(),
+            cases
+        ),
+    )
+    .unwrap();
+    for f in [&ffprobe, &ffmpeg] {
+        std::fs::set_permissions(f, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let pool = common::memory_pool().await;
+    let id = db::upsert_media(
+        pool.clone(),
+        &NewMediaEntry {
+            name: "clip.mp4".to_string(),
+            path: "clip.mp4".to_string(),
+            parent_id: None,
+            mime_type: Some("video/mp4".to_string()),
+            size: Some(18),
+            tags: None,
+            thumb_path: None,
+            width: None,
+            height: None,
+            duration_secs: None,
+            mtime_ns: None,
+            inode: None,
+        },
+    )
+    .await
+    .expect("upsert media");
+    let state = Arc::new(TokioMutex::new(AppState {
+        ffmpeg_enabled: true,
+        ffmpeg_path: Some(ffmpeg.to_string_lossy().to_string()),
+        ffprobe_path: Some(ffprobe.to_string_lossy().to_string()),
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+    let entry = db::get_media_by_id(pool.clone(), id)
+        .await
+        .unwrap()
+        .expect("entry");
+    let runs = |base: &Path| -> Vec<String> {
+        std::fs::read_to_string(base.join("runs"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    };
+
+    // Every candidate is sampled, then the most detailed one is rendered
+    let name = generate_thumbnail_for_entry(state.clone(), &entry, 320, 180)
+        .await
+        .expect("thumbnail");
+    let log = runs(&base);
+    assert_eq!(log.len(), 6);
+    assert!(log[..5].iter().all(|l| l.ends_with(" -")));
+    assert!(log[5].starts_with("55.000 ") && log[5].ends_with(".jpg"));
+    assert_eq!(db::get_thumb_time(&pool, id).await.unwrap(), Some(55.0));
+
+    // Regenerating goes straight to the stored frame
+    std::fs::remove_file(thumbs_dir.join(&name)).unwrap();
+    generate_thumbnail_for_entry(state.clone(), &entry, 320, 180)
+        .await
+        .expect("regenerated thumbnail");
+    generate_thumbnail_for_entry(state.clone(), &entry, 640, 360)
+        .await
+        .expect("larger thumbnail");
+    let log = runs(&base);
+    assert_eq!(log.len(), 8);
+    assert!(log[6..].iter().all(|l| l.starts_with("55.000 ")));
+}
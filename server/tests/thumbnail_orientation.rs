@@ -0,0 +1,166 @@
+mod common;
+
+use exif::experimental::Writer;
+use exif::{Field, In, Tag, Value};
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+use server::db;
+use server::handlers::thumbnails::generate_thumbnail_for_entry;
+use server::scanner::scan_directory_and_index;
+use server::state::AppState;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+const RED: [u8; 3] = [220, 30, 30];
+const GREEN: [u8; 3] = [30, 220, 30];
+const BLUE: [u8; 3] = [30, 30, 220];
+const WHITE: [u8; 3] = [240, 240, 240];
+
+// 64x32 image as it should be displayed: red, green / blue, white quadrants
+fn upright() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(64, 32, |x, y| {
+        Rgb(match (x < 32, y < 16) {
+            (true, true) => RED,
+            (false, true) => GREEN,
+            (true, false) => BLUE,
+            (false, false) => WHITE,
+        })
+    }))
+}
+
+// Pixels a camera would store for an upright scene with this orientation tag
+fn stored_pixels(orientation: u16) -> DynamicImage {
+    let img = upright();
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate270(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate90(),
+        _ => img,
+    }
+}
+
+// Encode `img` as JPEG with an APP1 EXIF segment carrying `orientation`
+fn write_fixture(path: &Path, orientation: u16) {
+    let img = stored_pixels(orientation);
+    let mut jpeg = Cursor::new(Vec::new());
+    img.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(95))
+        .unwrap();
+    let jpeg = jpeg.into_inner();
+
+    let fields = [
+        Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![orientation]),
+        },
+        Field {
+            tag: Tag::PixelXDimension,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![img.width() as u16]),
+        },
+        Field {
+            tag: Tag::PixelYDimension,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![img.height() as u16]),
+        },
+    ];
+    let mut writer = Writer::new();
+    for f in &fields {
+        writer.push_field(f);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).unwrap();
+    let tiff = tiff.into_inner();
+
+    let mut out = jpeg[..2].to_vec();
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(&jpeg[2..]);
+    std::fs::write(path, out).unwrap();
+}
+
+fn assert_color(img: &DynamicImage, x: u32, y: u32, want: [u8; 3], orientation: u16) {
+    let px = img.get_pixel(x, y);
+    let close = (0..3).all(|i| (px[i] as i32 - want[i] as i32).abs() < 60);
+    assert!(
+        close,
+        "orientation {}: pixel ({}, {}) is {:?}, want {:?}",
+        orientation, x, y, px, want
+    );
+}
+
+#[tokio::test]
+async fn thumbnails_follow_exif_orientation() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let thumbs_dir = base.join("thumbnails");
+    let _ = std::fs::create_dir_all(&media_dir);
+    let _ = std::fs::create_dir_all(&thumbs_dir);
+    for o in 1..=8u16 {
+        write_fixture(&media_dir.join(format!("orientation_{}.jpg", o)), o);
+    }
+
+    let pool = common::memory_pool().await;
+    scan_directory_and_index(
+        pool.clone(),
+        media_dir.to_string_lossy().to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("scan");
+
+    let state = Arc::new(TokioMutex::new(AppState {
+        thumbnails_dir: Some(thumbs_dir.to_string_lossy().to_string()),
+        ..common::app_state(pool.clone(), &media_dir)
+    }));
+
+    for o in 1..=8u16 {
+        let path = format!("orientation_{}.jpg", o);
+        let entry = db::get_media_by_path(pool.clone(), path.clone())
+            .await
+            .expect("db get")
+            .expect("indexed");
+        // The scanner already reports the displayed size from EXIF
+        assert_eq!(
+            (entry.width, entry.height),
+            (Some(64), Some(32)),
+            "orientation {} after scan",
+            o
+        );
+
+        let name = generate_thumbnail_for_entry(state.clone(), &entry, 32, 32)
+            .await
+            .expect("thumbnail");
+        let thumb = image::open(thumbs_dir.join(&name)).expect("open thumbnail");
+        assert_eq!(
+            (thumb.width(), thumb.height()),
+            (32, 16),
+            "orientation {}",
+            o
+        );
+        assert_color(&thumb, 8, 4, RED, o);
+        assert_color(&thumb, 24, 4, GREEN, o);
+        assert_color(&thumb, 8, 12, BLUE, o);
+        assert_color(&thumb, 24, 12, WHITE, o);
+
+        let entry = db::get_media_by_path(pool.clone(), path)
+            .await
+            .expect("db get")
+            .expect("indexed");
+        assert_eq!(
+            (entry.width, entry.height),
+            (Some(64), Some(32)),
+            "orientation {} after thumbnail",
+            o
+        );
+    }
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use axum::http::StatusCode;
+use axum::Router;
+use server::auth::hash_password;
+use server::db;
+use server::models::Role;
+use server::startup::build_router;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+
+async fn login(app: &Router, username: &str, password: &str) -> Option<String> {
+    let body = serde_json::json!({ "username": username, "password": password });
+    match common::send_json(app, "POST", "/auth/login", None, Some(body)).await {
+        (StatusCode::OK, v) => Some(v["token"].as_str().unwrap().to_string()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn admins_manage_users_and_viewers_only_browse() {
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(&media_dir);
+
+    let pool = common::memory_pool().await;
+    let root_id = db::create_user(&pool, "root", &hash_password("toor").unwrap(), Role::Admin)
+        .await
+        .expect("create admin");
+    let photo = common::add(&pool, "photo.jpg", None, Some("image/jpeg")).await;
+    let state = Arc::new(TokioMutex::new(common::app_state(pool.clone(), &media_dir)));
+
+    let app = build_router(state).await;
+
+    let root = login(&app, "root", "toor").await.expect("admin login");
+    let (status, me) = common::send_json(&app, "GET", "/auth/me", Some(&root), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["role"], "admin");
+
+    // Invite a viewer; the account has no usable password until it is accepted
+    let (status, invite) = common::send_json(
+        &app,
+        "POST",
+        "/admin/users",
+        Some(&root),
+        Some(serde_json::json!({ "username": "vera" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", invite);
+    assert_eq!(invite["user"]["role"], "viewer");
+    let vera_id = invite["user"]["id"].as_i64().unwrap();
+    let invite_token = invite["invite_token"].as_str().unwrap().to_string();
+    assert!(login(&app, "vera", "").await.is_none());
+    let (status, _) = common::send_json(
+        &app,
+        "POST",
+        "/admin/users",
+        Some(&root),
+        Some(serde_json::json!({ "username": "VERA" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let accept =
+        |token: &str, password: &str| serde_json::json!({ "token": token, "password": password });
+    let (status, _) = common::send_json(
+        &app,
+        "POST",
+        "/auth/accept_invite",
+        None,
+        Some(accept(&invite_token, "s3cret")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    // An invite works only once
+    let (status, _) = common::send_json(
+        &app,
+        "POST",
+        "/auth/accept_invite",
+        None,
+        Some(accept(&invite_token, "other")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let vera = login(&app, "vera", "s3cret").await.expect("viewer login");
+
+    // Viewers browse but cannot scan, manage users, edit tags or share
+    let (status, _) = common::send_json(&app, "GET", "/media", Some(&vera), None).await;
+    assert_eq!(status, StatusCode::OK);
+    for (method, uri) in [("POST", "/scan"), ("GET", "/admin/users")] {
+        let (status, _) = common::send_json(&app, method, uri, Some(&vera), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+    }
+    let tags_uri = format!("/media/{}/tags", photo);
+    let tags = serde_json::json!({ "tags": ["cat"] });
+    for (method, uri, body) in [
+        ("POST", tags_uri.as_str(), tags.clone()),
+        ("DELETE", tags_uri.as_str(), tags.clone()),
+        ("POST", "/shares", serde_json::json!({ "id": photo })),
+    ] {
+        let (status, _) = common::send_json(&app, method, uri, Some(&vera), Some(body)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+    }
+    let (status, tagged) =
+        common::send_json(&app, "POST", &tags_uri, Some(&root), Some(tags)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tagged["tags"], serde_json::json!(["cat"]));
+    let (status, _) = common::send_json(&app, "POST", "/scan", Some(&root), None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, list) = common::send_json(&app, "GET", "/admin/users", Some(&root), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(list["users"].as_array().unwrap().len(), 2);
+
+    // Disabling ends the viewer's sessions and blocks new logins
+    let vera_uri = format!("/admin/users/{}", vera_id);
+    let (status, updated) = common::send_json(
+        &app,
+        "PATCH",
+        &vera_uri,
+        Some(&root),
+        Some(serde_json::json!({ "disabled": true })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["disabled"], true);
+    let (status, _) = common::send_json(&app, "GET", "/media", Some(&vera), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = serde_json::json!({ "username": "vera", "password": "s3cret" });
+    let (status, _) = common::send_json(&app, "POST", "/auth/login", None, Some(body)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    common::send_json(
+        &app,
+        "PATCH",
+        &vera_uri,
+        Some(&root),
+        Some(serde_json::json!({ "disabled": false, "role": "admin" })),
+    )
+    .await;
+
+    // A reset replaces the password through a fresh invite
+    let (status, reset) = common::send_json(
+        &app,
+        "POST",
+        &format!("{}/reset", vera_uri),
+        Some(&root),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(login(&app, "vera", "s3cret").await.is_none());
+    let (status, _) = common::send_json(
+        &app,
+        "POST",
+        "/auth/accept_invite",
+        None,
+        Some(accept(reset["invite_token"].as_str().unwrap(), "n3w")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let vera = login(&app, "vera", "n3w").await.expect("login after reset");
+
+    // The last active admin cannot be demoted, disabled or deleted
+    let (status, _) = common::send_json(&app, "DELETE", &vera_uri, Some(&vera), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let root_uri = format!("/admin/users/{}", root_id);
+    for (method, body) in [
+        ("PATCH", Some(serde_json::json!({ "role": "viewer" }))),
+        ("PATCH", Some(serde_json::json!({ "disabled": true }))),
+        ("DELETE", None),
+    ] {
+        let (status, _) = common::send_json(&app, method, &root_uri, Some(&root), body).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+    let (status, _) = common::send_json(&app, "GET", &vera_uri, Some(&root), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use server::db;
+use server::scanner::scan_directory_and_index;
+use server::watcher::spawn_watcher;
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+// Poll the index until `path` is (or is no longer) present
+async fn wait_for_path(pool: &SqlitePool, path: &str, present: bool) -> bool {
+    for _ in 0..50 {
+        let found = db::get_media_by_path(pool.clone(), path.to_string())
+            .await
+            .expect("db get")
+            .is_some();
+        if found == present {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn watcher_indexes_created_and_deleted_files() {
+    // Setup repo-local temp directories under <crate>/tests/tmp
+    let base = common::TestDir::new();
+    let media_dir = base.join("media");
+    let _ = std::fs::create_dir_all(media_dir.join("existing"));
+
+    let pool = common::memory_pool().await;
+    scan_directory_and_index(
+        pool.clone(),
+        media_dir.to_string_lossy().to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .expect("initial scan");
+
+    spawn_watcher(pool.clone(), media_dir.clone(), None, Some(100)).expect("start watcher");
+
+    // A file dropped into an already-indexed directory
+    std::fs::write(media_dir.join("existing").join("new.txt"), b"hello").unwrap();
+    assert!(wait_for_path(&pool, "existing/new.txt", true).await);
+
+    // A whole directory created with content inside it
+    std::fs::create_dir_all(media_dir.join("fresh")).unwrap();
+    std::fs::write(media_dir.join("fresh").join("inner.txt"), b"inner").unwrap();
+    assert!(wait_for_path(&pool, "fresh/inner.txt", true).await);
+
+    // Deletions are reflected too
+    std::fs::remove_file(media_dir.join("existing").join("new.txt")).unwrap();
+    assert!(wait_for_path(&pool, "existing/new.txt", false).await);
+}